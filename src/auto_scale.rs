@@ -1,12 +1,63 @@
-
+use crate::traits::*;
 
 /**
  * Contains types related to coordinating auto scaling behavior
  */
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum AutoScalePolicy {
-	WhenAllFull(usize)
+	WhenAllFull(usize),
+	// Grows by the amount once more than depth jobs are waiting, or when full
+	WhenQueueDeeper(usize /*depth*/, usize /*amount*/)
+}
+
+/**
+ * How a pool of workers grows and shrinks. A pool never shrinks below
+ * the size it started with.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct AutoScale {
+	pub policy:AutoScalePolicy,
+	pub max_workers:usize,
+	// Workers idle for this long, in ms, are stopped, 0 disables shrinking
+	pub idle_shrink:u64
 }
 
+impl NewType for AutoScale {
+	fn new() -> Self {
+		AutoScale{policy:AutoScalePolicy::WhenAllFull(1), max_workers:64, idle_shrink:0}
+	}
+}
+
+impl AutoScale {
+	// How many workers to add, given how many there are, how many jobs
+	// are waiting and if the queue is full. Never goes past max_workers.
+	pub fn growth(&self, workers:usize, queued:usize, full:bool) -> usize {
+		let wanted = match self.policy {
+			AutoScalePolicy::WhenAllFull(amnt) if full => amnt,
+			AutoScalePolicy::WhenQueueDeeper(depth, amnt) if full || queued > depth => amnt,
+			_ => 0
+		};
+		wanted.min(self.max_workers.saturating_sub(workers))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn growth_works() {
+    	let mut scale = AutoScale::new();
+    	scale.max_workers = 6;
+    	assert_eq!(scale.growth(4, 100, false), 0);
+    	assert_eq!(scale.growth(4, 100, true), 1);
+    	scale.policy = AutoScalePolicy::WhenQueueDeeper(8, 3);
+    	assert_eq!(scale.growth(2, 8, false), 0);
+    	assert_eq!(scale.growth(2, 9, false), 3);
+    	// capped by the max
+    	assert_eq!(scale.growth(4, 9, false), 2);
+    	assert_eq!(scale.growth(6, 0, true), 0);
+    }
+}
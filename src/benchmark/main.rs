@@ -4,12 +4,22 @@
 
 use std::env;
 use std::process;
-use std::time::Instant;
+use std::time::{Instant, Duration};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::{hint, mem};
 
 use floton::logging::*;
 use floton::log_always;
 use floton::traits::*;
 use floton::trie::*;
+use floton::hashtree::{HashTree, HashScheme, HashKind};
+use floton::threading::{ExecUnit, ExecUnitGroup};
+use floton::shared::{Shared, TimePtr};
+use floton::{tlocal, reclaim};
+use floton::database::Database;
+use floton::{alloc, free};
 
 fn int_trie_node() {
 	average_s!(IntTrieNodeGet, 100000, {
@@ -20,11 +30,191 @@ fn int_trie_node() {
 	});
 }
 
+// Every SLOW_EVERY'th job takes SLOW_MS, the rest are near instant
+const SKEW_JOBS:usize = 4000;
+const SKEW_WORKERS:usize = 4;
+const SLOW_EVERY:usize = 16;
+const SLOW_MS:u64 = 4;
+
+struct SkewJob {
+	queued:Instant,
+	slow:bool,
+	latencies:*const Mutex<Vec<u64>>
+}
+
+fn run_skew_job(job:*mut SkewJob) {
+	let rjob = unsafe { job.as_ref().unwrap() };
+	if rjob.slow {
+		thread::sleep(Duration::from_millis(SLOW_MS));
+	}
+	let waited = rjob.queued.elapsed().as_micros() as u64;
+	unsafe { rjob.latencies.as_ref().unwrap() }.lock().unwrap().push(waited);
+	free!(job);
+}
+
+fn report_latencies(name:&str, latencies:&Mutex<Vec<u64>>) {
+	let mut lats = latencies.lock().unwrap();
+	lats.sort_unstable();
+	let at = |pct:usize| lats[(lats.len() - 1) * pct / 100];
+	log_always!(Bench, "{} latency in micros: p50 = {}, p99 = {}, max = {}", name, at(50), at(99), at(100));
+}
+
+// Feeds jobs at a steady pace, through submit, which returns false when full
+fn feed_skewed(latencies:&Mutex<Vec<u64>>, mut submit:impl FnMut(*mut SkewJob) -> bool) {
+	for i in 0..SKEW_JOBS {
+		let job = alloc!(SkewJob{queued:Instant::now(), slow:i % SLOW_EVERY == 0, latencies});
+		while !submit(job) {
+			thread::yield_now();
+		}
+		thread::sleep(Duration::from_micros(50));
+	}
+}
+
+// Compares private per unit queues, handed jobs round robin as the
+// group used to, against the shared queue of ExecUnitGroup
+fn exec_group_skewed() {
+	let latencies = Mutex::new(Vec::with_capacity(SKEW_JOBS));
+	let mut units:Vec<ExecUnit<SkewJob>> = (0..SKEW_WORKERS).map(|_| ExecUnit::new(64, run_skew_job)).collect();
+	let mut turn = 0;
+	feed_skewed(&latencies, |job| {
+		turn += 1;
+		units[turn % SKEW_WORKERS].give_ptr(job)
+	});
+	for unit in units.iter_mut() {
+		unit.stop();
+	}
+	report_latencies("RoundRobinUnits", &latencies);
+
+	let latencies = Mutex::new(Vec::with_capacity(SKEW_JOBS));
+	let mut egroup = ExecUnitGroup::new(SKEW_WORKERS, 64, run_skew_job);
+	feed_skewed(&latencies, |job| egroup.assign_ptr(job).is_some());
+	egroup.stop_all();
+	report_latencies("SharedQueueGroup", &latencies);
+}
+
+const SHARED_KEYS:usize = 100000;
+const SHARED_THREADS:usize = 4;
+
+// Bytes the allocator has handed out and not had back
+fn heap_in_use() -> usize {
+	let info = unsafe { libc::mallinfo2() };
+	info.uordblks + info.hblkhd
+}
+
+fn read_all(keys:&[Shared<u64>]) -> u64 {
+	reclaim::pin();
+	let mut sum = 0;
+	for key in keys.iter() {
+		sum += unsafe { key.read().as_ref().unwrap().0 };
+	}
+	reclaim::unpin();
+	sum
+}
+
+// Per key footprint of Shared before it was one pointer, when it kept a
+// table of read times and free lists for each thread
+const BASELINE_SHARED_INLINE:usize = 40;
+const BASELINE_SHARED_HEAP:usize = 648;
+
+// The memory each key's value slot takes, inline and on the heap, once a
+// few threads have read it, as workers do
+fn shared_per_key() {
+	tlocal::set_epoch();
+	let before = heap_in_use();
+	let keys:Vec<Shared<u64>> = (0..SHARED_KEYS).map(|i| Shared::new_val(i as u64)).collect();
+	thread::scope(|s| {
+		for _ in 0..SHARED_THREADS {
+			s.spawn(|| read_all(&keys));
+		}
+	});
+	let used = heap_in_use() - before;
+	log_always!(Bench, "Shared inline size = {} bytes, heap per key after {} readers = {} bytes",
+	            mem::size_of::<Shared<u64>>(), SHARED_THREADS, used / SHARED_KEYS);
+	log_always!(Bench, "Baseline Shared inline size = {} bytes, heap per key after {} readers = {} bytes",
+	            BASELINE_SHARED_INLINE, SHARED_THREADS, BASELINE_SHARED_HEAP);
+}
+
+// Read latency on a quiet map, then with a thread writing over it
+fn shared_read_latency() {
+	tlocal::set_epoch();
+	let keys:Vec<Shared<u64>> = (0..SHARED_KEYS).map(|i| Shared::new_val(i as u64)).collect();
+	read_all(&keys);
+	let quiet = average_s!(20, { hint::black_box(read_all(&keys)); });
+	log_always!(Bench, "Quiet read latency = {} ns", quiet * 1e9 / SHARED_KEYS as f64);
+	let writing = AtomicBool::new(true);
+	// writers take their free limit from the database settings
+	let mut db = Database::new_for_testing();
+	let db_ptr = &mut db as *mut Database as usize;
+	let busy = thread::scope(|s| {
+		s.spawn(|| {
+			tlocal::set_db(db_ptr as *mut Database);
+			let mut i = 0;
+			while writing.load(Ordering::Relaxed) {
+				reclaim::pin();
+				keys[i % SHARED_KEYS].write(TimePtr::make(i as u64));
+				reclaim::unpin();
+				i += 1;
+			}
+		});
+		let busy = average_s!(20, { hint::black_box(read_all(&keys)); });
+		writing.store(false, Ordering::Relaxed);
+		busy
+	});
+	log_always!(Bench, "Read latency while writing = {} ns", busy * 1e9 / SHARED_KEYS as f64);
+}
+
+const WRITE_THREADS:usize = 4;
+const WRITE_KEYS:usize = 20000;
+const WRITES_PER_THREAD:usize = 500000;
+
+// Writes per second into a map as workers do them, each thread inserting
+// its own keys then overwriting them, every write making a new version.
+// Built with --features box-alloc, this is the global allocator baseline.
+fn write_throughput() {
+	let allocator = if cfg!(feature = "box-alloc") { "global allocator" } else { "slab" };
+	tlocal::set_epoch();
+	let mut db = Database::new_for_testing();
+	let db_ptr = &mut db as *mut Database as usize;
+	for threads in [1, WRITE_THREADS] {
+		let tree = HashTree::<Shared<u64>>::new_table(HashScheme::new(HashKind::Fx, 0), 64);
+		let start = Instant::now();
+		thread::scope(|s| {
+			for t in 0..threads {
+				let tree = &tree;
+				s.spawn(move || {
+					tlocal::set_db(db_ptr as *mut Database);
+					for i in 0..WRITES_PER_THREAD {
+						let key = ((t * WRITE_KEYS + i % WRITE_KEYS) as u64).to_le_bytes();
+						reclaim::pin();
+						tree.insert_bytes(&key, 8).write(TimePtr::make(i as u64));
+						reclaim::unpin();
+					}
+				});
+			}
+		});
+		let writes = (threads * WRITES_PER_THREAD) as f64;
+		log_always!(Bench, "Write throughput with {} threads on the {} = {:.0} writes per second",
+		            threads, allocator, writes / start.elapsed().as_secs_f64());
+	}
+}
+
 const INT_TRIE_NODE_GET:&'static str = "int_trie_node_get";
+const EXEC_GROUP_SKEWED:&'static str = "exec_group_skewed";
+const SHARED_PER_KEY:&'static str = "shared_per_key";
+const SHARED_READ_LATENCY:&'static str = "shared_read_latency";
+const WRITE_THROUGHPUT:&'static str = "write_throughput";
 
 fn run_bench(key:&str) {
 	if key == INT_TRIE_NODE_GET {
 		int_trie_node()
+	} else if key == EXEC_GROUP_SKEWED {
+		exec_group_skewed()
+	} else if key == SHARED_PER_KEY {
+		shared_per_key()
+	} else if key == SHARED_READ_LATENCY {
+		shared_read_latency()
+	} else if key == WRITE_THROUGHPUT {
+		write_throughput()
 	} else {
 		log_always!(Bench, "Error: The Benchmark \"{}\" is not found!", key);
 		process::exit(2);
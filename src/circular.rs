@@ -54,6 +54,7 @@ impl<T: Clone> CircleList<T> {
 		let head =  unsafe { self.0.load(Ordering::SeqCst).as_ref().unwrap() };
 		let head_next = head.1.load(Ordering::SeqCst);
 		head.1.store(CircleNode::extend_ptr(val, head_next), Ordering::SeqCst);
+		self.1.fetch_add(1, Ordering::SeqCst);
 	}
 
 	pub fn next_ptr(&self) -> *mut CircleNode<T> {
@@ -68,6 +69,32 @@ impl<T: Clone> CircleList<T> {
 	pub fn len(&self) -> usize {
 		self.1.load(Ordering::SeqCst)
 	}
+
+	// Unlinks and returns the first value matching, the last node is never
+	// removed. Not safe alongside other threads walking the list.
+	pub fn remove_where(&self, pred:impl Fn(&T) -> bool) -> Option<T> {
+		let len = self.len();
+		if len < 2 {
+			return None;
+		}
+		let head = self.0.load(Ordering::SeqCst);
+		let mut prev = head;
+		for _ in 0..len {
+			let cur = ptref!(prev).1.load(Ordering::SeqCst);
+			if pred(&ptref!(cur).0) {
+				let next = ptref!(cur).1.load(Ordering::SeqCst);
+				ptref!(prev).1.store(next, Ordering::SeqCst);
+				if cur == head {
+					self.0.store(next, Ordering::SeqCst);
+				}
+				self.1.fetch_sub(1, Ordering::SeqCst);
+				let node = unsafe { Box::from_raw(cur) };
+				return Some(node.0);
+			}
+			prev = cur;
+		}
+		None
+	}
 }
 
 #[cfg(test)]
@@ -110,9 +137,34 @@ mod tests {
     		(*list.0.load(Ordering::SeqCst)).0 = TestType(1);
     	}
     	list.add(&base);
+    	assert_eq!(list.len(), 11);
     	for _ in 0..11 {
     		list.next();
     	}
     	assert_eq!(list.next().0, 1);	
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn circ_list_remove_where_works() {
+    	let base = TestType(7);
+    	let list = CircleList::new(&base, 3);
+    	unsafe {
+    		(*list.0.load(Ordering::SeqCst)).0 = TestType(1);
+    	}
+    	list.add(&TestType(2));
+    	assert_eq!(list.remove_where(|v| v.0 == 1).unwrap().0, 1);
+    	assert_eq!(list.len(), 3);
+    	assert!(list.remove_where(|v| v.0 == 1).is_none());
+    	let mut seen = vec![];
+    	for _ in 0..3 {
+    		seen.push(list.next().0);
+    	}
+    	seen.sort();
+    	assert_eq!(seen, vec![2, 7, 7]);
+    	assert!(list.remove_where(|v| v.0 == 7).is_some());
+    	assert!(list.remove_where(|v| v.0 == 7).is_some());
+    	// the last one stays
+    	assert!(list.remove_where(|_| true).is_none());
+    	assert_eq!(list.next().0, 2);
+    }
+}
@@ -7,6 +7,16 @@ pub const CMD_RETURN_KV:u8 = 1;
 pub const CMD_SET_KV:u8 = 2;
 pub const CMD_OP_ATOMIC:u8 = 3;
 pub const CMD_OP_NORMAL:u8 = 4;
+pub const CMD_RANGE:u8 = 5;
+pub const CMD_QPUSH:u8 = 6;
+pub const CMD_QPOP:u8 = 7;
+pub const CMD_QBPOP:u8 = 8; // the key is followed by a u64 timeout in ms, 0 waits without one
+pub const CMD_SET_ALGEBRA:u8 = 9; // followed by a SET_ op, then two key paths
+pub const CMD_INFO:u8 = 10; // gives back a map of server stats
+pub const CMD_SLOWLOG_GET:u8 = 11; // followed by a u64 limit, 0 gives back every slow command
+pub const CMD_SLOWLOG_RESET:u8 = 12; // gives back how many slow commands were dropped
+pub const CMD_CONFIG_GET:u8 = 13; // gives back every setting, as a map keyed by the argument giving it
+pub const CMD_CONFIG_SET:u8 = 14; // followed by a key path of one key, the argument giving the setting
 
 //data types
 pub const VBIN_NOTHING:u8 = 0;
@@ -19,10 +29,31 @@ pub const VBIN_UINT:u8 = 6;
 pub const VBIN_AUINT:u8 = 7;
 pub const VBIN_IINT:u8 = 8;
 pub const VBIN_AIINT:u8 = 9;
+pub const VBIN_OMAP_BEGIN:u8 = 12; // a map kept in key order
+pub const VBIN_OMAP_END:u8 = 13;
+pub const VBIN_LIST_BEGIN:u8 = 14;
+pub const VBIN_LIST_END:u8 = 15;
+pub const VBIN_QUEUE:u8 = 16; // followed by the u64 capacity, items are only reached by popping
+pub const VBIN_SET_BEGIN:u8 = 17; // members are written as keys
+pub const VBIN_SET_END:u8 = 18;
+pub const VBIN_HLL:u8 = 19; // followed by the u8 precision, then a byte for each of the 2^precision registers
+pub const VBIN_BLOOM:u8 = 21; // followed by the u8 hash count, the u64 word count, then the u64 words
 
 //symbolizes intra-map key
 pub const CMAPB_KEY:u8 = 2;
 
+//range flags, the start key follows if set, then the end key, then a u64 limit
+pub const RANGE_HAS_START:u8 = 1;
+pub const RANGE_START_INCL:u8 = 2;
+pub const RANGE_HAS_END:u8 = 4;
+pub const RANGE_END_INCL:u8 = 8;
+pub const RANGE_REVERSE:u8 = 16;
+
+//set algebra, the result is written as a set
+pub const SET_UNION:u8 = 0;
+pub const SET_INTERSECT:u8 = 1;
+pub const SET_DIFF:u8 = 2; // members of the first set not in the second
+
 //atomic ops
 // These are a bit moe numerous so better to do u16
 pub const OP_ATOMIC_STORE:u16 = 0;
@@ -40,6 +71,26 @@ pub const OP_ATOMIC_SUB_FETCH:u16 = 11;
 
 // normal ops
 pub const OP_NORM_UPDATE:u16 = 0;
+// list ops, indexes are i64 and negative ones count from the end
+pub const OP_NORM_LIST_PUSH_BACK:u16 = 1;
+pub const OP_NORM_LIST_PUSH_FRONT:u16 = 2;
+pub const OP_NORM_LIST_POP_BACK:u16 = 3;
+pub const OP_NORM_LIST_POP_FRONT:u16 = 4;
+pub const OP_NORM_LIST_GET:u16 = 5;
+pub const OP_NORM_LIST_SET:u16 = 6;
+pub const OP_NORM_LIST_LEN:u16 = 7;
+pub const OP_NORM_LIST_RANGE:u16 = 8; // start included, end not
+// set ops, members are given as keys
+pub const OP_NORM_SET_ADD:u16 = 9;
+pub const OP_NORM_SET_REMOVE:u16 = 10;
+pub const OP_NORM_SET_IS_MEMBER:u16 = 11;
+pub const OP_NORM_SET_CARD:u16 = 12;
+pub const OP_NORM_SET_MEMBERS:u16 = 13; // u64 cursor and limit, gives the next cursor then a set
+// sketch ops, items are given as keys
+pub const OP_NORM_SKETCH_ADD:u16 = 14;
+pub const OP_NORM_SKETCH_COUNT:u16 = 15; // an estimate of the distinct items added
+pub const OP_NORM_SKETCH_CHECK:u16 = 16; // bloom filters only
+pub const OP_NORM_SKETCH_MERGE:u16 = 17; // followed by a sketch of the same kind and shape
 
 //errors
 pub const ERR_DATE_TIME:u8 = 0;
@@ -47,6 +98,19 @@ pub const ERR_RET_NOT_FOUND:u8 = 1;
 pub const ERR_UNEXPECT_BYTE:u8 = 2;
 pub const ERR_TYPE_NOT_ATOMIC:u8 = 3;
 pub const ERR_OPER_NOT_SUPPORTED:u8 = 4; // operation isn't supported for type
+pub const ERR_SERVER_BUSY:u8 = 5; // followed by a u32 retry after hint in ms
+pub const ERR_REQUEST_TOO_LARGE:u8 = 6; // followed by the u64 max request size
+pub const ERR_TOO_MANY_CONNS:u8 = 7;
+pub const ERR_READ_TIMEOUT:u8 = 8;
+pub const ERR_WRITE_TIMEOUT:u8 = 9;
+pub const ERR_IDLE_TIMEOUT:u8 = 10;
+pub const ERR_QUEUE_FULL:u8 = 11;
+pub const ERR_SKETCH_MISMATCH:u8 = 12; // sketches of different kinds or shapes can't be merged
+pub const ERR_OUT_OF_MEMORY:u8 = 13; // the write would go over --max-memory, and nothing could be evicted
+pub const ERR_CONFIG_READ_ONLY:u8 = 14; // the setting can only be given at startup
+pub const ERR_CONFIG_INVALID:u8 = 15; // not a setting, or not a valid value for it
+pub const ERR_QUEUE_TOO_LONG:u8 = 16; // followed by the u64 --max-queue-len
+pub const ERR_BAD_REQUEST:u8 = 17; // a key path or field that can't be read, followed by the u64 place it starts at
 
 //db states
 pub const DBSTATE_START:u8 = 0;
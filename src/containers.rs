@@ -4,55 +4,125 @@ use std::ptr;
 use std::ops::Deref;
 use crate::shared::*;
 use crate::tlocal;
-use crate::hashtree::{HashTree, HashScheme};
+use crate::keys;
+use crate::hashtree::{self, HashTree, HashScheme};
+use crate::skiplist::{self, SkipList};
+use crate::lists::{List, ListItems};
+use crate::queues::Queue;
+use crate::sets::Set;
+use crate::sketches::Sketch;
+use crate::threading::TVal;
+use crate::values::Value;
 use crate::logging::*;
 use crate::traits::*;
 use crate::errors::FlotonErr;
-use crate::constants::{VBIN_CMAP_BEGIN, VBIN_CMAP_END, CMAPB_KEY};
+use crate::constants::{VBIN_CMAP_BEGIN, VBIN_CMAP_END, VBIN_OMAP_BEGIN, VBIN_OMAP_END, VBIN_LIST_BEGIN, VBIN_LIST_END, VBIN_QUEUE, VBIN_SET_BEGIN, VBIN_SET_END, VBIN_HLL, VBIN_BLOOM, CMAPB_KEY};
 
 #[derive(Debug)]
 pub enum Container<T> {
 	Val(T),
-	Map(HashTree<Shared<Container<T>>>)
+	Map(HashTree<Shared<Container<T>>>),
+	OrdMap(SkipList<Shared<Container<T>>>),
+	List(List<T>),
+	Queue(Queue<T>),
+	Set(Set),
+	Sketch(Sketch)
 }
 
-fn hash_tree_cont_output_binary<T: InPutOutPut + Debug>(tree:&HashTree<Shared<Container<T>>>, 
-                                                output: &mut Vec<u8>) {
-    match tree {
-        HashTree::Table(_, tble) => for i in 0..tble.len() {
-            let tptr = tble[i].load(Ordering::SeqCst);
-            if nonull!(tptr) {
-                unsafe {
-                    match tptr.as_ref().unwrap() {
-                        HashTree::Item(ikey, ival, iother) => {
-                            let current_val = ival.read();
-                            if nonull!(current_val) {
-                                // annotates a key
-                                output.push(CMAPB_KEY);
-                                // only u64 aligned keys are supported
-                                let len_u64 = ikey.len() as u64;
-                                output.extend_from_slice(&len_u64.to_le_bytes());
-                                output.extend_from_slice(ikey.deref());
-                                current_val.as_ref().unwrap().0.output_binary(output);
-                            }
-                            // also add collided key-value pairs
-                            let other_ptr = iother.load(Ordering::SeqCst);
-                            if nonull!(other_ptr) {
-                                hash_tree_cont_output_binary(other_ptr.as_ref().unwrap(), output);
-                            }
-                        },
-                        HashTree::Table(gen, _) => {
-                            log_fatal!(Output, "Unexpected table in place of item during format, gen: {:?}", gen);
-                            panic!("Cannot format container with invalid item");
-                        }
+/**
+ * The entries of either kind of map, hash maps in no set order and
+ * ordered maps in key order.
+ */
+#[derive(Debug)]
+pub enum Entries<'a, T> {
+    Hash(hashtree::Iter<'a, Shared<Container<T>>>),
+    Ordered(skiplist::Range<'a, Shared<Container<T>>>)
+}
+
+impl<'a, T> Iterator for Entries<'a, T> {
+    type Item = (&'a [u8], &'a Shared<Container<T>>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Entries::Hash(it) => it.next(),
+            Entries::Ordered(it) => it.next()
+        }
+    }
+}
+
+pub fn entries_output_binary<'a, T: InPutOutPut + Debug + 'a>(entries:impl Iterator<Item = (&'a [u8], &'a Shared<Container<T>>)>, 
+                                                            output: &mut Vec<u8>) {
+    for (ikey, ival) in entries {
+        let current_val = ival.read();
+        if !current_val.is_null() {
+            // annotates a key
+            output.push(CMAPB_KEY);
+            keys::write_key(ikey, output);
+            unsafe { current_val.as_ref().unwrap() }.0.output_binary(output);
+        }
+    }
+}
+
+pub fn list_output_binary<T: InPutOutPut + Debug>(items:&[TVal<Container<T>>], output: &mut Vec<u8>) {
+    output.push(VBIN_LIST_BEGIN);
+    for item in items {
+        item.output_binary(output);
+    }
+    output.push(VBIN_LIST_END);
+}
+
+pub fn set_output_binary<'a>(members:impl Iterator<Item = &'a [u8]>, output: &mut Vec<u8>) {
+    output.push(VBIN_SET_BEGIN);
+    for member in members {
+        keys::write_key(member, output);
+    }
+    output.push(VBIN_SET_END);
+}
+
+/**
+ * Walks every leaf value under a container, depth first, along with the
+ * path of keys leading to it. Each map is iterated with its entries,
+ * so the walk is weakly consistent in the same way. Lists, queues and
+ * sets hold no values under keys, so the walk does not go into them.
+ */
+#[derive(Debug)]
+pub struct Walk<'a, T> {
+    stack:Vec<Entries<'a, T>>,
+    path:Vec<&'a [u8]>,
+    root:Option<&'a T>
+}
+
+impl<'a, T: Debug> Iterator for Walk<'a, T> {
+    type Item = (Vec<&'a [u8]>, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(v) = self.root.take() {
+            return Some((vec![], v));
+        }
+        while let Some(level) = self.stack.last_mut() {
+            match level.next() {
+                Some((ikey, ival)) => {
+                    let current_val = ival.read();
+                    if current_val.is_null() {
+                        continue;
                     }
+                    let inner = unsafe { &current_val.as_ref().unwrap().0 };
+                    if let Some(entries) = inner.entries() {
+                        self.path.push(ikey);
+                        self.stack.push(entries);
+                    } else if let Ok(v) = inner.value() {
+                        let mut path = self.path.clone();
+                        path.push(ikey);
+                        return Some((path, v));
+                    }
+                },
+                None => {
+                    self.stack.pop();
+                    self.path.pop();
                 }
             }
-        },
-        HashTree::Item(key, _, _) => {
-            log_fatal!(Output, "Unexpected item in place of table during format, key: {:?}", key);
-            panic!("Cannot format container with invalid map");
         }
+        None
     }
 } 
 
@@ -62,41 +132,84 @@ impl<T: InPutOutPut + Debug> InPutOutPut for Container<T> {
             Container::Val(v) => v.output_binary(output),
             Container::Map(m) => {
                 output.push(VBIN_CMAP_BEGIN);
-                hash_tree_cont_output_binary(m, output);
+                entries_output_binary(m.iter(), output);
                 output.push(VBIN_CMAP_END);
-            }
+            },
+            Container::OrdMap(m) => {
+                output.push(VBIN_OMAP_BEGIN);
+                entries_output_binary(m.iter(), output);
+                output.push(VBIN_OMAP_END);
+            },
+            Container::List(l) => list_output_binary(&l.snapshot(), output),
+            Container::Queue(q) => {
+                output.push(VBIN_QUEUE);
+                output.extend_from_slice(&(q.capacity() as u64).to_le_bytes());
+            },
+            Container::Set(s) => set_output_binary(s.iter(), output),
+            Container::Sketch(s) => s.output_binary(output)
         }
     }
 
     fn input_binary(input:&[u8], place:&mut usize) -> Result<Self, FlotonErr>  {
-        if input[*place] == VBIN_CMAP_BEGIN {
-            *place += 1;
-            let nmap = Container::new_map(40); // todo make configurable
-            while input[*place] != VBIN_CMAP_END {
-                if input[*place] == CMAPB_KEY {
-                    *place += 1;
-                    let key_ptr = unsafe { input.as_ptr().offset(*place as isize) as *const u64 };
-                    let ksize = (unsafe { *key_ptr }) as usize;
-                    *place += 8;
-                    let kslice = &input[*place..(*place + ksize)];
-                    *place += ksize;
+        let in_type = *input.get(*place).ok_or(FlotonErr::BadRequest(*place as u64))?;
+        let (nmap, end) = match in_type {
+            VBIN_CMAP_BEGIN => (Container::new_map(40), VBIN_CMAP_END), // todo make configurable
+            VBIN_OMAP_BEGIN => (Container::new_ordered_map(), VBIN_OMAP_END),
+            VBIN_LIST_BEGIN => {
+                *place += 1;
+                let mut items = ListItems::new();
+                while *input.get(*place).ok_or(FlotonErr::BadRequest(*place as u64))? != VBIN_LIST_END {
                     match Container::input_binary(input, place) {
-                        Ok(val) => nmap.set_map(kslice, val),
+                        Ok(val) => items.push_back(TVal::new(val)),
                         Err(e) => return Err(e)
                     }
-                } else {
-                    log_error!(Input, "Invalid byte for container: {}, place: {}", input[*place], *place);
-                    return Err(FlotonErr::UnexpectedByte(input[*place]));
                 }
-            }
-            *place += 1; // move past end
-            return Ok(nmap);
-        } else {
-            return match T::input_binary(input, place) {
+                *place += 1; // move past end
+                return Ok(Container::List(List::new(items)));
+            },
+            VBIN_QUEUE => {
+                *place += 1;
+                let size = keys::read_u64(input, place)?;
+                let max_len = tlocal::get_max_queue_len() as u64;
+                if size > max_len {
+                    return Err(FlotonErr::QueueTooLong(max_len));
+                }
+                return Ok(Container::Queue(Queue::new(size as usize)));
+            },
+            VBIN_SET_BEGIN => {
+                *place += 1;
+                let set = Set::new(8); // grows as members are added
+                while *input.get(*place).ok_or(FlotonErr::BadRequest(*place as u64))? != VBIN_SET_END {
+                    set.add(keys::read_key(input, place)?);
+                }
+                *place += 1; // move past end
+                return Ok(Container::Set(set));
+            },
+            VBIN_HLL | VBIN_BLOOM => return Ok(Container::Sketch(Sketch::input_binary(input, place)?)),
+            _ => return match T::input_binary(input, place) {
                 Ok(r) => Ok(Container::Val(r)),
                 Err(e) => Err(e)
             }
+        };
+        *place += 1;
+        loop {
+            let byte = *input.get(*place).ok_or(FlotonErr::BadRequest(*place as u64))?;
+            if byte == end {
+                break;
+            } else if byte == CMAPB_KEY {
+                *place += 1;
+                let kslice = keys::read_key(input, place)?;
+                match Container::input_binary(input, place) {
+                    Ok(val) => nmap.set_map(kslice, val),
+                    Err(e) => return Err(e)
+                }
+            } else {
+                log_error!(Input, "Invalid byte for container: {}, place: {}", byte, *place);
+                return Err(FlotonErr::UnexpectedByte(byte));
+            }
         }
+        *place += 1; // move past end
+        return Ok(nmap);
     }
 }
 
@@ -105,61 +218,139 @@ impl<T: Debug> Container<T> {
 		Container::Map(HashTree::new_table(HashScheme::default(), size))
 	}
 
+	pub fn new_ordered_map() -> Container<T> {
+		Container::OrdMap(SkipList::new())
+	}
+
+    pub fn is_map(&self) -> bool {
+        matches!(self, Container::Map(_) | Container::OrdMap(_))
+    }
+
+    pub fn list(&self) -> Option<&List<T>> {
+        match self {
+            Container::List(l) => Some(l),
+            _ => None
+        }
+    }
+
+    pub fn queue(&self) -> Option<&Queue<T>> {
+        match self {
+            Container::Queue(q) => Some(q),
+            _ => None
+        }
+    }
+
+    pub fn set(&self) -> Option<&Set> {
+        match self {
+            Container::Set(s) => Some(s),
+            _ => None
+        }
+    }
+
+    pub fn sketch(&self) -> Option<&Sketch> {
+        match self {
+            Container::Sketch(s) => Some(s),
+            _ => None
+        }
+    }
+
+    pub fn entries(&self) -> Option<Entries<'_, T>> {
+        match self {
+            Container::Val(_) | Container::List(_) | Container::Queue(_) | Container::Set(_) | Container::Sketch(_) => None,
+            Container::Map(m) => Some(Entries::Hash(m.iter())),
+            Container::OrdMap(m) => Some(Entries::Ordered(m.iter()))
+        }
+    }
+
+    pub fn walk(&self) -> Walk<'_, T> {
+        match self.entries() {
+            Some(entries) => Walk{stack:vec![entries], path:vec![], root:None},
+            None => Walk{stack:vec![], path:vec![], root:self.value().ok()}
+        }
+    }
+
     pub fn value(&self) -> Result<&T, u8> {
         match self {
             Container::Val(v) => Ok(&v),
-            Container::Map(_) => Err(VBIN_CMAP_BEGIN)
+            Container::Map(_) => Err(VBIN_CMAP_BEGIN),
+            Container::OrdMap(_) => Err(VBIN_OMAP_BEGIN),
+            Container::List(_) => Err(VBIN_LIST_BEGIN),
+            Container::Queue(_) => Err(VBIN_QUEUE),
+            Container::Set(_) => Err(VBIN_SET_BEGIN),
+            Container::Sketch(s) => Err(s.vbin_type())
+        }
+    }
+
+    // The slot holding key, made if it isn't there yet
+    pub fn map_slot(&self, key:&[u8]) -> &Shared<Container<T>> {
+        match self {
+            Container::Val(v) => panic!("Expected Map, got Val({:?})", v),
+            Container::Map(m) => m.insert_bytes(key, keys::key_align(key)),
+            Container::OrdMap(m) => m.insert_bytes(key),
+            Container::List(l) => panic!("Expected Map, got List({:?})", l),
+            Container::Queue(q) => panic!("Expected Map, got Queue({:?})", q),
+            Container::Set(s) => panic!("Expected Map, got Set({:?})", s),
+            Container::Sketch(s) => panic!("Expected Map, got Sketch({:?})", s)
         }
     }
 
 	pub fn set_map(&self, key:&[u8], val:Container<T>) {
-		match self {
-			Container::Val(v) => panic!("Expected Map, got Val({:?})", v),
-			Container::Map(m) => m.insert_bytes(key, 8).write(TimePtr::make(val))
-		}
+		self.map_slot(key).write(TimePtr::make(val))
 	}
 
     pub fn create_set_map(&self, key:&[u8], slots_size:usize) -> &Container<T> {
-        match self {
-            Container::Val(v) => panic!("Expected Map, got Val({:?})", v),
-            Container::Map(m) => {
-                let location = m.insert_bytes(key, 8);
-                // first, check if map already exists
-                unsafe {
-                    match location.read().as_ref() {
-                        Some(loc_r) => match loc_r.0 {
-                            Container::Map(_) => return &loc_r.0,
-                            Container::Val(_) => () // can overwrite a val, proceed.
-                        },
-                        None => () // proceed to write
-                    }
-                }
-                location.write(TimePtr::make(Container::new_map(slots_size)));
-                // Do another read. This helps get the most up to date value.
-                unsafe {
-                    &location.read().as_ref().unwrap().0
-                }
+        let location = self.map_slot(key);
+        // first, check if map already exists
+        unsafe {
+            match location.read().as_ref() {
+                Some(loc_r) if loc_r.0.is_map() => return &loc_r.0,
+                _ => () // can overwrite a val, or write a new one
             }
         }
+        location.write(TimePtr::make(Container::new_map(slots_size)));
+        // Do another read. This helps get the most up to date value.
+        unsafe {
+            &location.read().as_ref().unwrap().0
+        }
     }
 
     pub fn get_map_shared(&self, key:&[u8]) -> Option<&Shared<Container<T>>> {
         match self {
             Container::Val(v) => panic!("Expected Map, got Val({:?})", v),
-            Container::Map(m) => m.find_bytes(key, 8)
+            Container::Map(m) => m.find_bytes(key, keys::key_align(key)),
+            Container::OrdMap(m) => m.find_bytes(key),
+            Container::List(l) => panic!("Expected Map, got List({:?})", l),
+            Container::Queue(q) => panic!("Expected Map, got Queue({:?})", q),
+            Container::Set(s) => panic!("Expected Map, got Set({:?})", s),
+            Container::Sketch(s) => panic!("Expected Map, got Sketch({:?})", s)
         }
     }
 
     pub fn get_map(&self, key:&[u8]) -> Option<&Container<T>> {
-        match self {
-            Container::Val(v) => panic!("Expected Map, got Val({:?})", v),
-            Container::Map(m) => match m.find_bytes(key, 8) {
-                Some(refval) => unsafe { match refval.read().as_ref() {
-                    Some(r) => Some(&r.0),
-                    None => None
-                }},
+        match self.get_map_shared(key) {
+            Some(refval) => unsafe { match refval.read().as_ref() {
+                Some(r) => {
+                    r.touch();
+                    Some(&r.0)
+                },
                 None => None
-            }
+            }},
+            None => None
+        }
+    }
+}
+
+impl Container<Value> {
+    // The VBIN_ tag this container is written with
+    pub fn vbin_type(&self) -> u8 {
+        match self {
+            Container::Val(v) => v.vbin_type(),
+            Container::Map(_) => VBIN_CMAP_BEGIN,
+            Container::OrdMap(_) => VBIN_OMAP_BEGIN,
+            Container::List(_) => VBIN_LIST_BEGIN,
+            Container::Queue(_) => VBIN_QUEUE,
+            Container::Set(_) => VBIN_SET_BEGIN,
+            Container::Sketch(s) => s.vbin_type()
         }
     }
 }
@@ -190,7 +381,12 @@ mod tests {
                  } },
                 None => panic!("Expected map {:?} to contain value for key {:?}", m, key)
             }
-            Container::Val(v) => panic!("Unexpected Value({:?})", v)
+            Container::Val(v) => panic!("Unexpected Value({:?})", v),
+            Container::OrdMap(m) => panic!("Unexpected ordered map {:?}", m),
+            Container::List(l) => panic!("Unexpected list {:?}", l),
+            Container::Queue(q) => panic!("Unexpected queue {:?}", q),
+            Container::Set(s) => panic!("Unexpected set {:?}", s),
+            Container::Sketch(s) => panic!("Unexpected sketch {:?}", s)
         }
     }
 
@@ -250,7 +446,12 @@ mod tests {
         let created = map.create_set_map(key, 30);
         match created {
             Container::Val(v) => panic!("Expected Map to be returned, got Val({:?})", v),
-            Container::Map(_) => () // This is expected
+            Container::Map(_) => (), // This is expected
+            Container::OrdMap(m) => panic!("Expected Map to be returned, got {:?}", m),
+            Container::List(l) => panic!("Expected Map to be returned, got {:?}", l),
+            Container::Queue(q) => panic!("Expected Map to be returned, got {:?}", q),
+            Container::Set(s) => panic!("Expected Map to be returned, got {:?}", s),
+            Container::Sketch(s) => panic!("Expected Map to be returned, got {:?}", s)
         }
         created.set_map(key2, val);
         // test for overwrite
@@ -260,6 +461,45 @@ mod tests {
         }
     }
 
+    #[test]
+    fn walk_works() {
+        tlocal::set_epoch();
+        let key1 = 1u64.to_le_bytes();
+        let key2 = 2u64.to_le_bytes();
+        let key3 = 3u64.to_le_bytes();
+        let map = Container::new_map(20);
+        map.set_map(&key1, Container::Val(TestType(1)));
+        let inner = map.create_set_map(&key2, 10);
+        inner.set_map(&key1, Container::Val(TestType(21)));
+        inner.create_set_map(&key3, 10).set_map(&key3, Container::Val(TestType(233)));
+        // empty maps have no leaves
+        map.create_set_map(&key3, 10);
+        let mut seen:Vec<(Vec<&[u8]>, u32)> = map.walk().map(|(path, v)| (path, v.0)).collect();
+        seen.sort_by_key(|(_, v)| *v);
+        assert_eq!(seen, vec![
+            (vec![&key1[..]], 1),
+            (vec![&key2[..], &key1[..]], 21),
+            (vec![&key2[..], &key3[..], &key3[..]], 233)
+        ]);
+        let val = Container::Val(TestType(5));
+        let leaves:Vec<_> = val.walk().collect();
+        assert_eq!(leaves.len(), 1);
+        assert!(leaves[0].0.is_empty());
+    }
+
+    #[test]
+    fn unaligned_keys_works() {
+        tlocal::set_epoch();
+        let map = Container::new_map(10);
+        map.set_map(b"user", Container::Val(TestType(1)));
+        map.set_map(b"user\0\0\0\0", Container::Val(TestType(2)));
+        map.set_map(b"", Container::Val(TestType(3)));
+        assert_eq!(map.get_map(b"user").unwrap().value().unwrap().0, 1);
+        assert_eq!(map.get_map(b"user\0\0\0\0").unwrap().value().unwrap().0, 2);
+        assert_eq!(map.get_map(b"").unwrap().value().unwrap().0, 3);
+        assert!(map.get_map(b"use").is_none());
+    }
+
     #[derive(Debug)]
     enum TestData {
         A,
@@ -357,6 +597,29 @@ mod tests {
         assert_eq!(out_vec[43], VBIN_CMAP_END);
     }
 
+    #[test]
+    fn tdata_unaligned_round_trip() {
+        tlocal::set_epoch();
+        let map = Container::new_map(10);
+        map.set_map(b"user", Container::Val(TestData::A));
+        map.create_set_map(b"nested", 10).set_map(b"abcdefghi", Container::Val(TestData::B));
+        let mut out_vec = vec![];
+        map.output_binary(&mut out_vec);
+        // keys are padded to whole words on the wire
+        assert_eq!(out_vec.len(), 1 + (1 + 16 + 1) + (1 + 16 + 1 + (1 + 24 + 1) + 1) + 1);
+        let mut i = 0;
+        let parsed = Container::<TestData>::input_binary(&out_vec, &mut i).expect("Cannot parse map from bytes");
+        assert_eq!(i, out_vec.len());
+        match parsed.get_map(b"user").unwrap() {
+            Container::Val(TestData::A) => (),
+            other => panic!("Expected A, got {:?}", other)
+        }
+        match parsed.get_map(b"nested").unwrap().get_map(b"abcdefghi").unwrap() {
+            Container::Val(TestData::B) => (),
+            other => panic!("Expected B, got {:?}", other)
+        }
+    }
+
     #[test]
     fn tdata_container_input() { 
         tlocal::set_epoch();
@@ -396,7 +659,12 @@ mod tests {
                     TestData::B => println!("{:?} passes", v),
                     TestData::A => panic!("Expected B, but got A")
                 },
-                Container::Map(m) => panic!("Expected parsed value. got map: {:?}", m)
+                Container::Map(m) => panic!("Expected parsed value. got map: {:?}", m),
+                Container::OrdMap(m) => panic!("Expected parsed value. got ordered map: {:?}", m),
+                Container::List(l) => panic!("Expected parsed value. got list: {:?}", l),
+                Container::Queue(q) => panic!("Expected parsed value. got queue: {:?}", q),
+                Container::Set(s) => panic!("Expected parsed value. got set: {:?}", s),
+                Container::Sketch(s) => panic!("Expected parsed value. got sketch: {:?}", s)
             },
             None => panic!("Expected value in parsed map for key {:?}", key1)
         }
@@ -407,7 +675,12 @@ mod tests {
                     TestData::A => println!("{:?} passes", v),
                     TestData::B => panic!("Expected A, but got B")
                 },
-                Container::Map(m) => panic!("Expected parsed value. got map: {:?}", m)
+                Container::Map(m) => panic!("Expected parsed value. got map: {:?}", m),
+                Container::OrdMap(m) => panic!("Expected parsed value. got ordered map: {:?}", m),
+                Container::List(l) => panic!("Expected parsed value. got list: {:?}", l),
+                Container::Queue(q) => panic!("Expected parsed value. got queue: {:?}", q),
+                Container::Set(s) => panic!("Expected parsed value. got set: {:?}", s),
+                Container::Sketch(s) => panic!("Expected parsed value. got sketch: {:?}", s)
             },
             None => panic!("Expected value in parsed map for key {:?}", key1)
         }
@@ -447,7 +720,12 @@ mod tests {
                     TestData::A => println!("{:?} passes", v),
                     TestData::B => panic!("Expected A, but got B")
                 },
-                Container::Map(m) => panic!("Expected parsed value. got map: {:?}", m)
+                Container::Map(m) => panic!("Expected parsed value. got map: {:?}", m),
+                Container::OrdMap(m) => panic!("Expected parsed value. got ordered map: {:?}", m),
+                Container::List(l) => panic!("Expected parsed value. got list: {:?}", l),
+                Container::Queue(q) => panic!("Expected parsed value. got queue: {:?}", q),
+                Container::Set(s) => panic!("Expected parsed value. got set: {:?}", s),
+                Container::Sketch(s) => panic!("Expected parsed value. got sketch: {:?}", s)
             },
             None => panic!("Expected value in parsed map for key {:?}", key1)
         }
@@ -497,7 +775,12 @@ mod tests {
                     TestData::A => println!("{:?} passes", v),
                     TestData::B => panic!("Expected A, but got B")
                 },
-                Container::Map(m) => panic!("Expected parsed value. got map: {:?}", m)
+                Container::Map(m) => panic!("Expected parsed value. got map: {:?}", m),
+                Container::OrdMap(m) => panic!("Expected parsed value. got ordered map: {:?}", m),
+                Container::List(l) => panic!("Expected parsed value. got list: {:?}", l),
+                Container::Queue(q) => panic!("Expected parsed value. got queue: {:?}", q),
+                Container::Set(s) => panic!("Expected parsed value. got set: {:?}", s),
+                Container::Sketch(s) => panic!("Expected parsed value. got sketch: {:?}", s)
             },
             None => panic!("Expected value in parsed map for key {:?}", key1)
         }
@@ -510,11 +793,21 @@ mod tests {
                             TestData::B => println!("{:?} passes", v),
                             TestData::A => panic!("Expected B but got A")
                         },
-                        Container::Map(m) => panic!("Expected inner nest value, got map: {:?}", m)
+                        Container::Map(m) => panic!("Expected inner nest value, got map: {:?}", m),
+                        Container::OrdMap(m) => panic!("Expected inner nest value, got ordered map: {:?}", m),
+                        Container::List(l) => panic!("Expected inner nest value, got list: {:?}", l),
+                        Container::Queue(q) => panic!("Expected inner nest value, got queue: {:?}", q),
+                        Container::Set(s) => panic!("Expected inner nest value, got set: {:?}", s),
+                        Container::Sketch(s) => panic!("Expected inner nest value, got sketch: {:?}", s)
                     },
                     None => panic!("Expected value for inner nested key {:?}", key1)
                 },
-                Container::Val(v) => panic!("Expected parsed map. got val: {:?}", v)
+                Container::Val(v) => panic!("Expected parsed map. got val: {:?}", v),
+                Container::OrdMap(m) => panic!("Expected parsed map. got ordered map: {:?}", m),
+                Container::List(l) => panic!("Expected parsed map. got list: {:?}", l),
+                Container::Queue(q) => panic!("Expected parsed map. got queue: {:?}", q),
+                Container::Set(s) => panic!("Expected parsed map. got set: {:?}", s),
+                Container::Sketch(s) => panic!("Expected parsed map. got sketch: {:?}", s)
             },
             None => panic!("Expected value in parsed map for key {:?}", key1)
         }
@@ -561,4 +854,57 @@ mod tests {
         assert_eq!(out_vec[85], VBIN_CMAP_END);
         assert_eq!(out_vec[86], VBIN_CMAP_END);
     }
+
+    // Every cut of a whole encoding has to be refused, not read past
+    fn assert_cut_short(encoded:&[u8]) {
+        for cut in 0..encoded.len() {
+            let mut place = 0;
+            match Container::<Value>::input_binary(&encoded[..cut], &mut place) {
+                Err(FlotonErr::BadRequest(_)) => (),
+                other => panic!("Expected bad request for {} of {} bytes, got {:?}", cut, encoded.len(), other)
+            }
+        }
+        let mut place = 0;
+        assert!(Container::<Value>::input_binary(encoded, &mut place).is_ok());
+        assert_eq!(place, encoded.len());
+    }
+
+    #[test]
+    fn list_cut_short_input() {
+        tlocal::set_epoch();
+        let mut encoded = vec![VBIN_LIST_BEGIN];
+        Value::UInt(1).output_binary(&mut encoded);
+        Value::Bool(true).output_binary(&mut encoded);
+        encoded.push(VBIN_LIST_END);
+        assert_cut_short(&encoded);
+    }
+
+    #[test]
+    fn queue_cut_short_input() {
+        tlocal::set_epoch();
+        let mut encoded = vec![VBIN_QUEUE];
+        encoded.extend_from_slice(&16u64.to_le_bytes());
+        assert_cut_short(&encoded);
+    }
+
+    #[test]
+    fn set_cut_short_input() {
+        tlocal::set_epoch();
+        let mut encoded = vec![VBIN_SET_BEGIN];
+        keys::write_key(b"red", &mut encoded);
+        keys::write_key(b"blue", &mut encoded);
+        encoded.push(VBIN_SET_END);
+        assert_cut_short(&encoded);
+    }
+
+    #[test]
+    fn map_cut_short_input() {
+        tlocal::set_epoch();
+        let map = Container::new_map(10);
+        map.set_map(b"a", Container::Val(Value::UInt(1)));
+        map.set_map(b"bb", Container::new_ordered_map());
+        let mut encoded = vec![];
+        map.output_binary(&mut encoded);
+        assert_cut_short(&encoded);
+    }
 }
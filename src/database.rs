@@ -1,15 +1,24 @@
-use std::sync::atomic::{AtomicPtr, AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicPtr, AtomicU8, AtomicUsize, Ordering};
 use std::ptr;
+use std::time::{Duration, Instant};
 use crate::containers::Container;
 use crate::values::Value;
-use crate::processors;
-use crate::tcp::{TcpServer, TcpServerStream, TcpServerContext};
+use crate::processors::{self, Blocked};
+use crate::tcp::{TcpServer, TcpServerStream, TcpServerContext, TcpServerOpts, TcpServerStats, ConnLimits, HeldRequest};
 use crate::threading::Parker;
-use crate::settings::Settings;
+use crate::reclaim::{self, Collector};
+use crate::memory::Memory;
+use crate::stats::{CmdStats, CMD_NAMES};
+use crate::metrics::MetricsServer;
+use crate::slowlog::SlowLog;
+use crate::slab;
+use crate::settings::{Settings, ConfigErr};
 use crate::requests::Request;
 use crate::responses::Response;
 use crate::constants::*;
 use crate::tlocal;
+use crate::hashtree;
 use crate::traits::*;
 use crate::logging::*;
 
@@ -46,36 +55,193 @@ impl DatabaseState {
 
 #[derive(Debug)]
 pub struct Database {
-	settings:Settings,
+	// as they are now, changed by CONFIG_SET
+	settings:Mutex<Settings>,
+	// read for every map made and value freed, so kept out of the lock
+	map_slots:AtomicUsize,
+	// read for every queue made
+	max_queue_len:AtomicUsize,
 	data:Container<Value>,
+	memory:Memory,
+	cmd_stats:CmdStats,
+	slowlog:SlowLog,
+	started:Instant,
 	server:AtomicPtr<TcpServer<Database>>,
+	collector:Option<Collector>,
+	metrics:Option<MetricsServer>,
 	state:DatabaseState
 }
 
 
 impl Database {
 	fn tcp_handler(obj_ptr:*mut TcpServerStream<Database>) {
-		let cstream = unsafe { obj_ptr.as_ref().unwrap() };
-		tlocal::set_db(cstream.get_ptr());
-		let context = cstream.get_ctx();
 		let tstream = unsafe { obj_ptr.as_mut().unwrap() };
-		let mut output:Vec<u8> = vec![];
-		let req = Request::parse(&mut tstream.0).unwrap();
-		processors::run_cmd(&req.body, &context.data, &mut output);
-		let resp = Response::from_vec(output);
-		resp.to_tcp_stream(&mut tstream.0);
+		tlocal::set_db(tstream.get_ptr());
+		tlocal::set_client(tstream.peer());
+		let db = unsafe { tstream.get_ptr().as_ref().unwrap() };
+		// A request blocked on a queue goes before anything after it
+		if let Some(held) = tstream.take_held() {
+			if !db.run_request(tstream, held, true) {
+				return;
+			}
+		}
+		// Connections are persistent, run every request that has fully arrived
+		while let Some(req) = Request::parse(tstream) {
+			db.cmd_stats.count_bytes(req.body.len() + 8, 0);
+			if !db.run_request(tstream, HeldRequest{body:req.body, output:vec![], place:0, until:None}, false) {
+				return;
+			}
+		}
+	}
+
+	// Runs a request from where it was held, returning false if it has
+	// to wait on a queue, which parks the connection until a push. A
+	// resumed request doesn't count the pop it waited on again.
+	fn run_request(&self, tstream:&mut TcpServerStream<Database>, mut req:HeldRequest, resumed:bool) -> bool {
+		let can_block = match req.until {
+			Some(until) => Instant::now() < until,
+			None => true
+		};
+		// nothing read from the data is freed while pinned
+		reclaim::pin();
+		let ran = match processors::run_cmd_at(req.place, resumed, &req.body, &self.data, &mut req.output, can_block) {
+			None => {
+				let resp = Response::from_vec(req.output);
+				self.cmd_stats.count_bytes(0, resp.size() as usize + 8);
+				resp.to_server_stream(tstream);
+				true
+			},
+			Some(Blocked{place, timeout, queue}) => {
+				req.place = place;
+				// a request woken early keeps waiting to its first deadline
+				if req.until.is_none() && timeout > 0 {
+					req.until = Some(Instant::now() + Duration::from_millis(timeout));
+				}
+				queue.wait(Box::new(tstream.park(req)));
+				false
+			}
+		};
+		reclaim::unpin();
+		ran
 	}
 
 	pub fn get_free_lim(&self) -> u32 {
-		self.settings.th_free_lim
+		reclaim::free_lim() as u32
 	}
 
 	pub fn get_port(&self) -> u16 {
-		self.settings.db_port
+		self.settings.lock().unwrap().db_port
 	}
 
 	pub fn get_map_slots(&self) -> usize {
-		self.settings.db_map_slots
+		self.map_slots.load(Ordering::Relaxed)
+	}
+
+	pub fn get_max_queue_len(&self) -> usize {
+		self.max_queue_len.load(Ordering::Relaxed)
+	}
+
+	pub fn settings(&self) -> Settings {
+		self.settings.lock().unwrap().clone()
+	}
+
+	// Changes a setting while running, from its argument like --tcp-park-max=500
+	pub fn set_config(&self, arg:&str) -> Result<(), ConfigErr> {
+		let mut settings = self.settings.lock().unwrap();
+		settings.set_live(arg)?;
+		self.map_slots.store(settings.db_map_slots, Ordering::Relaxed);
+		self.max_queue_len.store(settings.max_queue_len, Ordering::Relaxed);
+		reclaim::set_free_lim(settings.th_free_lim as usize);
+		self.slowlog.set_threshold(settings.slowlog_threshold_us);
+		GLOBAL_LOGGING_LEVEL.store(settings.log_level, Ordering::Relaxed);
+		if let Some(server) = unsafe { self.server.load(Ordering::SeqCst).as_ref() } {
+			server.tune(Parker::new(settings.tcp_park_min, settings.tcp_park_max, settings.tcp_park_seg), settings.conn_scale());
+		}
+		log_info!(Database, "Setting changed to {}", arg);
+		Ok(())
+	}
+
+	pub fn memory(&self) -> &Memory {
+		&self.memory
+	}
+
+	pub fn cmd_stats(&self) -> &CmdStats {
+		&self.cmd_stats
+	}
+
+	pub fn slowlog(&self) -> &SlowLog {
+		&self.slowlog
+	}
+
+	// Only there once constructed
+	pub fn server_stats(&self) -> Option<&TcpServerStats> {
+		unsafe { self.server.load(Ordering::SeqCst).as_ref() }.map(|server| server.stats())
+	}
+
+	// A map of how the server is doing, as given back by CMD_INFO
+	pub fn info(&self) -> Container<Value> {
+		let uint = |map:&Container<Value>, name:&[u8], val:usize| map.set_map(name, Container::Val(Value::UInt(val as u64)));
+		let info = Container::new_map(16);
+		uint(&info, b"uptime_ms", self.started.elapsed().as_millis() as usize);
+		uint(&info, b"state", self.state.0.load(Ordering::Acquire) as usize);
+		// keyed by the argument giving each setting, text can't be a value
+		let settings = Container::new_map(32);
+		for arg in self.settings().to_args() {
+			settings.set_map(arg.as_bytes(), Container::Val(Value::Nothing));
+		}
+		info.set_map(b"settings", settings);
+		if let Some(stats) = self.server_stats() {
+			let conns = Container::new_map(8);
+			uint(&conns, b"open", stats.conns.load(Ordering::Relaxed));
+			uint(&conns, b"refused", stats.refused.load(Ordering::Relaxed) as usize);
+			uint(&conns, b"timed_out", stats.timed_out.load(Ordering::Relaxed) as usize);
+			uint(&conns, b"parked", stats.parked.load(Ordering::Relaxed));
+			info.set_map(b"conns", conns);
+			let workers = Container::new_map(8);
+			uint(&workers, b"count", stats.workers.load(Ordering::Relaxed));
+			uint(&workers, b"queued", stats.worker_queue.load(Ordering::Relaxed));
+			uint(&workers, b"backlog", stats.backlog_len.load(Ordering::Relaxed));
+			uint(&workers, b"backlogged", stats.queued.load(Ordering::Relaxed) as usize);
+			uint(&workers, b"rejected", stats.rejected.load(Ordering::Relaxed) as usize);
+			info.set_map(b"workers", workers);
+		}
+		let commands = Container::new_map(16);
+		for (cmd, name) in CMD_NAMES.iter().enumerate().skip(1) {
+			let counts = Container::new_map(2);
+			uint(&counts, b"runs", self.cmd_stats.runs(cmd as u8) as usize);
+			uint(&counts, b"errors", self.cmd_stats.errors(cmd as u8) as usize);
+			commands.set_map(name.as_bytes(), counts);
+		}
+		info.set_map(b"commands", commands);
+		if let Container::Map(top) = &self.data {
+			let keys = Container::new_map(8);
+			uint(&keys, b"total", top.len());
+			// the keys in each top level map
+			let maps = Container::new_map(16);
+			for (key, slot) in top.iter() {
+				match unsafe { slot.read().as_ref() }.map(|version| &version.0) {
+					Some(Container::Map(m)) => uint(&maps, key, m.len()),
+					Some(Container::OrdMap(m)) => uint(&maps, key, m.len()),
+					_ => ()
+				}
+			}
+			keys.set_map(b"maps", maps);
+			info.set_map(b"keys", keys);
+		}
+		let reclaim = Container::new_map(4);
+		uint(&reclaim, b"epoch", reclaim::epoch() as usize);
+		uint(&reclaim, b"pending", reclaim::pending());
+		uint(&reclaim, b"pending_bytes", reclaim::pending_bytes());
+		info.set_map(b"reclaim", reclaim);
+		let memory = Container::new_map(8);
+		uint(&memory, b"used", self.memory.used());
+		uint(&memory, b"live", self.memory.live());
+		uint(&memory, b"limit", self.memory.limit());
+		uint(&memory, b"evicted", self.memory.evicted());
+		uint(&memory, b"slab_reserved", slab::reserved());
+		info.set_map(b"memory", memory);
+		uint(&info, b"threads", tlocal::live_threads());
+		info
 	}
 
 	pub fn new_for_testing() -> Database {
@@ -86,27 +252,60 @@ impl Database {
 
 	pub fn new_from_settings(settings:Settings) -> Database {
 		let slots_size = settings.db_map_slots;
-		Database{settings:settings, 
+		hashtree::set_default_scheme(settings.hash_kind, settings.hash_seed);
+		reclaim::set_free_lim(settings.th_free_lim as usize);
+		let memory = Memory::new(settings.max_memory, settings.evict_policy, settings.evict_samples);
+		let slowlog = SlowLog::new(settings.slowlog_threshold_us, settings.slowlog_len);
+		Database{map_slots:AtomicUsize::new(settings.db_map_slots),
+			     max_queue_len:AtomicUsize::new(settings.max_queue_len),
+			     settings:Mutex::new(settings),
 			     data:Container::new_map(slots_size),
+			     memory:memory,
+			     cmd_stats:CmdStats::new(),
+			     slowlog:slowlog,
+			     started:Instant::now(),
 			     server:newptr!(),
+			     collector:None,
+			     metrics:None,
 			     state:DatabaseState::new()}
 	}
 
 	pub fn construct(&mut self) {
-		let parker = Parker::new(self.settings.tcp_park_min, 
-			                     self.settings.tcp_park_max, 
-			                     self.settings.tcp_park_seg);
+		let settings = self.settings();
+		GLOBAL_LOGGING_LEVEL.store(settings.log_level, Ordering::Relaxed);
+		let mut opts = TcpServerOpts::new();
+		opts.parker = Parker::new(settings.tcp_park_min, 
+			                      settings.tcp_park_max, 
+			                      settings.tcp_park_seg);
+		opts.backlog = settings.conn_backlog;
+		opts.retry_after = settings.conn_retry_after;
+		opts.max_conns = settings.max_conns;
+		opts.scale = settings.conn_scale();
+		opts.limits = ConnLimits{max_request_size:settings.max_request_size,
+		                         read_timeout:settings.read_timeout_ms,
+		                         write_timeout:settings.write_timeout_ms,
+		                         idle_timeout:settings.idle_timeout_ms};
 		// Can't borrow immutably from mut, so need to clone
-		let serv_addr = self.settings.serv_addr.clone();
-		let serv = TcpServer::new(self.settings.conn_th_count, 
-			     	                      self.settings.conn_queue_size, 
+		let serv_addr = settings.serv_addr.clone();
+		let serv = TcpServer::new(settings.conn_th_count, 
+			     	                      settings.conn_queue_size, 
 			     	                      &serv_addr, 
-			     	                      self.settings.db_port, 
-			     	                      &parker,
+			     	                      settings.db_port, 
+			     	                      &opts,
 			     	                      Database::tcp_handler,
 			     	                      TcpServerContext::new(self));
 
 		self.server.store(alloc!(serv), Ordering::SeqCst);
+		if settings.reclaim_ms > 0 {
+			self.collector = Some(Collector::start(Duration::from_millis(settings.reclaim_ms)));
+		}
+		if settings.metrics_port > 0 {
+			// the database still runs without them
+			match MetricsServer::start(&serv_addr, settings.metrics_port, TcpServerContext::new(self)) {
+				Ok(metrics) => self.metrics = Some(metrics),
+				Err(e) => log_error!(Database, "Could not serve metrics on port {}, got {}", settings.metrics_port, e)
+			}
+		}
 
 	}
 
@@ -128,6 +327,12 @@ impl Database {
 			let serv_ptr = self.server.load(Ordering::Acquire);
 			unsafe { serv_ptr.as_mut().unwrap().stop(); }
 			free!(serv_ptr);
+			if let Some(mut collector) = self.collector.take() {
+				collector.stop();
+			}
+			if let Some(mut metrics) = self.metrics.take() {
+				metrics.stop();
+			}
 		}
 	}
 }
@@ -138,6 +343,9 @@ mod tests {
     use std::net::TcpStream;
     use std::io::prelude::*;
     use std::convert::TryInto;
+    use std::thread;
+    use crate::keys;
+    use crate::errors::FlotonErr;
 
     #[test]
     fn db_state_works() {
@@ -202,7 +410,7 @@ mod tests {
         log_debug!(TESTbasic_set_get_works, "Get Req: {:?}", get_cmd);
         db.construct();
         db.start();
-        let mut client = TcpStream::connect((db.settings.serv_addr.as_str(), db.settings.db_port)).expect("Could not connect to db port and addr");
+        let mut client = TcpStream::connect((db.settings().serv_addr.as_str(), db.get_port())).expect("Could not connect to db port and addr");
         log_trace!(TESTbasic_set_get_works, "Connecting from {}, to {}", 
         	                             client.local_addr().unwrap(), 
         	                             client.peer_addr().unwrap());
@@ -218,7 +426,7 @@ mod tests {
         assert_eq!(resp_header[6], 0);
         assert_eq!(resp_header[7], 0);
 
-        let mut client2 = TcpStream::connect((db.settings.serv_addr.as_str(), db.settings.db_port)).expect("Could not connect to db port and addr");
+        let mut client2 = TcpStream::connect((db.settings().serv_addr.as_str(), db.get_port())).expect("Could not connect to db port and addr");
         client2.write_all(&get_cmd).expect("Could not write the get request");
         client2.read_exact(&mut resp_header).expect("Could not read back from get resp header");
         let get_resp_size = u64::from_le_bytes(resp_header);
@@ -229,4 +437,188 @@ mod tests {
         assert_eq!(1, resp_body[1]);
         db.stop();
     }
-}
\ No newline at end of file
+
+    fn with_header(cmd:&[u8]) -> Vec<u8> {
+    	let mut req = (cmd.len() as u64).to_le_bytes().to_vec();
+    	req.extend_from_slice(cmd);
+    	req
+    }
+
+    fn read_response(client:&mut TcpStream) -> Vec<u8> {
+    	let mut header = [0;8];
+    	client.read_exact(&mut header).expect("Could not read response header");
+    	let mut body = vec![0; u64::from_le_bytes(header) as usize];
+    	client.read_exact(&mut body).expect("Could not read response body");
+    	body
+    }
+
+    fn queue_cmd(cmd:&mut Vec<u8>, cmd_type:u8) {
+    	cmd.push(cmd_type);
+    	cmd.extend_from_slice(&1u64.to_le_bytes());
+    	keys::write_key(b"jobs", cmd);
+    }
+
+    // Starts db with two workers that can't grow, and a queue at jobs. The
+    // server keeps a pointer to db, so it can't be moved once started.
+    fn start_queue_db(db:&mut Database) -> TcpStream {
+    	let settings = db.settings.get_mut().unwrap();
+    	settings.conn_th_count = 2;
+    	settings.conn_max_threads = 2;
+    	db.construct();
+    	db.start();
+    	let mut client = TcpStream::connect((db.settings().serv_addr.as_str(), db.get_port())).expect("Could not connect to db port and addr");
+    	let mut cmd = vec![CMD_SET_KV];
+    	cmd.extend_from_slice(&1u64.to_le_bytes());
+    	keys::write_key(b"jobs", &mut cmd);
+    	cmd.push(VBIN_QUEUE);
+    	cmd.extend_from_slice(&8u64.to_le_bytes());
+    	cmd.push(CMD_STOP);
+    	client.write_all(&with_header(&cmd)).unwrap();
+    	assert!(read_response(&mut client).is_empty());
+    	client
+    }
+
+    #[test]
+    fn qbpop_wakes_works() {
+    	tlocal::set_epoch();
+    	let mut db = Database::new_for_testing();
+    	let mut pusher = start_queue_db(&mut db);
+    	let mut cmd = vec![];
+    	queue_cmd(&mut cmd, CMD_QBPOP);
+    	cmd.extend_from_slice(&5000u64.to_le_bytes());
+    	cmd.push(CMD_STOP);
+    	let started = Instant::now();
+    	let mut poppers = vec![];
+    	for _ in 0..2 {
+    		let mut popper = TcpStream::connect((db.settings().serv_addr.as_str(), db.get_port())).unwrap();
+    		popper.write_all(&with_header(&cmd)).unwrap();
+    		poppers.push(popper);
+    	}
+    	thread::sleep(Duration::from_millis(50));
+    	// both workers are free again while the pops wait
+    	let mut cmd = vec![];
+    	for i in 0..2u64 {
+    		queue_cmd(&mut cmd, CMD_QPUSH);
+    		cmd.push(VBIN_UINT);
+    		cmd.extend_from_slice(&i.to_le_bytes());
+    	}
+    	cmd.push(CMD_STOP);
+    	pusher.write_all(&with_header(&cmd)).unwrap();
+    	assert!(read_response(&mut pusher).is_empty());
+    	let mut popped:Vec<u8> = poppers.iter_mut().map(|p| {
+    		let resp = read_response(p);
+    		assert_eq!(resp.len(), 9);
+    		assert_eq!(resp[0], VBIN_UINT);
+    		resp[1]
+    	}).collect();
+    	popped.sort();
+    	assert_eq!(popped, vec![0, 1]);
+    	assert!(started.elapsed() < Duration::from_millis(5000));
+    	db.stop();
+    }
+
+    #[test]
+    fn qbpop_timeout_works() {
+    	tlocal::set_epoch();
+    	let mut db = Database::new_for_testing();
+    	let mut client = start_queue_db(&mut db);
+    	// output before the pop is kept, and the request after it waits its turn
+    	let mut cmd = vec![];
+    	queue_cmd(&mut cmd, CMD_QPUSH);
+    	cmd.push(VBIN_BOOL);
+    	cmd.push(1);
+    	queue_cmd(&mut cmd, CMD_QPOP);
+    	queue_cmd(&mut cmd, CMD_QBPOP);
+    	cmd.extend_from_slice(&50u64.to_le_bytes());
+    	cmd.push(CMD_STOP);
+    	let mut reqs = with_header(&cmd);
+    	let mut cmd = vec![];
+    	queue_cmd(&mut cmd, CMD_QPOP);
+    	cmd.push(CMD_STOP);
+    	reqs.extend(with_header(&cmd));
+    	let started = Instant::now();
+    	client.write_all(&reqs).unwrap();
+    	assert_eq!(read_response(&mut client), vec![VBIN_BOOL, 1, VBIN_NOTHING]);
+    	assert!(started.elapsed() >= Duration::from_millis(50));
+    	assert_eq!(read_response(&mut client), vec![VBIN_NOTHING]);
+    	db.stop();
+    }
+
+    fn info_uint(info:&Container<Value>, path:&[&[u8]]) -> u64 {
+    	let (last, parents) = path.split_last().unwrap();
+    	let map = parents.iter().fold(info, |map, key| map.get_map(key).unwrap());
+    	map.get_map(last).unwrap().value().unwrap().to_uint()
+    }
+
+    #[test]
+    fn info_works() {
+    	tlocal::set_epoch();
+    	let mut db = Database::new_for_testing();
+    	db.construct();
+    	db.start();
+    	let mut client = TcpStream::connect((db.settings().serv_addr.as_str(), db.get_port())).unwrap();
+    	let mut cmd = vec![CMD_SET_KV];
+    	cmd.extend_from_slice(&2u64.to_le_bytes());
+    	keys::write_key(b"users", &mut cmd);
+    	keys::write_key(b"ann", &mut cmd);
+    	cmd.push(VBIN_BOOL);
+    	cmd.push(1);
+    	cmd.push(CMD_RETURN_KV);
+    	cmd.extend_from_slice(&1u64.to_le_bytes());
+    	keys::write_key(b"missing", &mut cmd);
+    	cmd.push(CMD_INFO);
+    	cmd.push(CMD_STOP);
+    	client.write_all(&with_header(&cmd)).unwrap();
+    	let resp = read_response(&mut client);
+    	let mut place = 0;
+    	assert!(matches!(FlotonErr::input_binary(&resp, &mut place), Ok(FlotonErr::ReturnNotFound(_))));
+    	let info = Container::<Value>::input_binary(&resp, &mut place).unwrap();
+    	assert_eq!(place, resp.len());
+    	assert_eq!(info_uint(&info, &[b"state"]), DBSTATE_OK as u64);
+    	assert_eq!(info_uint(&info, &[b"commands", b"set_kv", b"runs"]), 1);
+    	assert_eq!(info_uint(&info, &[b"commands", b"return_kv", b"errors"]), 1);
+    	// counted as it runs
+    	assert_eq!(info_uint(&info, &[b"commands", b"info", b"runs"]), 1);
+    	assert_eq!(info_uint(&info, &[b"keys", b"total"]), 1);
+    	assert_eq!(info_uint(&info, &[b"keys", b"maps", b"users"]), 1);
+    	assert_eq!(info_uint(&info, &[b"conns", b"open"]), 1);
+    	assert!(info_uint(&info, &[b"workers", b"count"]) >= 1);
+    	assert!(info_uint(&info, &[b"memory", b"live"]) > 0);
+    	let port = format!("--port={}", db.get_port());
+    	assert!(info.get_map(b"settings").unwrap().get_map(port.as_bytes()).is_some());
+    	db.stop();
+    }
+
+    #[test]
+    fn set_config_works() {
+    	tlocal::set_epoch();
+    	let mut db = Database::new_for_testing();
+    	db.construct();
+    	db.start();
+    	assert_eq!(db.set_config("--tcp-park-max=200"), Ok(()));
+    	assert_eq!(db.set_config("--conn-max-threads=8"), Ok(()));
+    	assert_eq!(db.set_config("--thread-free-limit=9"), Ok(()));
+    	assert_eq!(db.set_config("--host=0.0.0.0"), Err(ConfigErr::ReadOnly));
+    	assert_eq!(db.set_config("--conn-max-threads=1"), Err(ConfigErr::Invalid));
+    	assert_eq!(db.get_free_lim(), 9);
+    	let settings = db.settings();
+    	assert_eq!(settings.tcp_park_max, 200);
+    	assert_eq!(settings.conn_max_threads, 8);
+    	assert_eq!(settings.serv_addr, "127.0.0.1");
+    	// the server keeps going once retuned
+    	let mut client = TcpStream::connect((settings.serv_addr.as_str(), db.get_port())).unwrap();
+    	let mut cmd = vec![CMD_CONFIG_GET, CMD_STOP];
+    	client.write_all(&with_header(&cmd)).unwrap();
+    	let resp = read_response(&mut client);
+    	let config = Container::<Value>::input_binary(&resp, &mut 0).unwrap();
+    	assert!(config.get_map(b"--tcp-park-max=200").is_some());
+    	cmd = vec![CMD_CONFIG_SET];
+    	cmd.extend_from_slice(&1u64.to_le_bytes());
+    	keys::write_key(b"--reclaim-ms=5", &mut cmd);
+    	cmd.push(CMD_STOP);
+    	client.write_all(&with_header(&cmd)).unwrap();
+    	let resp = read_response(&mut client);
+    	assert!(matches!(FlotonErr::input_binary(&resp, &mut 0), Ok(FlotonErr::ConfigReadOnly(_))));
+    	db.stop();
+    }
+}
@@ -0,0 +1,148 @@
+extern crate libc;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::Ordering;
+use crate::logging::*;
+
+// Thin wrappers over the linux epoll and eventfd calls, used to
+// wait on socket readiness instead of polling.
+
+pub const EV_READ:u32 = libc::EPOLLIN as u32;
+pub const EV_WRITE:u32 = libc::EPOLLOUT as u32;
+// Once an event fires for a registered fd, it is disabled until re-armed.
+// This guarantees only one worker touches a connection at a time.
+pub const EV_ONESHOT:u32 = libc::EPOLLONESHOT as u32;
+
+#[derive(Debug)]
+pub struct Epoll(RawFd);
+
+impl Drop for Epoll {
+	fn drop(&mut self) {
+		unsafe { libc::close(self.0); }
+	}
+}
+
+impl Epoll {
+	pub fn new() -> io::Result<Epoll> {
+		let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+		if fd < 0 {
+			Err(io::Error::last_os_error())
+		} else {
+			Ok(Epoll(fd))
+		}
+	}
+
+	fn ctl(&self, op:i32, fd:RawFd, token:u64, events:u32) -> io::Result<()> {
+		let mut event = libc::epoll_event{events, u64:token};
+		if unsafe { libc::epoll_ctl(self.0, op, fd, &mut event) } < 0 {
+			Err(io::Error::last_os_error())
+		} else {
+			Ok(())
+		}
+	}
+
+	pub fn add(&self, fd:RawFd, token:u64, events:u32) -> io::Result<()> {
+		self.ctl(libc::EPOLL_CTL_ADD, fd, token, events)
+	}
+
+	pub fn modify(&self, fd:RawFd, token:u64, events:u32) -> io::Result<()> {
+		self.ctl(libc::EPOLL_CTL_MOD, fd, token, events)
+	}
+
+	pub fn delete(&self, fd:RawFd) -> io::Result<()> {
+		self.ctl(libc::EPOLL_CTL_DEL, fd, 0, 0)
+	}
+
+	// Waits for events, with a timeout in milliseconds, -1 waits forever.
+	// The ready events are placed into events, up to its capacity.
+	pub fn wait(&self, events:&mut Vec<libc::epoll_event>, timeout:i32) -> io::Result<usize> {
+		let cap = events.capacity();
+		let got = unsafe { libc::epoll_wait(self.0, events.as_mut_ptr(), cap as i32, timeout) };
+		if got < 0 {
+			let err = io::Error::last_os_error();
+			if err.kind() == io::ErrorKind::Interrupted {
+				unsafe { events.set_len(0); }
+				return Ok(0);
+			}
+			Err(err)
+		} else {
+			unsafe { events.set_len(got as usize); }
+			Ok(got as usize)
+		}
+	}
+}
+
+/**
+ * Used to wake a thread blocked in Epoll::wait from another thread
+ */
+#[derive(Debug)]
+pub struct EventFd(RawFd);
+
+impl Drop for EventFd {
+	fn drop(&mut self) {
+		unsafe { libc::close(self.0); }
+	}
+}
+
+impl EventFd {
+	pub fn new() -> io::Result<EventFd> {
+		let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+		if fd < 0 {
+			Err(io::Error::last_os_error())
+		} else {
+			Ok(EventFd(fd))
+		}
+	}
+
+	pub fn fd(&self) -> RawFd {
+		self.0
+	}
+
+	pub fn wake(&self) {
+		let one:u64 = 1;
+		let wrote = unsafe { libc::write(self.0, &one as *const u64 as *const libc::c_void, 8) };
+		if wrote != 8 {
+			log_error!(Epoll, "Could not write to eventfd, err: {}", io::Error::last_os_error());
+		}
+	}
+
+	// Resets the counter so the fd stops reporting readable
+	pub fn drain(&self) {
+		let mut val:u64 = 0;
+		unsafe { libc::read(self.0, &mut val as *mut u64 as *mut libc::c_void, 8); }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eventfd_wakes_epoll() {
+    	let poller = Epoll::new().expect("Could not create epoll");
+    	let waker = EventFd::new().expect("Could not create eventfd");
+    	poller.add(waker.fd(), 7, EV_READ).expect("Could not register eventfd");
+    	let mut events = Vec::with_capacity(4);
+    	assert_eq!(poller.wait(&mut events, 0).unwrap(), 0);
+    	waker.wake();
+    	assert_eq!(poller.wait(&mut events, 1000).unwrap(), 1);
+    	let token = events[0].u64;
+    	assert_eq!(token, 7);
+    	waker.drain();
+    	assert_eq!(poller.wait(&mut events, 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn oneshot_disarms() {
+    	let poller = Epoll::new().expect("Could not create epoll");
+    	let waker = EventFd::new().expect("Could not create eventfd");
+    	poller.add(waker.fd(), 3, EV_READ | EV_ONESHOT).expect("Could not register eventfd");
+    	waker.wake();
+    	let mut events = Vec::with_capacity(4);
+    	assert_eq!(poller.wait(&mut events, 1000).unwrap(), 1);
+    	// still readable, but disarmed
+    	assert_eq!(poller.wait(&mut events, 0).unwrap(), 0);
+    	poller.modify(waker.fd(), 3, EV_READ | EV_ONESHOT).expect("Could not re-arm eventfd");
+    	assert_eq!(poller.wait(&mut events, 1000).unwrap(), 1);
+    }
+}
@@ -1,4 +1,5 @@
 use std::slice;
+use std::convert::TryInto;
 use std::sync::atomic::Ordering;
 use crate::constants::*;
 use crate::traits::*;
@@ -14,7 +15,20 @@ pub enum FlotonErr {
 	ReturnNotFound(*const u64),
 	UnexpectedByte(u8),
 	TypeNotAtomic(*const u64, u8),
-    OperationNoSupport(*const u64, u8, u16)
+    OperationNoSupport(*const u64, u8, u16),
+    ServerBusy(u32 /*retry after, ms*/),
+    RequestTooLarge(u64 /*max size*/),
+    TooManyConnections,
+    ReadTimeout,
+    WriteTimeout,
+    IdleTimeout,
+    QueueFull(*const u64),
+    SketchMismatch(*const u64),
+    OutOfMemory(*const u64),
+    ConfigReadOnly(*const u64),
+    ConfigInvalid(*const u64),
+    QueueTooLong(u64 /*max len*/),
+    BadRequest(u64 /*place*/)
 }
 
 impl InPutOutPut for FlotonErr {
@@ -41,6 +55,46 @@ impl InPutOutPut for FlotonErr {
                 output.push(*t);
                 output.extend_from_slice(&o.to_le_bytes());
                 keys::key_u64_out_vu8(*key, output);
+            },
+            FlotonErr::ServerBusy(retry) => {
+                output.push(ERR_SERVER_BUSY);
+                output.extend_from_slice(&retry.to_le_bytes());
+            },
+            FlotonErr::RequestTooLarge(max) => {
+                output.push(ERR_REQUEST_TOO_LARGE);
+                output.extend_from_slice(&max.to_le_bytes());
+            },
+            FlotonErr::TooManyConnections => output.push(ERR_TOO_MANY_CONNS),
+            FlotonErr::ReadTimeout => output.push(ERR_READ_TIMEOUT),
+            FlotonErr::WriteTimeout => output.push(ERR_WRITE_TIMEOUT),
+            FlotonErr::IdleTimeout => output.push(ERR_IDLE_TIMEOUT),
+            FlotonErr::QueueFull(key) => {
+                output.push(ERR_QUEUE_FULL);
+                keys::key_u64_out_vu8(*key, output);
+            },
+            FlotonErr::SketchMismatch(key) => {
+                output.push(ERR_SKETCH_MISMATCH);
+                keys::key_u64_out_vu8(*key, output);
+            },
+            FlotonErr::OutOfMemory(key) => {
+                output.push(ERR_OUT_OF_MEMORY);
+                keys::key_u64_out_vu8(*key, output);
+            },
+            FlotonErr::ConfigReadOnly(key) => {
+                output.push(ERR_CONFIG_READ_ONLY);
+                keys::key_u64_out_vu8(*key, output);
+            },
+            FlotonErr::ConfigInvalid(key) => {
+                output.push(ERR_CONFIG_INVALID);
+                keys::key_u64_out_vu8(*key, output);
+            },
+            FlotonErr::QueueTooLong(max) => {
+                output.push(ERR_QUEUE_TOO_LONG);
+                output.extend_from_slice(&max.to_le_bytes());
+            },
+            FlotonErr::BadRequest(at) => {
+                output.push(ERR_BAD_REQUEST);
+                output.extend_from_slice(&at.to_le_bytes());
             }
 		}
 	}
@@ -79,7 +133,56 @@ impl InPutOutPut for FlotonErr {
                     let parsed = FlotonErr::OperationNoSupport(parsed_ptr, val_type, u16::from_le_bytes(op_bytes));
                     *place += keys::key_u64_len(parsed_ptr);
                     return Ok(parsed);
-                }
+                },
+                ERR_SERVER_BUSY => {
+                    let retry = u32::from_le_bytes(input[*place..(*place + 4)].try_into().unwrap());
+                    *place += 4;
+                    return Ok(FlotonErr::ServerBusy(retry));
+                },
+                ERR_REQUEST_TOO_LARGE => {
+                    let max = u64::from_le_bytes(input[*place..(*place + 8)].try_into().unwrap());
+                    *place += 8;
+                    return Ok(FlotonErr::RequestTooLarge(max));
+                },
+                ERR_TOO_MANY_CONNS => Ok(FlotonErr::TooManyConnections),
+                ERR_READ_TIMEOUT => Ok(FlotonErr::ReadTimeout),
+                ERR_WRITE_TIMEOUT => Ok(FlotonErr::WriteTimeout),
+                ERR_IDLE_TIMEOUT => Ok(FlotonErr::IdleTimeout),
+                ERR_QUEUE_FULL => {
+                    let parsed_ptr = unsafe { input.as_ptr().offset(*place as isize) as *const u64 };
+                    *place += keys::key_u64_len(parsed_ptr);
+                    return Ok(FlotonErr::QueueFull(parsed_ptr));
+                },
+                ERR_SKETCH_MISMATCH => {
+                    let parsed_ptr = unsafe { input.as_ptr().offset(*place as isize) as *const u64 };
+                    *place += keys::key_u64_len(parsed_ptr);
+                    return Ok(FlotonErr::SketchMismatch(parsed_ptr));
+                },
+                ERR_OUT_OF_MEMORY => {
+                    let parsed_ptr = unsafe { input.as_ptr().offset(*place as isize) as *const u64 };
+                    *place += keys::key_u64_len(parsed_ptr);
+                    return Ok(FlotonErr::OutOfMemory(parsed_ptr));
+                },
+                ERR_CONFIG_READ_ONLY => {
+                    let parsed_ptr = unsafe { input.as_ptr().offset(*place as isize) as *const u64 };
+                    *place += keys::key_u64_len(parsed_ptr);
+                    return Ok(FlotonErr::ConfigReadOnly(parsed_ptr));
+                },
+                ERR_CONFIG_INVALID => {
+                    let parsed_ptr = unsafe { input.as_ptr().offset(*place as isize) as *const u64 };
+                    *place += keys::key_u64_len(parsed_ptr);
+                    return Ok(FlotonErr::ConfigInvalid(parsed_ptr));
+                },
+                ERR_QUEUE_TOO_LONG => {
+                    let max = u64::from_le_bytes(input[*place..(*place + 8)].try_into().unwrap());
+                    *place += 8;
+                    return Ok(FlotonErr::QueueTooLong(max));
+                },
+                ERR_BAD_REQUEST => {
+                    let at = u64::from_le_bytes(input[*place..(*place + 8)].try_into().unwrap());
+                    *place += 8;
+                    return Ok(FlotonErr::BadRequest(at));
+                },
 				_ => return Err(FlotonErr::UnexpectedByte(err_type))
 			}
 		} else {
@@ -234,4 +337,95 @@ mod tests {
             _ => panic!("Expected type operation not supported, but got different error {:?}", err_obj)
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn err_server_busy_works() {
+        let mut buf = vec![];
+        FlotonErr::ServerBusy(250).output_binary(&mut buf);
+        assert_eq!(buf.len(), 6);
+        assert_eq!(buf[0], VBIN_ERROR);
+        assert_eq!(buf[1], ERR_SERVER_BUSY);
+        let mut i = 0;
+        match FlotonErr::input_binary(&buf, &mut i) {
+            Ok(FlotonErr::ServerBusy(retry)) => assert_eq!(retry, 250),
+            other => panic!("Expected server busy error, got {:?}", other)
+        }
+        assert_eq!(i, 6);
+    }
+
+    #[test]
+    fn err_conn_limits_works() {
+        let errs = [FlotonErr::RequestTooLarge(1 << 40),
+                    FlotonErr::TooManyConnections,
+                    FlotonErr::ReadTimeout,
+                    FlotonErr::WriteTimeout,
+                    FlotonErr::IdleTimeout];
+        let mut buf = vec![];
+        for err in errs.iter() {
+            err.output_binary(&mut buf);
+        }
+        assert_eq!(buf.len(), 10 + 2 * 4);
+        let mut i = 0;
+        match FlotonErr::input_binary(&buf, &mut i) {
+            Ok(FlotonErr::RequestTooLarge(max)) => assert_eq!(max, 1 << 40),
+            other => panic!("Expected request too large error, got {:?}", other)
+        }
+        assert!(matches!(FlotonErr::input_binary(&buf, &mut i), Ok(FlotonErr::TooManyConnections)));
+        assert!(matches!(FlotonErr::input_binary(&buf, &mut i), Ok(FlotonErr::ReadTimeout)));
+        assert!(matches!(FlotonErr::input_binary(&buf, &mut i), Ok(FlotonErr::WriteTimeout)));
+        assert!(matches!(FlotonErr::input_binary(&buf, &mut i), Ok(FlotonErr::IdleTimeout)));
+        assert_eq!(i, buf.len());
+    }
+
+    #[test]
+    fn err_queue_full_works() {
+        let mut key = Vec::<u8>::new();
+        key.extend_from_slice(&1u64.to_le_bytes());
+        keys::write_key(b"jobs", &mut key);
+        let mut buf = vec![];
+        FlotonErr::QueueFull(key.as_ptr() as *const u64).output_binary(&mut buf);
+        assert_eq!(buf[1], ERR_QUEUE_FULL);
+        assert_eq!(&buf[2..], key.as_slice());
+        let mut i = 0;
+        match FlotonErr::input_binary(&buf, &mut i) {
+            Ok(FlotonErr::QueueFull(_)) => (),
+            other => panic!("Expected queue full error, got {:?}", other)
+        }
+        assert_eq!(i, buf.len());
+        FlotonErr::QueueTooLong(1 << 20).output_binary(&mut buf);
+        match FlotonErr::input_binary(&buf, &mut i) {
+            Ok(FlotonErr::QueueTooLong(max)) => assert_eq!(max, 1 << 20),
+            other => panic!("Expected queue too long error, got {:?}", other)
+        }
+        assert_eq!(i, buf.len());
+    }
+
+    #[test]
+    fn err_config_works() {
+        let mut key = Vec::<u8>::new();
+        key.extend_from_slice(&1u64.to_le_bytes());
+        keys::write_key(b"--port=9000", &mut key);
+        let mut buf = vec![];
+        FlotonErr::ConfigReadOnly(key.as_ptr() as *const u64).output_binary(&mut buf);
+        FlotonErr::ConfigInvalid(key.as_ptr() as *const u64).output_binary(&mut buf);
+        assert_eq!(buf[1], ERR_CONFIG_READ_ONLY);
+        assert_eq!(&buf[2..(2 + key.len())], key.as_slice());
+        let mut i = 0;
+        assert!(matches!(FlotonErr::input_binary(&buf, &mut i), Ok(FlotonErr::ConfigReadOnly(_))));
+        assert!(matches!(FlotonErr::input_binary(&buf, &mut i), Ok(FlotonErr::ConfigInvalid(_))));
+        assert_eq!(i, buf.len());
+    }
+
+    #[test]
+    fn err_bad_request_works() {
+        let mut buf = vec![];
+        FlotonErr::BadRequest(41).output_binary(&mut buf);
+        assert_eq!(buf[1], ERR_BAD_REQUEST);
+        let mut i = 0;
+        match FlotonErr::input_binary(&buf, &mut i) {
+            Ok(FlotonErr::BadRequest(at)) => assert_eq!(at, 41),
+            other => panic!("Expected bad request error, got {:?}", other)
+        }
+        assert_eq!(i, buf.len());
+    }
+}
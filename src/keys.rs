@@ -1,22 +1,66 @@
 use std::slice;
+use std::convert::TryInto;
+use crate::errors::FlotonErr;
 
 /**
- * Functions for processing packed, aligned keys
+ * Functions for processing packed, aligned keys. On the wire each key is
+ * its exact length in bytes, then the key, then zeros up to the next
+ * multiple of 8, so every length word stays 8 byte aligned.
  */
+#[inline]
+pub fn padded_len(len:usize) -> usize {
+	(len + 7) & !7
+}
+
+// Keys that fill whole words take the aligned paths in maps
+#[inline]
+pub fn key_align(key:&[u8]) -> usize {
+	if !key.is_empty() && padded_len(key.len()) == key.len() { 8 } else { 1 }
+}
+
+// Reads a u64 word, an error if input ends before it does
+pub fn read_u64(input:&[u8], place:&mut usize) -> Result<u64, FlotonErr> {
+	match input.get(*place..(place.saturating_add(8))) {
+		Some(word) => {
+			*place += 8;
+			Ok(u64::from_le_bytes(word.try_into().unwrap()))
+		},
+		None => Err(FlotonErr::BadRequest(*place as u64))
+	}
+}
+
+// Reads one length prefixed key, moving place past its padding
+pub fn read_key<'a>(input:&'a [u8], place:&mut usize) -> Result<&'a [u8], FlotonErr> {
+	let start = *place;
+	let key_len = read_u64(input, place)? as usize;
+	let key = input.get(*place..(place.saturating_add(key_len))).ok_or(FlotonErr::BadRequest(start as u64))?;
+	*place = place.saturating_add(padded_len(key_len));
+	Ok(key)
+}
+
+// Reads a whole key path, as each of its keys
+pub fn read_path(input:&[u8], place:&mut usize) -> Result<Vec<Vec<u8>>, FlotonErr> {
+	let depth = read_u64(input, place)?;
+	(0..depth).map(|_| read_key(input, place).map(|key| key.to_vec())).collect()
+}
 
+pub fn write_key(key:&[u8], output: &mut Vec<u8>) {
+	output.extend_from_slice(&(key.len() as u64).to_le_bytes());
+	output.extend_from_slice(key);
+	output.resize(output.len() + padded_len(key.len()) - key.len(), 0);
+}
 
 pub fn key_u64_out_vu8(key:*const u64, output: &mut Vec<u8>) {
 	let mut key_ptr = key;
 	unsafe {
-		let key_depth = *key_ptr;
+		let key_depth = key_ptr.read_unaligned();
 		output.extend_from_slice(&key_depth.to_le_bytes());
 		key_ptr = key_ptr.offset(1 as isize);
 		for _ in 0..key_depth {
-			let key_len = *key_ptr;
-			output.extend_from_slice(&key_len.to_le_bytes());
+			let key_len = key_ptr.read_unaligned() as usize;
 			key_ptr = key_ptr.offset(1 as isize);
-			output.extend_from_slice(slice::from_raw_parts(key_ptr as *const u8, key_len as usize));
-			key_ptr = key_ptr.offset((key_len / 8) as isize);
+			write_key(slice::from_raw_parts(key_ptr as *const u8, key_len), output);
+			key_ptr = key_ptr.offset((padded_len(key_len) / 8) as isize);
 		}
 	}
 }
@@ -25,13 +69,13 @@ pub fn key_u64_len(key:*const u64) -> usize {
 	let mut length = 0;
 	let mut read_ptr = key;
 	// advancement
-	let key_depth = unsafe { *read_ptr };
+	let key_depth = unsafe { read_ptr.read_unaligned() };
 	length += 8;
 	read_ptr = unsafe { read_ptr.offset(1 as isize) };
 	for _ in 0..key_depth {
-		let key_len = unsafe { *read_ptr };
+		let key_len = padded_len(unsafe { read_ptr.read_unaligned() } as usize);
 		read_ptr = unsafe { read_ptr.offset((1 + (key_len/8)) as isize) };
-		length += (8 + key_len) as usize;
+		length += 8 + key_len;
 	}
 	length
 }
@@ -63,5 +107,48 @@ mod tests {
     	let test_data:[u64;6] = [2, 8, 4532, 8, 4478, 1];
     	let ptr = test_data.as_ptr();
     	assert_eq!(key_u64_len(ptr), 40);
+    	// a 4 byte key still takes a whole word
+    	let test_data:[u64;6] = [2, 4, 0x72657375, 9, 4478, 1];
+    	assert_eq!(key_u64_len(test_data.as_ptr()), 48);
+    }
+
+    #[test]
+    fn read_write_key_works() {
+    	let mut output = vec![0xff];
+    	write_key(b"user", &mut output);
+    	write_key(b"12345678", &mut output);
+    	write_key(b"", &mut output);
+    	assert_eq!(output.len(), 1 + 16 + 16 + 8);
+    	assert_eq!(&output[9..17], b"user\0\0\0\0");
+    	let mut place = 1;
+    	assert_eq!(read_key(&output, &mut place).unwrap(), b"user");
+    	assert_eq!(place, 17);
+    	assert_eq!(read_key(&output, &mut place).unwrap(), b"12345678");
+    	assert_eq!(read_key(&output, &mut place).unwrap(), b"");
+    	assert_eq!(place, output.len());
+    	// a length running past the end
+    	let mut cut = vec![];
+    	cut.extend_from_slice(&u64::MAX.to_le_bytes());
+    	cut.extend_from_slice(b"user");
+    	assert!(matches!(read_key(&cut, &mut 0), Err(FlotonErr::BadRequest(0))));
+    	assert!(matches!(read_key(&cut[..4], &mut 0), Err(FlotonErr::BadRequest(0))));
+    	assert_eq!(key_align(b"user"), 1);
+    	assert_eq!(key_align(b"12345678"), 8);
+    	assert_eq!(key_align(b""), 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn read_path_works() {
+    	let mut cmd = vec![1];
+    	cmd.extend_from_slice(&2u64.to_le_bytes());
+    	write_key(b"users", &mut cmd);
+    	write_key(b"\x01id", &mut cmd);
+    	cmd.push(0);
+    	let mut place = 1;
+    	assert_eq!(read_path(&cmd, &mut place).unwrap(), vec![b"users".to_vec(), b"\x01id".to_vec()]);
+    	assert_eq!(place, cmd.len() - 1);
+    	assert!(read_path(&cmd[..4], &mut 1).is_err());
+    	// the depth is more keys than there are
+    	assert!(read_path(&cmd[..(cmd.len() - 17)], &mut 1).is_err());
+    }
+}
@@ -15,17 +15,29 @@ pub mod keys;
 pub mod circular;
 pub mod trie;
 pub mod tlocal;
+pub mod slab;
 pub mod values;
 pub mod hashtree;
+pub mod skiplist;
+pub mod reclaim;
+pub mod memory;
+pub mod stats;
+pub mod slowlog;
 pub mod shared;
 pub mod containers;
+pub mod lists;
+pub mod queues;
+pub mod sets;
+pub mod sketches;
 pub mod atomic_ops;
 pub mod normal_ops;
 pub mod processors;
 pub mod threading;
 pub mod ports;
+pub mod epoll;
 pub mod tcp;
 pub mod requests;
 pub mod responses;
 pub mod settings;
-pub mod database;
\ No newline at end of file
+pub mod database;
+pub mod metrics;
\ No newline at end of file
@@ -0,0 +1,211 @@
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use crate::traits::NewType;
+use crate::reclaim;
+use crate::containers::Container;
+use crate::threading::TVal;
+
+pub type ListItems<T> = VecDeque<TVal<Container<T>>>;
+
+/**
+ * A list of containers. Every change swaps in a new copy of the list,
+ * retrying if another change got there first, so readers always see a
+ * whole list. Replaced copies are retired to reclaim, so a reader can
+ * keep one for as long as it stays pinned. The items themselves are shared between copies, only their
+ * handles are copied.
+ */
+#[derive(Debug)]
+pub struct List<T> {
+	cur:AtomicPtr<ListItems<T>>
+}
+
+impl<T> Drop for List<T> {
+	fn drop(&mut self) {
+		free!(self.cur.load(Ordering::SeqCst));
+	}
+}
+
+// Where idx falls in a list of len items, negative ones counting from the end
+fn list_index(len:usize, idx:i64) -> Option<usize> {
+	let pos = if idx < 0 { len as i64 + idx } else { idx };
+	if pos >= 0 && (pos as usize) < len { Some(pos as usize) } else { None }
+}
+
+// Like list_index, but clamped to the list, for range bounds
+fn list_bound(len:usize, idx:i64) -> usize {
+	let pos = if idx < 0 { len as i64 + idx } else { idx };
+	pos.max(0).min(len as i64) as usize
+}
+
+impl<T: Debug> List<T> {
+	pub fn new(items:ListItems<T>) -> List<T> {
+		List{cur:AtomicPtr::new(alloc!(items))}
+	}
+
+	// Only valid while the reading thread stays pinned
+	fn items(&self) -> &ListItems<T> {
+		ptref!(self.cur.load(Ordering::SeqCst))
+	}
+
+	// Applies change to the current items until its copy is swapped in first.
+	// A change returning no items leaves the list as it is.
+	fn update<R>(&self, mut change:impl FnMut(&ListItems<T>) -> (Option<ListItems<T>>, R)) -> R {
+		loop {
+			let current = self.cur.load(Ordering::SeqCst);
+			let (changed, ret) = change(ptref!(current));
+			match changed {
+				None => return ret,
+				Some(next) => {
+					let next_ptr = alloc!(next);
+					if self.cur.compare_exchange(current, next_ptr, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+						reclaim::retire(current);
+						return ret;
+					}
+					free!(next_ptr);
+				}
+			}
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.items().len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	// Adds val to the back or front, returning the new length
+	pub fn push(&self, val:Container<T>, back:bool) -> usize {
+		let val = TVal::new(val);
+		self.update(|items| {
+			let mut next = items.clone();
+			if back {
+				next.push_back(val.clone());
+			} else {
+				next.push_front(val.clone());
+			}
+			let len = next.len();
+			(Some(next), len)
+		})
+	}
+
+	pub fn pop(&self, back:bool) -> Option<TVal<Container<T>>> {
+		self.update(|items| {
+			if items.is_empty() {
+				return (None, None);
+			}
+			let mut next = items.clone();
+			let popped = if back { next.pop_back() } else { next.pop_front() };
+			(Some(next), popped)
+		})
+	}
+
+	pub fn get(&self, idx:i64) -> Option<TVal<Container<T>>> {
+		let items = self.items();
+		list_index(items.len(), idx).map(|pos| items[pos].clone())
+	}
+
+	// Replaces the item at idx, returning false if there is none
+	pub fn set(&self, idx:i64, val:Container<T>) -> bool {
+		let val = TVal::new(val);
+		self.update(|items| match list_index(items.len(), idx) {
+			Some(pos) => {
+				let mut next = items.clone();
+				next[pos] = val.clone();
+				(Some(next), true)
+			},
+			None => (None, false)
+		})
+	}
+
+	// The items from start up to but not including end
+	pub fn range(&self, start:i64, end:i64) -> Vec<TVal<Container<T>>> {
+		let items = self.items();
+		let (from, to) = (list_bound(items.len(), start), list_bound(items.len(), end));
+		if from >= to {
+			return vec![];
+		}
+		items.range(from..to).cloned().collect()
+	}
+
+	pub fn snapshot(&self) -> Vec<TVal<Container<T>>> {
+		self.items().iter().cloned().collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::tlocal;
+
+    #[derive(Debug, Copy, Clone)]
+    struct TestType(u32);
+
+    fn vals(items:&[TVal<Container<TestType>>]) -> Vec<u32> {
+    	items.iter().map(|c| c.value().unwrap().0).collect()
+    }
+
+    #[test]
+    fn list_index_works() {
+    	assert_eq!(list_index(3, 0), Some(0));
+    	assert_eq!(list_index(3, -1), Some(2));
+    	assert_eq!(list_index(3, 3), None);
+    	assert_eq!(list_index(3, -4), None);
+    	assert_eq!(list_bound(3, -10), 0);
+    	assert_eq!(list_bound(3, 10), 3);
+    	assert_eq!(list_bound(3, -1), 2);
+    }
+
+    #[test]
+    fn push_pop_works() {
+    	tlocal::set_epoch();
+    	let list = List::<TestType>::new(VecDeque::new());
+    	assert!(list.pop(true).is_none());
+    	assert_eq!(list.push(Container::Val(TestType(2)), true), 1);
+    	assert_eq!(list.push(Container::Val(TestType(3)), true), 2);
+    	assert_eq!(list.push(Container::Val(TestType(1)), false), 3);
+    	assert_eq!(vals(&list.snapshot()), vec![1, 2, 3]);
+    	assert_eq!(list.get(-1).unwrap().value().unwrap().0, 3);
+    	assert!(list.get(3).is_none());
+    	assert!(list.set(1, Container::Val(TestType(20))));
+    	assert!(!list.set(5, Container::Val(TestType(50))));
+    	assert_eq!(vals(&list.range(1, 10)), vec![20, 3]);
+    	assert_eq!(vals(&list.range(-2, -1)), vec![20]);
+    	assert!(list.range(2, 1).is_empty());
+    	assert_eq!(list.pop(false).unwrap().value().unwrap().0, 1);
+    	assert_eq!(list.pop(true).unwrap().value().unwrap().0, 3);
+    	assert_eq!(list.len(), 1);
+    }
+
+    #[test]
+    fn mt_push_pop_works() {
+    	tlocal::set_epoch();
+    	let list = TVal::new(List::<TestType>::new(VecDeque::new()));
+    	let mut handles = vec![];
+    	for t in 0..4 {
+    		let tlist = list.clone();
+    		handles.push(thread::spawn(move || {
+    			let mut popped = 0;
+    			for i in 0..500 {
+    				reclaim::pin();
+    				tlist.push(Container::Val(TestType(t * 1000 + i)), t % 2 == 0);
+    				if i % 5 == 0 && tlist.pop(t % 2 == 1).is_some() {
+    					popped += 1;
+    				}
+    				reclaim::unpin();
+    			}
+    			popped
+    		}));
+    	}
+    	let popped:usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+    	// every push is kept unless popped
+    	assert_eq!(list.len(), 4 * 500 - popped);
+    	let mut seen = vals(&list.snapshot());
+    	seen.sort();
+    	seen.dedup();
+    	assert_eq!(seen.len(), list.len());
+    }
+}
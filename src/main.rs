@@ -8,7 +8,7 @@ use floton::signals;
 use floton::logging::*;
 use floton::{log_always, alloc, free};
 use floton::database::Database;
-use floton::settings::Settings;
+use floton::settings::{Settings, PRINT_CONFIG_FLAG};
 
 static THE_DATABASE:AtomicPtr<Database> = AtomicPtr::new(ptr::null_mut());
 
@@ -36,7 +36,22 @@ fn main() {
     signals::register_int_handler(int_handler);
     signals::register_term_handler(term_handler);
 
-    let settings = Settings::from_args(&cli_args);
+    let settings = match Settings::from_sources(user_args, env::vars()) {
+        Ok((settings, unknown)) => {
+            for name in unknown {
+                log_always!(Startup, "Ignoring unknown setting {}", name);
+            }
+            settings
+        },
+        Err(e) => {
+            log_always!(Startup, "Could not load settings, {}", e);
+            process::exit(1);
+        }
+    };
+    if user_args.iter().any(|arg| arg == PRINT_CONFIG_FLAG) {
+        print!("{}", settings.to_config());
+        process::exit(0);
+    }
     let mut db = Database::new_from_settings(settings);
     log_always!(Startup, "Will listen on port {} for connections", db.get_port());
     db.construct();
@@ -0,0 +1,399 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::cell::Cell;
+use std::str::FromStr;
+use std::fmt;
+use std::mem;
+use crate::containers::Container;
+use crate::hashtree::HashTree;
+use crate::shared::{Shared, TimePtr};
+use crate::values::Value;
+use crate::sketches::Sketch;
+use crate::reclaim;
+use crate::tlocal;
+use crate::keys;
+
+/**
+ * Approximate accounting of the memory the keyspace holds, and making
+ * room under a limit. Every version written into a map counts with all
+ * it holds, along with the map node and key it was written under. A
+ * replaced version counts as pending with reclaim until it is freed.
+ * Over the limit, writes either fail or evict whole top level keys,
+ * picked from a small random sample by their access stamps.
+ */
+// Tries at making room for one write before giving up on it
+const MAX_EVICT_ROUNDS:usize = 64;
+// Access counts start here, so new keys aren't evicted before they are used
+const LFU_INIT:u64 = 5;
+// How much harder each access count is to raise than the one below it
+const LFU_LOG_FACTOR:u64 = 10;
+// Access counts drop by one for every this many ms a key goes unused
+const LFU_DECAY_MS:u64 = 60000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EvictPolicy {
+	// Fail writes that would go over the limit
+	Reject,
+	// Evict the key accessed longest ago
+	Lru,
+	// Evict the key accessed least often lately
+	Lfu
+}
+
+impl FromStr for EvictPolicy {
+	type Err = ();
+	fn from_str(s:&str) -> Result<Self, Self::Err> {
+		match s {
+			"reject" => Ok(EvictPolicy::Reject),
+			"lru" => Ok(EvictPolicy::Lru),
+			"lfu" => Ok(EvictPolicy::Lfu),
+			_ => Err(())
+		}
+	}
+}
+
+impl fmt::Display for EvictPolicy {
+	fn fmt(&self, f:&mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			EvictPolicy::Reject => "reject",
+			EvictPolicy::Lru => "lru",
+			EvictPolicy::Lfu => "lfu"
+		})
+	}
+}
+
+thread_local!(static SAMPLE_RNG:Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1));
+
+fn random() -> u64 {
+	SAMPLE_RNG.with(|rng| {
+		let mut x = rng.get();
+		x ^= x << 13;
+		x ^= x >> 7;
+		x ^= x << 17;
+		rng.set(x);
+		x
+	})
+}
+
+// Access stamps are the ms a version was last accessed at, over an 8 bit
+// count of accesses that grows logarithmically
+fn now_ms() -> u64 {
+	tlocal::time() / 1_000_000
+}
+
+pub fn new_stamp() -> u64 {
+	(now_ms() << 8) | LFU_INIT
+}
+
+pub fn touch(stamp:&AtomicU64) {
+	let old = stamp.load(Ordering::Relaxed);
+	let mut count = old & 0xff;
+	if count < 0xff && random().is_multiple_of(count.saturating_sub(LFU_INIT) * LFU_LOG_FACTOR + 1) {
+		count += 1;
+	}
+	let stamped = (now_ms() << 8) | count;
+	// hot keys are read far more often than the stamp changes
+	if stamped != old {
+		stamp.store(stamped, Ordering::Relaxed);
+	}
+}
+
+fn idle_ms(stamp:u64, now:u64) -> u64 {
+	now.saturating_sub(stamp >> 8)
+}
+
+fn lfu_count(stamp:u64, now:u64) -> u64 {
+	(stamp & 0xff).saturating_sub(idle_ms(stamp, now) / LFU_DECAY_MS)
+}
+
+pub trait MemSize {
+	// Approximate bytes held, inline and on the heap
+	fn mem_size(&self) -> usize;
+}
+
+// A map node holding key, along with the key
+pub fn entry_size(key:&[u8]) -> usize {
+	mem::size_of::<HashTree<Shared<Container<Value>>>>() + key.len()
+}
+
+fn entries_size<'a>(entries:impl Iterator<Item = (&'a [u8], &'a Shared<Container<Value>>)>) -> usize {
+	entries.map(|(key, slot)| entry_size(key) + unsafe { slot.read().as_ref() }.map_or(0, |v| version_size(&v.0))).sum()
+}
+
+// Bytes a container holds outside of itself
+fn heap_size(container:&Container<Value>) -> usize {
+	match container {
+		Container::Val(_) => 0,
+		Container::Map(m) => m.bucket_count() * mem::size_of::<usize>() + entries_size(m.iter()),
+		Container::OrdMap(m) => entries_size(m.iter()),
+		Container::List(l) => l.snapshot().iter().map(|item| list_item_size(item)).sum(),
+		Container::Queue(q) => q.capacity() * mem::size_of::<usize>() + q.len() * mem::size_of::<Container<Value>>(),
+		Container::Set(s) => s.iter().map(member_size).sum(),
+		Container::Sketch(Sketch::Hll(h)) => 1 << h.precision(),
+		Container::Sketch(Sketch::Bloom(b)) => b.word_count() * mem::size_of::<u64>()
+	}
+}
+
+impl MemSize for Container<Value> {
+	fn mem_size(&self) -> usize {
+		mem::size_of::<Container<Value>>() + heap_size(self)
+	}
+}
+
+// A version written into a map, holding val
+pub fn version_size(val:&Container<Value>) -> usize {
+	mem::size_of::<TimePtr<Container<Value>>>() + heap_size(val)
+}
+
+// An item of a list, behind a counted pointer
+pub fn list_item_size(item:&Container<Value>) -> usize {
+	mem::size_of::<usize>() * 3 + item.mem_size()
+}
+
+// A member of a set, as a node holding the member
+pub fn member_size(member:&[u8]) -> usize {
+	mem::size_of::<HashTree<()>>() + member.len()
+}
+
+/**
+ * The memory a database's keyspace holds, and the limit it is kept
+ * under. A limit of 0 leaves it unlimited, only counting.
+ */
+#[derive(Debug)]
+pub struct Memory {
+	live:AtomicUsize,
+	evicted:AtomicUsize,
+	limit:usize,
+	policy:EvictPolicy,
+	samples:usize
+}
+
+impl Memory {
+	pub const fn new(limit:usize, policy:EvictPolicy, samples:usize) -> Memory {
+		Memory{live:AtomicUsize::new(0), evicted:AtomicUsize::new(0), limit, policy, samples}
+	}
+
+	pub fn add(&self, bytes:usize) {
+		self.live.fetch_add(bytes, Ordering::Relaxed);
+	}
+
+	// Estimates are redone on the way out, so never go below 0
+	pub fn sub(&self, bytes:usize) {
+		let _ = self.live.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |live| Some(live.saturating_sub(bytes)));
+	}
+
+	// Bytes in the keyspace, not counting versions waiting to be freed
+	pub fn live(&self) -> usize {
+		self.live.load(Ordering::Relaxed)
+	}
+
+	pub fn used(&self) -> usize {
+		self.live() + reclaim::pending_bytes()
+	}
+
+	pub fn limit(&self) -> usize {
+		self.limit
+	}
+
+	pub fn policy(&self) -> EvictPolicy {
+		self.policy
+	}
+
+	// Keys evicted to make room so far
+	pub fn evicted(&self) -> usize {
+		self.evicted.load(Ordering::Relaxed)
+	}
+
+	// Writes val to slot, which holds key in a map, counting what it adds
+	// and moving what it replaces to pending
+	pub fn write(&self, slot:&Shared<Container<Value>>, key:&[u8], val:Container<Value>) {
+		let added = version_size(&val) + if slot.is_empty() { entry_size(key) } else { 0 };
+		self.add(added);
+		slot.write_sized(TimePtr::make(val), |old| {
+			let bytes = version_size(old);
+			self.sub(bytes);
+			bytes
+		});
+	}
+
+	// As Container::create_set_map, counting the map if it is made
+	pub fn create_map<'a>(&self, map:&'a Container<Value>, key:&[u8], slots_size:usize) -> &'a Container<Value> {
+		let slot = map.map_slot(key);
+		unsafe {
+			match slot.read().as_ref() {
+				Some(found) if found.0.is_map() => return &found.0,
+				_ => ()
+			}
+		}
+		self.write(slot, key, Container::new_map(slots_size));
+		unsafe { &slot.read().as_ref().unwrap().0 }
+	}
+
+	// Makes room for bytes more, evicting top level keys of data if the
+	// policy allows. False if the write has to be turned away.
+	pub fn admit(&self, data:&Container<Value>, bytes:usize) -> bool {
+		if self.limit == 0 {
+			return true;
+		}
+		let mut rounds = 0;
+		while self.used() + bytes > self.limit {
+			if self.policy == EvictPolicy::Reject || rounds == MAX_EVICT_ROUNDS || !self.evict_one(data) {
+				return false;
+			}
+			rounds += 1;
+		}
+		true
+	}
+
+	// Evicts the worst of a random run of keys, false if there are none
+	fn evict_one(&self, data:&Container<Value>) -> bool {
+		let map = match data {
+			Container::Map(m) => m,
+			_ => return false
+		};
+		// the list is in hash order, so a random cursor starts a random run
+		let (mut sample, _) = map.page(random(), self.samples);
+		// wrapping around past the end
+		if sample.len() < self.samples {
+			sample.extend(map.page(0, self.samples - sample.len()).0);
+		}
+		let now = now_ms();
+		let versions = sample.into_iter().filter_map(|(key, slot)| unsafe { slot.read().as_ref() }.map(|v| (key, v)));
+		let victim = match self.policy {
+			EvictPolicy::Reject => None,
+			// accessed longest ago
+			EvictPolicy::Lru => versions.min_by_key(|(_, v)| v.2.load(Ordering::Relaxed) >> 8),
+			EvictPolicy::Lfu => versions.min_by_key(|(_, v)| {
+				let stamp = v.2.load(Ordering::Relaxed);
+				(lfu_count(stamp, now), stamp >> 8)
+			})
+		};
+		match victim {
+			Some((key, version)) => {
+				// another thread may have got to it first, there's still less to hold
+				if map.remove_bytes(key, keys::key_align(key)) {
+					self.sub(entry_size(key) + version_size(&version.0));
+					self.evicted.fetch_add(1, Ordering::Relaxed);
+				}
+				true
+			},
+			None => false
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sets::Set;
+
+    fn write_uint(memory:&Memory, data:&Container<Value>, key:&[u8], val:u64) {
+    	memory.write(data.map_slot(key), key, Container::Val(Value::UInt(val)));
+    }
+
+    fn set_stamp(data:&Container<Value>, key:&[u8], stamp:u64) {
+    	unsafe { data.get_map_shared(key).unwrap().read().as_ref().unwrap() }.2.store(stamp, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn mem_size_works() {
+    	tlocal::set_epoch();
+    	let val = Container::Val(Value::UInt(1));
+    	assert_eq!(val.mem_size(), mem::size_of::<Container<Value>>());
+    	let map = Container::<Value>::new_map(4);
+    	let empty = map.mem_size();
+    	map.set_map(b"a", Container::Val(Value::UInt(1)));
+    	assert_eq!(map.mem_size(), empty + entry_size(b"a") + version_size(&val));
+    	let set = Container::<Value>::Set(Set::new(4));
+    	let empty = set.mem_size();
+    	set.set().unwrap().add(b"member");
+    	assert!(set.mem_size() > empty + 6);
+    }
+
+    #[test]
+    fn write_counts_works() {
+    	tlocal::set_epoch();
+    	let memory = Memory::new(0, EvictPolicy::Reject, 5);
+    	let data = Container::<Value>::new_map(4);
+    	write_uint(&memory, &data, b"key", 1);
+    	let once = memory.live();
+    	assert_eq!(once, entry_size(b"key") + version_size(&Container::Val(Value::UInt(1))));
+    	// the old version moves to pending, the key isn't counted twice
+    	write_uint(&memory, &data, b"key", 2);
+    	assert_eq!(memory.live(), once);
+    	let nested = Container::<Value>::new_map(4);
+    	nested.set_map(b"inner", Container::Val(Value::UInt(3)));
+    	let nested_size = version_size(&nested);
+    	memory.write(data.map_slot(b"key"), b"key", nested);
+    	assert_eq!(memory.live(), entry_size(b"key") + nested_size);
+    }
+
+    #[test]
+    fn reject_works() {
+    	tlocal::set_epoch();
+    	let data = Container::<Value>::new_map(4);
+    	assert!(Memory::new(0, EvictPolicy::Reject, 5).admit(&data, usize::MAX / 2));
+    	let memory = Memory::new(1, EvictPolicy::Reject, 5);
+    	write_uint(&memory, &data, b"key", 1);
+    	assert!(!memory.admit(&data, 1));
+    	assert!(data.get_map(b"key").is_some());
+    	assert_eq!(memory.evicted(), 0);
+    }
+
+    // Three keys, with the given stamps, sampled all at once
+    fn stamped_keys(policy:EvictPolicy, stamps:[u64;3]) -> (Memory, Container<Value>) {
+    	let memory = Memory::new(0, policy, 5);
+    	let data = Container::<Value>::new_map(4);
+    	for (key, stamp) in [b"a", b"b", b"c"].iter().zip(stamps.iter()) {
+    		write_uint(&memory, &data, *key, 1);
+    		set_stamp(&data, *key, *stamp);
+    	}
+    	(memory, data)
+    }
+
+    #[test]
+    fn lru_evicts_works() {
+    	tlocal::set_epoch();
+    	let (memory, data) = stamped_keys(EvictPolicy::Lru, [(100 << 8) | LFU_INIT, (1 << 8) | LFU_INIT, (50 << 8) | LFU_INIT]);
+    	let before = memory.live();
+    	assert!(memory.evict_one(&data));
+    	assert!(data.get_map_shared(b"b").is_none());
+    	assert!(data.get_map_shared(b"a").is_some());
+    	assert!(data.get_map_shared(b"c").is_some());
+    	assert_eq!(memory.live(), before / 3 * 2);
+    	assert_eq!(memory.evicted(), 1);
+    	// the least recently used of what is left goes next
+    	assert!(memory.evict_one(&data));
+    	assert!(data.get_map_shared(b"c").is_none());
+    }
+
+    #[test]
+    fn lfu_evicts_works() {
+    	tlocal::set_epoch();
+    	let now = now_ms();
+    	let (memory, data) = stamped_keys(EvictPolicy::Lfu, [(now << 8) | 40, (now << 8) | 20, (now << 8) | 2]);
+    	assert!(memory.evict_one(&data));
+    	assert!(data.get_map_shared(b"c").is_none());
+    	assert!(data.get_map_shared(b"a").is_some());
+    	assert!(data.get_map_shared(b"b").is_some());
+    	assert!(memory.evict_one(&data));
+    	assert!(data.get_map_shared(b"b").is_none());
+    	assert_eq!(memory.evicted(), 2);
+    }
+
+    #[test]
+    fn touch_works() {
+    	tlocal::set_epoch();
+    	let stamp = AtomicU64::new(LFU_INIT);
+    	// the first accesses always count
+    	touch(&stamp);
+    	assert_eq!(stamp.load(Ordering::SeqCst) & 0xff, LFU_INIT + 1);
+    	for _ in 0..1000 {
+    		touch(&stamp);
+    	}
+    	let count = stamp.load(Ordering::SeqCst) & 0xff;
+    	assert!(count > LFU_INIT + 1 && count < 0xff);
+    	assert_eq!(lfu_count(count, LFU_DECAY_MS * 3), count - 3);
+    }
+}
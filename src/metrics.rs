@@ -0,0 +1,238 @@
+use std::sync::atomic::Ordering;
+use std::net::{TcpListener, TcpStream, SocketAddr};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use std::fmt::Write as FmtWrite;
+use std::io;
+use std::io::prelude::*;
+use crate::database::Database;
+use crate::tcp::TcpServerContext;
+use crate::threading::Switch;
+use crate::stats::{Latencies, CMD_NAMES, OP_ATOMIC_NAMES, LATENCY_BOUNDS_NS};
+use crate::reclaim;
+use crate::slab;
+use crate::tlocal;
+use crate::traits::*;
+use crate::logging::*;
+
+/**
+ * Serves the database's stats in the Prometheus text format, over plain
+ * http on its own port. Scrapes are few and far between, so they are
+ * answered one at a time on the listening thread.
+ */
+#[derive(Debug)]
+pub struct MetricsServer {
+	handle:Option<JoinHandle<()>>,
+	switch:Switch,
+	addr:SocketAddr
+}
+
+// Only the head of a request is looked at, anything past this is ignored
+const MAX_HEAD_SIZE:usize = 8 * 1024;
+const READ_TIMEOUT_MS:u64 = 1000;
+
+impl MetricsServer {
+	pub fn start(host:&str, port:u16, db:TcpServerContext<Database>) -> io::Result<MetricsServer> {
+		let listener = TcpListener::bind((host, port))?;
+		let addr = listener.local_addr()?;
+		log_info!(Metrics, "Will serve metrics on port {} at address: {}", port, host);
+		let switch = Switch::new();
+		switch.set(true);
+		let tswitch = switch.clone();
+		let handle = thread::spawn(move || {
+			for conn in listener.incoming() {
+				if !tswitch.get() {
+					break;
+				}
+				match conn {
+					Ok(stream) => {
+						let db = unsafe { db.get().as_ref().unwrap() };
+						if let Err(e) = MetricsServer::serve(stream, db) {
+							log_warn!(Metrics, "Failed to answer a scrape, got {}", e);
+						}
+					},
+					Err(e) => log_error!(Metrics, "Failed to accept a scrape, got {}", e)
+				}
+			}
+		});
+		Ok(MetricsServer{handle:Some(handle), switch, addr})
+	}
+
+	pub fn addr(&self) -> SocketAddr {
+		self.addr
+	}
+
+	fn serve(mut stream:TcpStream, db:&Database) -> io::Result<()> {
+		stream.set_read_timeout(Some(Duration::from_millis(READ_TIMEOUT_MS)))?;
+		let mut head = Vec::with_capacity(512);
+		let mut buf = [0;1024];
+		while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_HEAD_SIZE {
+			match stream.read(&mut buf)? {
+				0 => break,
+				n => head.extend_from_slice(&buf[..n])
+			}
+		}
+		let line = head.split(|b| *b == b'\r').next().unwrap_or(&[]);
+		let mut parts = line.split(|b| *b == b' ');
+		let (status, body) = match (parts.next(), parts.next()) {
+			(Some(b"GET"), Some(b"/metrics")) => ("200 OK", render(db)),
+			(Some(b"GET"), _) => ("404 Not Found", String::from("Not found, metrics are at /metrics\n")),
+			_ => ("405 Method Not Allowed", String::from("Only GET is allowed\n"))
+		};
+		write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+		       status, body.len(), body)?;
+		stream.flush()
+	}
+
+	pub fn stop(&mut self) {
+		self.switch.set(false);
+		if let Some(handle) = self.handle.take() {
+			// the listener only sees the switch once something connects
+			if let Err(e) = TcpStream::connect(self.addr) {
+				log_error!(Metrics, "Could not wake the metrics listener, got {}", e);
+			}
+			handle.join().unwrap();
+		}
+	}
+}
+
+/**
+ * Writes out metric families, each with its type given once before
+ * its samples.
+ */
+struct Exposition(String);
+
+impl Exposition {
+	fn family(&mut self, name:&str, kind:&str, help:&str) {
+		let _ = writeln!(self.0, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+	}
+
+	fn sample(&mut self, name:&str, labels:&str, val:impl std::fmt::Display) {
+		match labels {
+			"" => { let _ = writeln!(self.0, "{} {}", name, val); },
+			_ => { let _ = writeln!(self.0, "{}{{{}}} {}", name, labels, val); }
+		}
+	}
+
+	fn single(&mut self, name:&str, kind:&str, help:&str, val:impl std::fmt::Display) {
+		self.family(name, kind, help);
+		self.sample(name, "", val);
+	}
+
+	// Buckets are bounded in seconds, and are cumulative
+	fn histogram(&mut self, name:&str, labels:&str, lat:&Latencies) {
+		let bucket = format!("{}_bucket", name);
+		let cumulative = lat.cumulative();
+		for (bound, count) in LATENCY_BOUNDS_NS.iter().zip(cumulative.iter()) {
+			self.sample(&bucket, &format!("{},le=\"{}\"", labels, *bound as f64 / 1e9), count);
+		}
+		self.sample(&bucket, &format!("{},le=\"+Inf\"", labels), lat.count());
+		self.sample(&format!("{}_sum", name), labels, lat.sum_ns as f64 / 1e9);
+		self.sample(&format!("{}_count", name), labels, lat.count());
+	}
+}
+
+// Every metric of the database, in the Prometheus text format
+pub fn render(db:&Database) -> String {
+	let mut out = Exposition(String::with_capacity(16 * 1024));
+	let stats = db.cmd_stats();
+	out.family("floton_commands_total", "counter", "Commands run.");
+	for (cmd, name) in CMD_NAMES.iter().enumerate().skip(1) {
+		out.sample("floton_commands_total", &format!("cmd=\"{}\"", name), stats.runs(cmd as u8));
+	}
+	out.family("floton_command_errors_total", "counter", "Commands that gave back an error.");
+	for (cmd, name) in CMD_NAMES.iter().enumerate().skip(1) {
+		out.sample("floton_command_errors_total", &format!("cmd=\"{}\"", name), stats.errors(cmd as u8));
+	}
+	out.family("floton_command_duration_seconds", "histogram", "Time taken to run commands.");
+	for (cmd, name) in CMD_NAMES.iter().enumerate().skip(1) {
+		out.histogram("floton_command_duration_seconds", &format!("cmd=\"{}\"", name), &stats.latency(cmd as u8));
+	}
+	out.family("floton_atomic_op_duration_seconds", "histogram", "Time taken to run atomic ops.");
+	for (op, name) in OP_ATOMIC_NAMES.iter().enumerate() {
+		out.histogram("floton_atomic_op_duration_seconds", &format!("op=\"{}\"", name), &stats.op_latency(op as u16));
+	}
+	out.single("floton_request_bytes_total", "counter", "Bytes of requests read, headers included.", stats.bytes_in());
+	out.single("floton_response_bytes_total", "counter", "Bytes of responses written, headers included.", stats.bytes_out());
+	if let Some(server) = db.server_stats() {
+		out.single("floton_busy_rejections_total", "counter", "Connections turned away with no free worker or backlog room.",
+		           server.rejected.load(Ordering::Relaxed));
+		out.single("floton_backlogged_total", "counter", "Connections that had to wait in the backlog for a worker.",
+		           server.queued.load(Ordering::Relaxed));
+		out.single("floton_backlog", "gauge", "Connections waiting in the backlog.", server.backlog_len.load(Ordering::Relaxed));
+		out.single("floton_workers", "gauge", "Worker threads in the pool.", server.workers.load(Ordering::Relaxed));
+		out.single("floton_worker_queue", "gauge", "Connections queued on the workers.", server.worker_queue.load(Ordering::Relaxed));
+		out.single("floton_connections", "gauge", "Open connections.", server.conns.load(Ordering::Relaxed));
+		out.single("floton_connections_parked", "gauge", "Connections waiting on a queue.", server.parked.load(Ordering::Relaxed));
+		out.single("floton_connections_refused_total", "counter", "Connections refused over --max-conns.", server.refused.load(Ordering::Relaxed));
+		out.single("floton_connections_timed_out_total", "counter", "Connections closed on a timeout.", server.timed_out.load(Ordering::Relaxed));
+	}
+	let memory = db.memory();
+	out.single("floton_memory_used_bytes", "gauge", "Bytes held by the keyspace, including versions waiting to be reclaimed.", memory.used());
+	out.single("floton_memory_live_bytes", "gauge", "Bytes held by live keys.", memory.live());
+	out.single("floton_memory_limit_bytes", "gauge", "The --max-memory limit, 0 if unlimited.", memory.limit());
+	out.single("floton_evicted_keys_total", "counter", "Keys evicted to stay under --max-memory.", memory.evicted());
+	out.single("floton_slab_reserved_bytes", "gauge", "Bytes reserved by the slab allocators.", slab::reserved());
+	out.single("floton_reclaim_epoch", "counter", "The reclamation epoch.", reclaim::epoch());
+	out.single("floton_reclaim_pending", "gauge", "Retired versions waiting to be freed.", reclaim::pending());
+	out.single("floton_reclaim_pending_bytes", "gauge", "Bytes of retired versions waiting to be freed.", reclaim::pending_bytes());
+	out.single("floton_threads", "gauge", "Threads holding a thread ID.", tlocal::live_threads());
+	out.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::*;
+    use crate::settings::Settings;
+
+    fn scrape(addr:SocketAddr, request:&str) -> String {
+    	let mut stream = TcpStream::connect(addr).unwrap();
+    	stream.write_all(request.as_bytes()).unwrap();
+    	let mut resp = String::new();
+    	stream.read_to_string(&mut resp).unwrap();
+    	resp
+    }
+
+    #[test]
+    fn render_works() {
+    	let db = Database::new_from_settings(Settings::new());
+    	db.cmd_stats().count_run(CMD_SET_KV);
+    	db.cmd_stats().time_cmd(CMD_SET_KV, Duration::from_micros(2));
+    	db.cmd_stats().time_op(OP_ATOMIC_ADD, Duration::from_millis(2));
+    	db.cmd_stats().count_bytes(30, 12);
+    	let text = render(&db);
+    	assert!(text.contains("# TYPE floton_command_duration_seconds histogram\n"));
+    	assert!(text.contains("floton_commands_total{cmd=\"set_kv\"} 1\n"));
+    	assert!(text.contains("floton_command_duration_seconds_bucket{cmd=\"set_kv\",le=\"0.000001\"} 0\n"));
+    	assert!(text.contains("floton_command_duration_seconds_bucket{cmd=\"set_kv\",le=\"0.000005\"} 1\n"));
+    	assert!(text.contains("floton_command_duration_seconds_bucket{cmd=\"set_kv\",le=\"+Inf\"} 1\n"));
+    	assert!(text.contains("floton_command_duration_seconds_count{cmd=\"set_kv\"} 1\n"));
+    	assert!(text.contains("floton_atomic_op_duration_seconds_bucket{op=\"add\",le=\"0.001\"} 0\n"));
+    	assert!(text.contains("floton_atomic_op_duration_seconds_bucket{op=\"add\",le=\"0.005\"} 1\n"));
+    	assert!(text.contains("floton_atomic_op_duration_seconds_sum{op=\"add\"} 0.002\n"));
+    	assert!(text.contains("floton_request_bytes_total 30\n"));
+    	assert!(text.contains("floton_response_bytes_total 12\n"));
+    	assert!(text.contains("floton_memory_limit_bytes 0\n"));
+    	// not constructed, so there are no server stats yet
+    	assert!(!text.contains("floton_workers"));
+    	// every family is typed once
+    	assert_eq!(text.matches("# TYPE floton_commands_total ").count(), 1);
+    }
+
+    #[test]
+    fn serve_works() {
+    	let mut db = Database::new_from_settings(Settings::new());
+    	let mut server = MetricsServer::start("127.0.0.1", 0, TcpServerContext::new(&mut db)).unwrap();
+    	let resp = scrape(server.addr(), "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    	assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+    	assert!(resp.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+    	assert!(resp.contains("floton_memory_used_bytes "));
+    	let resp = scrape(server.addr(), "GET /other HTTP/1.1\r\n\r\n");
+    	assert!(resp.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    	let resp = scrape(server.addr(), "POST /metrics HTTP/1.1\r\n\r\n");
+    	assert!(resp.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    	server.stop();
+    	assert!(TcpStream::connect(server.addr()).is_err());
+    }
+}
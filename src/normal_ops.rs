@@ -3,8 +3,14 @@ use std::sync::atomic::Ordering;
 use crate::constants::*;
 use crate::values::Value;
 use crate::shared::{Shared, TimePtr};
-use crate::containers::Container;
+use crate::containers::{Container, list_output_binary, set_output_binary};
+use crate::lists::List;
+use crate::sets::Set;
+use crate::sketches::Sketch;
+use crate::keys;
 use crate::errors::FlotonErr;
+use crate::memory::{self, Memory};
+use crate::tlocal;
 use crate::traits::*;
 use crate::fast_output::{out_bool, out_u64, out_i64};
 
@@ -12,18 +18,227 @@ use crate::fast_output::{out_bool, out_u64, out_i64};
  * Files that handles normal operations (types can be anything)
  */
 
-pub fn run_normal_operation(place: &mut usize, cmd:&[u8], key:*const u64, data:&Shared<Container<Value>>, output:&mut Vec<u8>) -> Result<(), FlotonErr> {
-	let op_type = unsafe { *( cmd.as_ptr().offset(*place as isize) as *const u16)};
+fn read_i64(cmd:&[u8], place: &mut usize) -> Result<i64, FlotonErr> {
+	keys::read_u64(cmd, place).map(|val| val as i64)
+}
+
+#[derive(Debug)]
+enum ListOp {
+	Push(Container<Value>, bool /*back*/),
+	Pop(bool /*back*/),
+	Get(i64),
+	Set(i64, Container<Value>),
+	Len,
+	Range(i64, i64)
+}
+
+impl ListOp {
+	// Reads the op's arguments, so they are passed over even if it fails
+	fn input(op_type:u16, cmd:&[u8], place: &mut usize) -> Result<ListOp, FlotonErr> {
+		Ok(match op_type {
+			OP_NORM_LIST_PUSH_BACK => ListOp::Push(Container::input_binary(cmd, place)?, true),
+			OP_NORM_LIST_PUSH_FRONT => ListOp::Push(Container::input_binary(cmd, place)?, false),
+			OP_NORM_LIST_POP_BACK => ListOp::Pop(true),
+			OP_NORM_LIST_POP_FRONT => ListOp::Pop(false),
+			OP_NORM_LIST_GET => ListOp::Get(read_i64(cmd, place)?),
+			OP_NORM_LIST_SET => {
+				let idx = read_i64(cmd, place)?;
+				ListOp::Set(idx, Container::input_binary(cmd, place)?)
+			},
+			OP_NORM_LIST_LEN => ListOp::Len,
+			OP_NORM_LIST_RANGE => {
+				let start = read_i64(cmd, place)?;
+				ListOp::Range(start, read_i64(cmd, place)?)
+			},
+			_ => return Err(FlotonErr::UnexpectedByte((op_type >> 8) as u8))
+		})
+	}
+
+	// Bytes the op adds to the list, if it succeeds
+	fn added(&self) -> usize {
+		match self {
+			ListOp::Push(arg, _) | ListOp::Set(_, arg) => memory::list_item_size(arg),
+			_ => 0
+		}
+	}
+
+	fn run(self, key:*const u64, list:&List<Value>, memory:&Memory, output:&mut Vec<u8>) -> Result<(), FlotonErr> {
+		let added = self.added();
+		match self {
+			ListOp::Push(arg, back) => {
+				out_u64(list.push(arg, back) as u64, output);
+				memory.add(added);
+			},
+			ListOp::Pop(back) => match list.pop(back) {
+				Some(popped) => {
+					memory.sub(memory::list_item_size(&popped));
+					popped.output_binary(output);
+				},
+				None => Value::Nothing.output_binary(output)
+			},
+			ListOp::Get(idx) => match list.get(idx) {
+				Some(item) => item.output_binary(output),
+				None => return Err(FlotonErr::ReturnNotFound(key))
+			},
+			ListOp::Set(idx, arg) => {
+				// another write may replace it first, but it is near enough
+				let replaced = list.get(idx).map_or(0, |item| memory::list_item_size(&item));
+				if !list.set(idx, arg) {
+					return Err(FlotonErr::ReturnNotFound(key));
+				}
+				memory.add(added);
+				memory.sub(replaced);
+			},
+			ListOp::Len => out_u64(list.len() as u64, output),
+			ListOp::Range(start, end) => list_output_binary(&list.range(start, end), output)
+		}
+		Ok(())
+	}
+}
+
+#[derive(Debug)]
+enum SetOp<'a> {
+	Add(&'a [u8]),
+	Remove(&'a [u8]),
+	IsMember(&'a [u8]),
+	Card,
+	Members(u64 /*cursor*/, usize /*limit*/)
+}
+
+impl<'a> SetOp<'a> {
+	fn input(op_type:u16, cmd:&'a [u8], place: &mut usize) -> Result<SetOp<'a>, FlotonErr> {
+		Ok(match op_type {
+			OP_NORM_SET_ADD => SetOp::Add(keys::read_key(cmd, place)?),
+			OP_NORM_SET_REMOVE => SetOp::Remove(keys::read_key(cmd, place)?),
+			OP_NORM_SET_IS_MEMBER => SetOp::IsMember(keys::read_key(cmd, place)?),
+			OP_NORM_SET_CARD => SetOp::Card,
+			OP_NORM_SET_MEMBERS => {
+				let cursor = keys::read_u64(cmd, place)?;
+				SetOp::Members(cursor, keys::read_u64(cmd, place)? as usize)
+			},
+			_ => return Err(FlotonErr::UnexpectedByte((op_type >> 8) as u8))
+		})
+	}
+
+	// Bytes the op adds to the set, if it succeeds
+	fn added(&self) -> usize {
+		match self {
+			SetOp::Add(member) => memory::member_size(member),
+			_ => 0
+		}
+	}
+
+	fn run(self, set:&Set, memory:&Memory, output:&mut Vec<u8>) {
+		match self {
+			SetOp::Add(member) => {
+				let added = set.add(member);
+				if added {
+					memory.add(memory::member_size(member));
+				}
+				out_bool(added, output);
+			},
+			SetOp::Remove(member) => {
+				let removed = set.remove(member);
+				if removed {
+					memory.sub(memory::member_size(member));
+				}
+				out_bool(removed, output);
+			},
+			SetOp::IsMember(member) => out_bool(set.contains(member), output),
+			SetOp::Card => out_u64(set.len() as u64, output),
+			SetOp::Members(cursor, limit) => {
+				let (page, next) = set.members(cursor, limit);
+				out_u64(next, output);
+				set_output_binary(page.into_iter(), output);
+			}
+		}
+	}
+}
+
+#[derive(Debug)]
+enum SketchOp<'a> {
+	Add(&'a [u8]),
+	Count,
+	Check(&'a [u8]),
+	Merge(Sketch)
+}
+
+impl<'a> SketchOp<'a> {
+	fn input(op_type:u16, cmd:&'a [u8], place: &mut usize) -> Result<SketchOp<'a>, FlotonErr> {
+		Ok(match op_type {
+			OP_NORM_SKETCH_ADD => SketchOp::Add(keys::read_key(cmd, place)?),
+			OP_NORM_SKETCH_COUNT => SketchOp::Count,
+			OP_NORM_SKETCH_CHECK => SketchOp::Check(keys::read_key(cmd, place)?),
+			OP_NORM_SKETCH_MERGE => SketchOp::Merge(Sketch::input_binary(cmd, place)?),
+			_ => return Err(FlotonErr::UnexpectedByte((op_type >> 8) as u8))
+		})
+	}
+
+	fn run(self, key:*const u64, sketch:&Sketch, output:&mut Vec<u8>) -> Result<(), FlotonErr> {
+		match self {
+			SketchOp::Add(item) => out_bool(sketch.add(item), output),
+			SketchOp::Count => out_u64(sketch.count(), output),
+			SketchOp::Check(item) => match sketch.check(item) {
+				Some(seen) => out_bool(seen, output),
+				None => return Err(FlotonErr::OperationNoSupport(key, sketch.vbin_type(), OP_NORM_SKETCH_CHECK))
+			},
+			SketchOp::Merge(other) => if !sketch.merge(&other) {
+				return Err(FlotonErr::SketchMismatch(key));
+			}
+		}
+		Ok(())
+	}
+}
+
+// Runs the op on data, a value under root. Anything the op adds has to be
+// admitted under root's memory limit first.
+pub fn run_normal_operation(place: &mut usize, cmd:&[u8], key:*const u64, root:&Container<Value>, data:&Shared<Container<Value>>, output:&mut Vec<u8>) -> Result<(), FlotonErr> {
+	let op_type = unsafe { ( cmd.as_ptr().offset(*place as isize) as *const u16).read_unaligned() };
 	*place += 2;
+	let memory = tlocal::get_memory();
 	match op_type {
 		OP_NORM_UPDATE => {
-            let arg = match Value::input_binary(cmd, place) {
-                Ok(v) => v,
-                Err(e) => return Err(e)
-            };
-            data.write(TimePtr::make(Container::Val(arg)));
+            let arg = Container::Val(Value::input_binary(cmd, place)?);
+            if !memory.admit(root, memory::version_size(&arg)) {
+                return Err(FlotonErr::OutOfMemory(key));
+            }
+            // the slot is already there, so its key is already counted
+            memory.write(data, &[], arg);
             Ok(())
 		},
+		OP_NORM_LIST_PUSH_BACK..=OP_NORM_LIST_RANGE => {
+			let op = ListOp::input(op_type, cmd, place)?;
+			if !memory.admit(root, op.added()) {
+				return Err(FlotonErr::OutOfMemory(key));
+			}
+			let current = unsafe { &data.read().as_ref().unwrap().0 };
+			match current.list() {
+				Some(list) => op.run(key, list, memory, output),
+				None => Err(FlotonErr::OperationNoSupport(key, current.vbin_type(), op_type))
+			}
+		},
+		OP_NORM_SET_ADD..=OP_NORM_SET_MEMBERS => {
+			let op = SetOp::input(op_type, cmd, place)?;
+			if !memory.admit(root, op.added()) {
+				return Err(FlotonErr::OutOfMemory(key));
+			}
+			let current = unsafe { &data.read().as_ref().unwrap().0 };
+			match current.set() {
+				Some(set) => {
+					op.run(set, memory, output);
+					Ok(())
+				},
+				None => Err(FlotonErr::OperationNoSupport(key, current.vbin_type(), op_type))
+			}
+		},
+		OP_NORM_SKETCH_ADD..=OP_NORM_SKETCH_MERGE => {
+			let op = SketchOp::input(op_type, cmd, place)?;
+			let current = unsafe { &data.read().as_ref().unwrap().0 };
+			match current.sketch() {
+				Some(sketch) => op.run(key, sketch, output),
+				None => Err(FlotonErr::OperationNoSupport(key, current.vbin_type(), op_type))
+			}
+		},
 		_ => Err(FlotonErr::UnexpectedByte((op_type >> 8) as u8))
 	}
 }
@@ -31,6 +246,8 @@ pub fn run_normal_operation(place: &mut usize, cmd:&[u8], key:*const u64, data:&
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::tlocal;
+    use crate::sketches::{Hll, Bloom};
     //use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
     #[test]
@@ -42,8 +259,131 @@ mod tests {
     	let cmd = [op_16[0], op_16[1], VBIN_BOOL, 1, /*Unrelated byte*/ 56];
     	let mut output = vec![];
     	let mut i = 0;
-    	run_normal_operation(&mut i, &cmd, key.as_ptr(), &obj, &mut output).expect("Unable to run normal operation");
+    	run_normal_operation(&mut i, &cmd, key.as_ptr(), &Container::new_map(4), &obj, &mut output).expect("Unable to run normal operation");
     	assert_eq!(i, 4);
     	unsafe { assert!(obj.read().as_ref().unwrap().0.value().unwrap().to_bool()); }
     }
-}
\ No newline at end of file
+
+    fn run_op(op:u16, args:&[u8], obj:&Shared<Container<Value>>) -> Result<Vec<u8>, FlotonErr> {
+    	let key:[u64;3] = [1, 8, 4455];
+    	let mut cmd = op.to_le_bytes().to_vec();
+    	cmd.extend_from_slice(args);
+    	let mut output = vec![];
+    	let mut i = 0;
+    	let res = run_normal_operation(&mut i, &cmd, key.as_ptr(), &Container::new_map(4), obj, &mut output);
+    	// arguments are passed over either way
+    	assert_eq!(i, cmd.len());
+    	res.map(|_| output)
+    }
+
+    fn uint_arg(val:u64) -> Vec<u8> {
+    	let mut arg = vec![VBIN_UINT];
+    	arg.extend_from_slice(&val.to_le_bytes());
+    	arg
+    }
+
+    #[test]
+    fn list_ops_works() {
+    	tlocal::set_epoch();
+    	let obj = Shared::<Container<Value>>::new();
+    	obj.write(TimePtr::make(Container::List(List::new(Default::default()))));
+    	assert_eq!(run_op(OP_NORM_LIST_PUSH_BACK, &uint_arg(2), &obj).unwrap(), uint_arg(1));
+    	assert_eq!(run_op(OP_NORM_LIST_PUSH_BACK, &uint_arg(3), &obj).unwrap(), uint_arg(2));
+    	assert_eq!(run_op(OP_NORM_LIST_PUSH_FRONT, &uint_arg(1), &obj).unwrap(), uint_arg(3));
+    	assert_eq!(run_op(OP_NORM_LIST_GET, &(-1i64).to_le_bytes(), &obj).unwrap(), uint_arg(3));
+    	let mut set_args = 1i64.to_le_bytes().to_vec();
+    	set_args.extend(uint_arg(20));
+    	assert!(run_op(OP_NORM_LIST_SET, &set_args, &obj).unwrap().is_empty());
+    	let mut range_args = 0i64.to_le_bytes().to_vec();
+    	range_args.extend_from_slice(&2i64.to_le_bytes());
+    	let mut expect = vec![VBIN_LIST_BEGIN];
+    	expect.extend(uint_arg(1));
+    	expect.extend(uint_arg(20));
+    	expect.push(VBIN_LIST_END);
+    	assert_eq!(run_op(OP_NORM_LIST_RANGE, &range_args, &obj).unwrap(), expect);
+    	assert_eq!(run_op(OP_NORM_LIST_POP_FRONT, &[], &obj).unwrap(), uint_arg(1));
+    	assert_eq!(run_op(OP_NORM_LIST_POP_BACK, &[], &obj).unwrap(), uint_arg(3));
+    	assert_eq!(run_op(OP_NORM_LIST_LEN, &[], &obj).unwrap(), uint_arg(1));
+    	assert_eq!(run_op(OP_NORM_LIST_POP_BACK, &[], &obj).unwrap(), uint_arg(20));
+    	assert_eq!(run_op(OP_NORM_LIST_POP_BACK, &[], &obj).unwrap(), vec![VBIN_NOTHING]);
+    	match run_op(OP_NORM_LIST_GET, &0i64.to_le_bytes(), &obj) {
+    		Err(FlotonErr::ReturnNotFound(_)) => (),
+    		other => panic!("Expected not found, got {:?}", other)
+    	}
+    }
+
+    #[test]
+    fn list_ops_not_list_works() {
+    	tlocal::set_epoch();
+    	let obj = Shared::<Container<Value>>::new();
+    	obj.write(TimePtr::make(Container::Val(Value::UInt(0))));
+    	match run_op(OP_NORM_LIST_PUSH_BACK, &uint_arg(2), &obj) {
+    		Err(FlotonErr::OperationNoSupport(_, VBIN_UINT, OP_NORM_LIST_PUSH_BACK)) => (),
+    		other => panic!("Expected no support, got {:?}", other)
+    	}
+    }
+
+    fn key_arg(member:&[u8]) -> Vec<u8> {
+    	let mut arg = vec![];
+    	keys::write_key(member, &mut arg);
+    	arg
+    }
+
+    #[test]
+    fn set_ops_works() {
+    	tlocal::set_epoch();
+    	let obj = Shared::<Container<Value>>::new();
+    	obj.write(TimePtr::make(Container::Set(Set::new(4))));
+    	assert_eq!(run_op(OP_NORM_SET_ADD, &key_arg(b"red"), &obj).unwrap(), vec![VBIN_BOOL, 1]);
+    	assert_eq!(run_op(OP_NORM_SET_ADD, &key_arg(b"red"), &obj).unwrap(), vec![VBIN_BOOL, 0]);
+    	assert_eq!(run_op(OP_NORM_SET_ADD, &key_arg(b"blue"), &obj).unwrap(), vec![VBIN_BOOL, 1]);
+    	assert_eq!(run_op(OP_NORM_SET_IS_MEMBER, &key_arg(b"blue"), &obj).unwrap(), vec![VBIN_BOOL, 1]);
+    	assert_eq!(run_op(OP_NORM_SET_CARD, &[], &obj).unwrap(), uint_arg(2));
+    	assert_eq!(run_op(OP_NORM_SET_REMOVE, &key_arg(b"blue"), &obj).unwrap(), vec![VBIN_BOOL, 1]);
+    	assert_eq!(run_op(OP_NORM_SET_IS_MEMBER, &key_arg(b"blue"), &obj).unwrap(), vec![VBIN_BOOL, 0]);
+    	let mut page_args = 0u64.to_le_bytes().to_vec();
+    	page_args.extend_from_slice(&10u64.to_le_bytes());
+    	let mut expect = uint_arg(0);
+    	expect.push(VBIN_SET_BEGIN);
+    	expect.extend(key_arg(b"red"));
+    	expect.push(VBIN_SET_END);
+    	assert_eq!(run_op(OP_NORM_SET_MEMBERS, &page_args, &obj).unwrap(), expect);
+    	let list_obj = Shared::<Container<Value>>::new();
+    	list_obj.write(TimePtr::make(Container::List(List::new(Default::default()))));
+    	match run_op(OP_NORM_SET_ADD, &key_arg(b"red"), &list_obj) {
+    		Err(FlotonErr::OperationNoSupport(_, VBIN_LIST_BEGIN, OP_NORM_SET_ADD)) => (),
+    		other => panic!("Expected no support, got {:?}", other)
+    	}
+    }
+
+    #[test]
+    fn sketch_ops_works() {
+    	tlocal::set_epoch();
+    	let obj = Shared::<Container<Value>>::new();
+    	obj.write(TimePtr::make(Container::Sketch(Sketch::Bloom(Bloom::new(16, 4)))));
+    	assert_eq!(run_op(OP_NORM_SKETCH_ADD, &key_arg(b"alice"), &obj).unwrap(), vec![VBIN_BOOL, 1]);
+    	assert_eq!(run_op(OP_NORM_SKETCH_ADD, &key_arg(b"alice"), &obj).unwrap(), vec![VBIN_BOOL, 0]);
+    	assert_eq!(run_op(OP_NORM_SKETCH_CHECK, &key_arg(b"alice"), &obj).unwrap(), vec![VBIN_BOOL, 1]);
+    	assert_eq!(run_op(OP_NORM_SKETCH_COUNT, &[], &obj).unwrap(), uint_arg(1));
+    	let other = Bloom::new(16, 4);
+    	other.add(b"bob");
+    	let mut merge_arg = vec![];
+    	Sketch::Bloom(other).output_binary(&mut merge_arg);
+    	assert!(run_op(OP_NORM_SKETCH_MERGE, &merge_arg, &obj).unwrap().is_empty());
+    	assert_eq!(run_op(OP_NORM_SKETCH_CHECK, &key_arg(b"bob"), &obj).unwrap(), vec![VBIN_BOOL, 1]);
+    	let mut small_arg = vec![];
+    	Sketch::Bloom(Bloom::new(8, 4)).output_binary(&mut small_arg);
+    	match run_op(OP_NORM_SKETCH_MERGE, &small_arg, &obj) {
+    		Err(FlotonErr::SketchMismatch(_)) => (),
+    		other => panic!("Expected sketch mismatch, got {:?}", other)
+    	}
+    	let hll_obj = Shared::<Container<Value>>::new();
+    	hll_obj.write(TimePtr::make(Container::Sketch(Sketch::Hll(Hll::new(8)))));
+    	assert_eq!(run_op(OP_NORM_SKETCH_ADD, &key_arg(b"alice"), &hll_obj).unwrap(), vec![VBIN_BOOL, 1]);
+    	assert_eq!(run_op(OP_NORM_SKETCH_COUNT, &[], &hll_obj).unwrap(), uint_arg(1));
+    	match run_op(OP_NORM_SKETCH_CHECK, &key_arg(b"alice"), &hll_obj) {
+    		Err(FlotonErr::OperationNoSupport(_, VBIN_HLL, OP_NORM_SKETCH_CHECK)) => (),
+    		other => panic!("Expected no support, got {:?}", other)
+    	}
+    }
+}
@@ -1,12 +1,22 @@
 use std::ptr;
+use std::convert::TryInto;
+use std::ops::Bound;
 use std::sync::atomic::Ordering;
+use std::time::Instant;
 use crate::atomic_ops::run_atomic_operation;
 use crate::normal_ops::run_normal_operation;
 use crate::constants;
+use crate::keys;
 use crate::values::Value;
 use crate::tlocal;
-use crate::containers::Container;
+use crate::containers::{Container, entries_output_binary, set_output_binary};
+use crate::skiplist::SkipList;
+use crate::queues::Queue;
+use crate::sets::Set;
+use crate::shared::Shared;
 use crate::errors::FlotonErr;
+use crate::memory::{self, MemSize};
+use crate::settings::ConfigErr;
 use crate::logging::*;
 use crate::traits::*;
 use std::io::prelude::*;
@@ -20,99 +30,139 @@ use std::io::prelude::*;
 enum KeyAction {
     Return,
     AtomicOp,
-    NormalOp
+    NormalOp,
+    Range
 }
 
-fn run_key_action(action:KeyAction, place: &mut usize, cmd:&[u8], data:&Container<Value>, output:&mut Vec<u8>) -> Result<(), FlotonErr> {
-    let mut key_ptr = unsafe { cmd.as_ptr().offset(*place as isize) as *const u64 };
-    let key_orig = key_ptr;
-    let key_depth = unsafe { *key_ptr };
-    key_ptr = unsafe { key_ptr.offset(1) };
-    *place += 8;
-    let mut cur_map = data;
-    let mut not_found = false;
+/**
+ * The bounds of a range read, a zero limit returns every entry
+ */
+#[derive(Debug)]
+struct RangeArgs<'a> {
+    start:Bound<&'a [u8]>,
+    end:Bound<&'a [u8]>,
+    limit:usize,
+    reverse:bool
+}
+
+impl<'a> RangeArgs<'a> {
+    fn input(cmd:&'a [u8], place: &mut usize) -> Result<RangeArgs<'a>, FlotonErr> {
+        let flags = *cmd.get(*place).ok_or(FlotonErr::BadRequest(*place as u64))?;
+        *place += 1;
+        let mut read_bound = |has, incl| -> Result<Bound<&'a [u8]>, FlotonErr> {
+            Ok(if flags & has == 0 {
+                Bound::Unbounded
+            } else if flags & incl == 0 {
+                Bound::Excluded(keys::read_key(cmd, place)?)
+            } else {
+                Bound::Included(keys::read_key(cmd, place)?)
+            })
+        };
+        let start = read_bound(constants::RANGE_HAS_START, constants::RANGE_START_INCL)?;
+        let end = read_bound(constants::RANGE_HAS_END, constants::RANGE_END_INCL)?;
+        let limit = keys::read_u64(cmd, place)? as usize;
+        Ok(RangeArgs{start, end, limit:if limit == 0 { usize::MAX } else { limit }, reverse:flags & constants::RANGE_REVERSE != 0})
+    }
+
+    // Writes the matched entries as an ordered map
+    fn output(&self, map:&SkipList<Shared<Container<Value>>>, output:&mut Vec<u8>) {
+        output.push(constants::VBIN_OMAP_BEGIN);
+        if self.reverse {
+            entries_output_binary(map.range_rev(self.start, self.end).take(self.limit), output);
+        } else {
+            entries_output_binary(map.range(self.start, self.end).take(self.limit), output);
+        }
+        output.push(constants::VBIN_OMAP_END);
+    }
+}
+
+// Where a key path starts, the map holding its last key and that key
+type FollowedKey<'a, 'b> = (*const u64, Option<&'a Container<Value>>, &'b [u8]);
+
+// Reads a key path, returning the map holding its last key, if every map
+// along it is there. The whole path is read either way.
+fn follow_key<'a, 'b>(place: &mut usize, cmd:&'b [u8], data:&'a Container<Value>) -> Result<FollowedKey<'a, 'b>, FlotonErr> {
+    let start = *place;
+    let key_orig = unsafe { cmd.as_ptr().add(start) as *const u64 };
+    let key_depth = keys::read_u64(cmd, place)?;
+    if key_depth == 0 {
+        return Err(FlotonErr::BadRequest(start as u64));
+    }
+    let mut cur_map = Some(data);
     //advance to last before end
     for _ in 0..(key_depth-1) {
-        let key_len = (unsafe { *key_ptr }) as usize; // todo align error
-        key_ptr = unsafe { key_ptr.offset(1) };
-        *place += 8;
-        if !not_found {
-            match (*cur_map).get_map(&cmd[*place..(*place + key_len)]) {
-                Some(inner_map) => cur_map = inner_map,
-                None => {
-                    // start skipping
-                    not_found = true;
-                }
-            }
-        }
-        key_ptr = unsafe { key_ptr.offset((key_len / 8) as isize) };
-        *place += key_len;
+        let key = keys::read_key(cmd, place)?;
+        cur_map = cur_map.and_then(|m| m.get_map(key));
     }
-    if !not_found  {
+    Ok((key_orig, cur_map, keys::read_key(cmd, place)?))
+}
+
+fn run_key_action(action:KeyAction, place: &mut usize, cmd:&[u8], data:&Container<Value>, output:&mut Vec<u8>) -> Result<(), FlotonErr> {
+    let (key_orig, found_map, key) = follow_key(place, cmd, data)?;
+    // the bounds are read even when the map isn't found, to reach the next command
+    let range = match action {
+        KeyAction::Range => Some(RangeArgs::input(cmd, place)?),
+        _ => None
+    };
+    if let Some(cur_map) = found_map {
         // take special action at last key segment
         match action {
             KeyAction::Return => {
-                let key_len = (unsafe { *key_ptr }) as usize; // todo align error
-                key_ptr = unsafe { key_ptr.offset(1) };
-                *place += 8;
-                return match (*cur_map).get_map(&cmd[*place..(*place + key_len)]) {
+                return match (*cur_map).get_map(key) {
                     Some(inner_obj) => { 
                         inner_obj.output_binary(output);
-                        *place += key_len;
                         Ok(())
                     },
-                    None => {
-                        *place += key_len;
-                        Err(FlotonErr::ReturnNotFound(key_orig)) 
-                    }
+                    None => Err(FlotonErr::ReturnNotFound(key_orig))
                 };
             },
             KeyAction::AtomicOp => {
-                let key_len = (unsafe { *key_ptr }) as usize; // todo align error
-                key_ptr = unsafe { key_ptr.offset(1) };
-                *place += 8;
-                return match (*cur_map).get_map(&cmd[*place..(*place + key_len)]) {
+                return match (*cur_map).get_map(key) {
                     Some(inner_obj) => {
-                        *place += key_len;
                         let atomic_val = inner_obj.value();
                         match atomic_val {
                             Ok(v) => {
-                                run_atomic_operation(place, cmd, key_orig, v, output)
+                                let stats = tlocal::get_stats();
+                                let op_type = cmd.get(*place..(*place + 2)).map_or(u16::MAX, |b| u16::from_le_bytes(b.try_into().unwrap()));
+                                let began = stats.map(|_| Instant::now());
+                                let ran = run_atomic_operation(place, cmd, key_orig, v, output);
+                                if let (Some(stats), Some(began)) = (stats, began) {
+                                    stats.time_op(op_type, began.elapsed());
+                                }
+                                ran
                             },
                             Err(b) => Err(FlotonErr::TypeNotAtomic(key_orig, b))
                         }
                     },
-                    None => { 
-                        *place += key_len;
-                        Err(FlotonErr::ReturnNotFound(key_orig)) 
-                    }
+                    None => Err(FlotonErr::ReturnNotFound(key_orig))
                 };
             },
             KeyAction::NormalOp => {
-                let key_len = (unsafe { *key_ptr }) as usize; // todo align error
-                key_ptr = unsafe { key_ptr.offset(1) };
-                *place += 8;
-                return match (*cur_map).get_map_shared(&cmd[*place..(*place + key_len)]) {
+                return match (*cur_map).get_map_shared(key) {
                     Some(inner_shared) => {
-                        *place += key_len;
-                        if inner_shared.is_empty() {
-                            Err(FlotonErr::ReturnNotFound(key_orig)) 
-                        } else {
-                            run_normal_operation(place, cmd, key_orig, inner_shared, output)
+                        match unsafe { inner_shared.read().as_ref() } {
+                            Some(version) => {
+                                version.touch();
+                                run_normal_operation(place, cmd, key_orig, data, inner_shared, output)
+                            },
+                            None => Err(FlotonErr::ReturnNotFound(key_orig))
                         }
                     },
-                    None => { 
-                        key_ptr = unsafe { key_ptr.offset((key_len / 8) as isize) };
-                        *place += key_len;
-                        Err(FlotonErr::ReturnNotFound(key_orig)) 
-                    }
+                    None => Err(FlotonErr::ReturnNotFound(key_orig))
+                };
+            },
+            KeyAction::Range => {
+                return match (*cur_map).get_map(key) {
+                    Some(Container::OrdMap(m)) => {
+                        range.unwrap().output(m, output);
+                        Ok(())
+                    },
+                    Some(other) => Err(FlotonErr::OperationNoSupport(key_orig, other.vbin_type(), constants::CMD_RANGE as u16)),
+                    None => Err(FlotonErr::ReturnNotFound(key_orig))
                 };
             }
         }
     } else {
-        let key_len = (unsafe { *key_ptr }) as usize; // todo align error
-        *place += 8;
-        *place += key_len;
         Err(FlotonErr::ReturnNotFound(key_orig))
     }
 }
@@ -130,80 +180,318 @@ fn run_cmd_op_normal(place: &mut usize, cmd:&[u8], data:&Container<Value>, outpu
     run_key_action(KeyAction::NormalOp, place, cmd, data, output)
 }
 
+fn run_cmd_range(place: &mut usize, cmd:&[u8], data:&Container<Value>, output:&mut Vec<u8>) -> Result<(), FlotonErr> {
+    run_key_action(KeyAction::Range, place, cmd, data, output)
+}
+
+// The queue at a key path, or why there isn't one
+fn queue_at<'a>(key_orig:*const u64, found_map:Option<&'a Container<Value>>, key:&[u8], cmd_type:u8) -> Result<&'a Queue<Value>, FlotonErr> {
+    match found_map.and_then(|m| m.get_map(key)) {
+        Some(Container::Queue(q)) => Ok(q),
+        Some(other) => Err(FlotonErr::OperationNoSupport(key_orig, other.vbin_type(), cmd_type as u16)),
+        None => Err(FlotonErr::ReturnNotFound(key_orig))
+    }
+}
+
+fn run_cmd_qpush(place: &mut usize, cmd:&[u8], data:&Container<Value>) -> Result<(), FlotonErr> {
+    let start = *place;
+    let (key_orig, _, _) = follow_key(place, cmd, data)?;
+    let val = Container::input_binary(cmd, place)?;
+    let memory = tlocal::get_memory();
+    let bytes = val.mem_size();
+    if !memory.admit(data, bytes) {
+        return Err(FlotonErr::OutOfMemory(key_orig));
+    }
+    // found once admitted, as admitting may evict the queue
+    let (_, found_map, key) = follow_key(&mut start.clone(), cmd, data)?;
+    let queue = queue_at(key_orig, found_map, key, constants::CMD_QPUSH)?;
+    if !queue.push(val) {
+        return Err(FlotonErr::QueueFull(key_orig));
+    }
+    memory.add(bytes);
+    Ok(())
+}
+
+// Pops into output, an empty queue gives nothing back unless the pop
+// blocks, then the queue to wait on is returned instead.
+fn run_cmd_qpop<'a>(place: &mut usize, cmd:&[u8], data:&'a Container<Value>, output:&mut Vec<u8>, block:bool) -> Result<Option<&'a Queue<Value>>, FlotonErr> {
+    let (key_orig, found_map, key) = follow_key(place, cmd, data)?;
+    let queue = queue_at(key_orig, found_map, key, constants::CMD_QPOP)?;
+    match queue.pop() {
+        Some(item) => {
+            tlocal::get_memory().sub(item.mem_size());
+            item.output_binary(output);
+        },
+        None if block => return Ok(Some(queue)),
+        None => Value::Nothing.output_binary(output)
+    }
+    Ok(None)
+}
+
+// The set at a key path, or why there isn't one
+fn set_at<'a>(place: &mut usize, cmd:&[u8], data:&'a Container<Value>) -> Result<&'a Set, FlotonErr> {
+    let (key_orig, found_map, key) = follow_key(place, cmd, data)?;
+    match found_map.and_then(|m| m.get_map(key)) {
+        Some(Container::Set(s)) => Ok(s),
+        Some(other) => Err(FlotonErr::OperationNoSupport(key_orig, other.vbin_type(), constants::CMD_SET_ALGEBRA as u16)),
+        None => Err(FlotonErr::ReturnNotFound(key_orig))
+    }
+}
+
+fn run_cmd_set_algebra(place: &mut usize, cmd:&[u8], data:&Container<Value>, output:&mut Vec<u8>) -> Result<(), FlotonErr> {
+    let op = *cmd.get(*place).ok_or(FlotonErr::BadRequest(*place as u64))?;
+    *place += 1;
+    // both paths are read before either can fail, and both sets stay
+    // readable until the request unpins, as finding them doesn't pin
+    let first = set_at(place, cmd, data);
+    let second = set_at(place, cmd, data);
+    let (first, second) = (first?, second?);
+    let result = match op {
+        constants::SET_UNION => first.union(second),
+        constants::SET_INTERSECT => first.intersection(second),
+        constants::SET_DIFF => first.difference(second),
+        _ => return Err(FlotonErr::UnexpectedByte(op))
+    };
+    set_output_binary(result.into_iter(), output);
+    Ok(())
+}
+
 fn run_cmd_setkv(place: &mut usize, cmd:&[u8], data:&Container<Value>) -> Result<(), FlotonErr> {
-    let mut key_ptr = unsafe { cmd.as_ptr().offset(*place as isize) as *const u64 };
-    let key_depth = unsafe { *key_ptr };
-    key_ptr = unsafe { key_ptr.offset(1) };
-    *place += 8;
+    let start = *place;
+    let key_orig = unsafe { cmd.as_ptr().add(start) as *const u64 };
+    let key_depth = keys::read_u64(cmd, place)?;
+    // the whole path and value are read before anything is made
+    let path = (0..key_depth).map(|_| keys::read_key(cmd, place)).collect::<Result<Vec<&[u8]>, FlotonErr>>()?;
+    let (harvested_key, parents) = path.split_last().ok_or(FlotonErr::BadRequest(start as u64))?;
+    let hval = Container::input_binary(cmd, place)?;
+    let memory = tlocal::get_memory();
+    if !memory.admit(data, memory::version_size(&hval) + memory::entry_size(harvested_key)) {
+        return Err(FlotonErr::OutOfMemory(key_orig));
+    }
 	let mut cur_map = data;
-	for _ in 0..(key_depth-1) {
-        let key_len = (unsafe { *key_ptr }) as usize;
-        key_ptr = unsafe { key_ptr.offset(1) };
-        *place += 8;
-		cur_map = (*cur_map).create_set_map(&cmd[*place..(*place + key_len)], tlocal::get_map_slots());
-        key_ptr = unsafe { key_ptr.offset((key_len / 8) as isize) };
-		*place += key_len;
+	for parent in parents {
+		cur_map = memory.create_map(cur_map, parent, tlocal::get_map_slots());
 	}
-	let key_len = (unsafe { *key_ptr }) as usize; 
-	*place += 8;
-    key_ptr = unsafe { key_ptr.offset(1) };
-	let harvested_key = &cmd[*place..(*place + key_len)];
-	*place += key_len;
-    key_ptr = unsafe { key_ptr.offset( (key_len / 8) as isize) };
-    match Container::input_binary(cmd, place) {
-        Ok(hval) => Ok((*cur_map).set_map(harvested_key, hval)),
-        Err(e) => Err(e)
-    }
+    memory.write(cur_map.map_slot(harvested_key), harvested_key, hval);
+    Ok(())
+}
+
+/**
+ * Where a request stopped, at a QBPOP on an empty queue. Running the
+ * request again from place retries the pop.
+ */
+#[derive(Debug)]
+pub struct Blocked<'a> {
+    pub place:usize,
+    // in ms, 0 if the pop waits without a limit
+    pub timeout:u64,
+    pub queue:&'a Queue<Value>
 }
 
 pub fn run_cmd(cmd:&[u8], data:&Container<Value>, output:&mut Vec<u8>) {
-	let mut i = 0;
+    run_cmd_at(0, false, cmd, data, output, false);
+}
+
+// Changes a setting live, giving back nothing like a SET_KV, or a Nothing outside a database
+fn run_cmd_config_set(place: &mut usize, cmd:&[u8], output:&mut Vec<u8>) -> Result<(), FlotonErr> {
+    let key_orig = unsafe { cmd.as_ptr().offset(*place as isize) as *const u64 };
+    let path = keys::read_path(cmd, place)?;
+    let db = match unsafe { tlocal::get_db().as_ref() } {
+        Some(db) => db,
+        None => {
+            Value::Nothing.output_binary(output);
+            return Ok(());
+        }
+    };
+    let arg = match (path.len(), path.first().map(|key| std::str::from_utf8(key))) {
+        (1, Some(Ok(arg))) => arg,
+        _ => return Err(FlotonErr::ConfigInvalid(key_orig))
+    };
+    match db.set_config(arg) {
+        Ok(()) => Ok(()),
+        Err(ConfigErr::ReadOnly) => Err(FlotonErr::ConfigReadOnly(key_orig)),
+        Err(ConfigErr::Invalid) => Err(FlotonErr::ConfigInvalid(key_orig))
+    }
+}
+
+// The key path of the command at place, the first one for set algebra
+fn cmd_key(cmd_type:u8, cmd:&[u8], place:usize) -> Vec<Vec<u8>> {
+    match cmd_type {
+        constants::CMD_INFO | constants::CMD_SLOWLOG_GET | constants::CMD_SLOWLOG_RESET | constants::CMD_CONFIG_GET => vec![],
+        constants::CMD_SET_ALGEBRA => keys::read_path(cmd, &mut (place + 2)).unwrap_or_default(),
+        _ => keys::read_path(cmd, &mut (place + 1)).unwrap_or_default()
+    }
+}
+
+// Writes the error a command gave, counting it against the command. Gives
+// back false if the rest of the request can't be read past it.
+fn output_err(cmd_type:u8, e:FlotonErr, output:&mut Vec<u8>) -> bool {
+    if let Some(stats) = tlocal::get_stats() {
+        stats.count_error(cmd_type);
+    }
+    e.output_binary(output);
+    !matches!(e, FlotonErr::BadRequest(_))
+}
+
+// Runs the commands from start, unless a QBPOP has to wait on an empty
+// queue. Without can_block, a QBPOP on an empty queue gives nothing back.
+// When resumed, start is a QBPOP run again after it had to wait.
+pub fn run_cmd_at<'a>(start:usize, resumed:bool, cmd:&[u8], data:&'a Container<Value>, output:&mut Vec<u8>, can_block:bool) -> Option<Blocked<'a>> {
+	let mut i = start;
+	let stats = tlocal::get_stats();
+	let slowlog = tlocal::get_slowlog();
 	loop {
-		match cmd[i] {
-			constants::CMD_STOP => return,
+		let at = i;
+		// a request cut short stops where it ends
+		let cmd_type = match cmd.get(i) {
+			Some(cmd_type) => *cmd_type,
+			None => return None
+		};
+		// a QBPOP run again once woken was counted when it first ran
+		if let (Some(stats), true) = (stats, !(resumed && i == start)) {
+			stats.count_run(cmd_type);
+		}
+		// a QBPOP that has to wait is timed once it's run again
+		let began = stats.map(|_| Instant::now());
+		match cmd_type {
+			constants::CMD_STOP => return None,
 			constants::CMD_RETURN_KV  => {
 				i += 1;
 				match run_cmd_returnkv(&mut i, cmd, data, output) {
-                    Err(e) => e.output_binary(output),
+                    Err(e) => if !output_err(cmd_type, e, output) { return None; },
                     Ok(_) => ()
                 }
 			},
 			constants::CMD_SET_KV => {
 				i += 1;
 				match run_cmd_setkv(&mut i, cmd, data) {
-                    Err(e) => { 
-                        e.output_binary(output);
-                        match e {
-                            FlotonErr::UnexpectedByte(b) => {
-                                log_error!(Input, "Unexpected set command byte: {}", b);
-                                return;
-                            },
-                            _ => ()
-                        } 
+                    Err(FlotonErr::UnexpectedByte(b)) => {
+                        log_error!(Input, "Unexpected set command byte: {}", b);
+                        output_err(cmd_type, FlotonErr::UnexpectedByte(b), output);
+                        return None;
                     },
+                    Err(e) => if !output_err(cmd_type, e, output) { return None; },
                     Ok(_) => ()
                 }
 			},
             constants::CMD_OP_ATOMIC => {
                 i += 1;
                 match run_cmd_op_atomic(&mut i, cmd, data, output) {
-                    Err(e) => e.output_binary(output),
+                    Err(e) => if !output_err(cmd_type, e, output) { return None; },
                     Ok(_) => ()
                 }
             },
             constants::CMD_OP_NORMAL => {
                 i += 1;
                 match run_cmd_op_normal(&mut i, cmd, data, output) {
-                    Err(e) => e.output_binary(output),
+                    Err(e) => if !output_err(cmd_type, e, output) { return None; },
+                    Ok(_) => ()
+                }
+            },
+            constants::CMD_RANGE => {
+                i += 1;
+                match run_cmd_range(&mut i, cmd, data, output) {
+                    Err(e) => if !output_err(cmd_type, e, output) { return None; },
+                    Ok(_) => ()
+                }
+            },
+            constants::CMD_SET_ALGEBRA => {
+                i += 1;
+                if let Err(e) = run_cmd_set_algebra(&mut i, cmd, data, output) {
+                    if !output_err(cmd_type, e, output) {
+                        return None;
+                    }
+                }
+            },
+            constants::CMD_QPUSH => {
+                i += 1;
+                match run_cmd_qpush(&mut i, cmd, data) {
+                    Err(FlotonErr::UnexpectedByte(b)) => {
+                        log_error!(Input, "Unexpected queue push byte: {}", b);
+                        output_err(cmd_type, FlotonErr::UnexpectedByte(b), output);
+                        return None;
+                    },
+                    Err(e) => if !output_err(cmd_type, e, output) { return None; },
                     Ok(_) => ()
                 }
+            },
+            constants::CMD_QPOP => {
+                i += 1;
+                if let Err(e) = run_cmd_qpop(&mut i, cmd, data, output, false) {
+                    if !output_err(cmd_type, e, output) {
+                        return None;
+                    }
+                }
+            },
+            constants::CMD_QBPOP => {
+                let place = i;
+                i += 1;
+                let popped = run_cmd_qpop(&mut i, cmd, data, output, can_block);
+                // the timeout is read even when the pop fails, to reach the next command
+                let timeout = keys::read_u64(cmd, &mut i);
+                match timeout.and_then(|timeout| popped.map(|queue| queue.map(|queue| (timeout, queue)))) {
+                    Err(e) => if !output_err(cmd_type, e, output) { return None; },
+                    Ok(Some((timeout, queue))) => return Some(Blocked{place, timeout, queue}),
+                    Ok(None) => ()
+                }
+            },
+            constants::CMD_INFO => {
+                i += 1;
+                match unsafe { tlocal::get_db().as_ref() } {
+                    Some(db) => db.info().output_binary(output),
+                    None => Value::Nothing.output_binary(output)
+                }
+            },
+            constants::CMD_SLOWLOG_GET => {
+                i += 1;
+                match (keys::read_u64(cmd, &mut i), slowlog) {
+                    (Err(e), _) => if !output_err(cmd_type, e, output) { return None; },
+                    (Ok(limit), Some(log)) => log.output(limit as usize).output_binary(output),
+                    (Ok(_), None) => Value::Nothing.output_binary(output)
+                }
+            },
+            constants::CMD_SLOWLOG_RESET => {
+                i += 1;
+                match slowlog {
+                    Some(log) => Value::UInt(log.reset() as u64).output_binary(output),
+                    None => Value::Nothing.output_binary(output)
+                }
+            },
+            constants::CMD_CONFIG_GET => {
+                i += 1;
+                match unsafe { tlocal::get_db().as_ref() } {
+                    Some(db) => {
+                        // keyed by the argument giving each setting, text can't be a value
+                        let settings = Container::<Value>::new_map(32);
+                        for arg in db.settings().to_args() {
+                            settings.set_map(arg.as_bytes(), Container::Val(Value::Nothing));
+                        }
+                        settings.output_binary(output);
+                    },
+                    None => Value::Nothing.output_binary(output)
+                }
+            },
+            constants::CMD_CONFIG_SET => {
+                i += 1;
+                if let Err(e) = run_cmd_config_set(&mut i, cmd, output) {
+                    if !output_err(cmd_type, e, output) {
+                        return None;
+                    }
+                }
             },
 			_ => {
-                log_error!(Input, "Unexpected command byte: {}", cmd[i]);
-                FlotonErr::UnexpectedByte(cmd[i]).output_binary(output);
-                return;
+                log_error!(Input, "Unexpected command byte: {}", cmd_type);
+                output_err(cmd_type, FlotonErr::UnexpectedByte(cmd_type), output);
+                return None;
             }
 		}
+		if let (Some(stats), Some(began)) = (stats, began) {
+			let took = began.elapsed();
+			stats.time_cmd(cmd_type, took);
+			if let Some(log) = slowlog.filter(|log| log.is_slow(took)) {
+				log.record(cmd_type, cmd_key(cmd_type, cmd, at), took, tlocal::get_client());
+			}
+		}
 	}
 }
 
@@ -211,6 +499,9 @@ pub fn run_cmd(cmd:&[u8], data:&Container<Value>, output:&mut Vec<u8>) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::database::Database;
+    use crate::settings::Settings;
+    use crate::memory::EvictPolicy;
 
     #[test]
     fn returnkv_works() {
@@ -293,6 +584,131 @@ mod tests {
     	assert_eq!(out_buf[1], 1);
     }
 
+    #[test]
+    fn setkv_unaligned_works() {
+    	tlocal::set_epoch();
+    	let cont = Container::<Value>::new_map(10);
+    	let mut cmd_s_buf = Vec::<u8>::new();
+    	let input_key_depth:u64 = 2;
+    	cmd_s_buf.push(constants::CMD_SET_KV);
+    	cmd_s_buf.extend_from_slice(&input_key_depth.to_le_bytes());
+    	keys::write_key(b"users", &mut cmd_s_buf);
+    	keys::write_key(b"ann", &mut cmd_s_buf);
+    	cmd_s_buf.push(constants::VBIN_BOOL);
+    	cmd_s_buf.push(1);
+    	// the zero padded key is a different key
+    	cmd_s_buf.push(constants::CMD_SET_KV);
+    	cmd_s_buf.extend_from_slice(&input_key_depth.to_le_bytes());
+    	keys::write_key(b"users", &mut cmd_s_buf);
+    	keys::write_key(b"ann\0\0\0\0\0", &mut cmd_s_buf);
+    	cmd_s_buf.push(constants::VBIN_BOOL);
+    	cmd_s_buf.push(0);
+    	cmd_s_buf.push(constants::CMD_RETURN_KV);
+    	cmd_s_buf.extend_from_slice(&input_key_depth.to_le_bytes());
+    	keys::write_key(b"users", &mut cmd_s_buf);
+    	keys::write_key(b"ann", &mut cmd_s_buf);
+    	cmd_s_buf.push(constants::CMD_RETURN_KV);
+    	cmd_s_buf.extend_from_slice(&input_key_depth.to_le_bytes());
+    	keys::write_key(b"users", &mut cmd_s_buf);
+    	keys::write_key(b"ann\0\0\0\0\0", &mut cmd_s_buf);
+    	cmd_s_buf.push(constants::CMD_STOP);
+    	let mut out_buf = Vec::<u8>::new();
+    	run_cmd(cmd_s_buf.as_slice(), &cont, &mut out_buf);
+    	assert_eq!(out_buf, vec![constants::VBIN_BOOL, 1, constants::VBIN_BOOL, 0]);
+    	assert!(cont.get_map(b"users").unwrap().get_map(b"ann").is_some());
+    }
+
+    fn range_cmd(cmd_buf:&mut Vec<u8>, flags:u8, start:&[u8], end:&[u8], limit:u64) {
+    	cmd_buf.push(constants::CMD_RANGE);
+    	cmd_buf.extend_from_slice(&1u64.to_le_bytes());
+    	keys::write_key(b"scores", cmd_buf);
+    	cmd_buf.push(flags);
+    	if flags & constants::RANGE_HAS_START != 0 {
+    		keys::write_key(start, cmd_buf);
+    	}
+    	if flags & constants::RANGE_HAS_END != 0 {
+    		keys::write_key(end, cmd_buf);
+    	}
+    	cmd_buf.extend_from_slice(&limit.to_le_bytes());
+    }
+
+    // Entries in the order they were written
+    fn range_keys(out_buf:&[u8], place:&mut usize) -> Vec<(String, u64)> {
+    	assert_eq!(out_buf[*place], constants::VBIN_OMAP_BEGIN);
+    	*place += 1;
+    	let mut found = vec![];
+    	while out_buf[*place] == constants::CMAPB_KEY {
+    		*place += 1;
+    		let key = String::from_utf8(keys::read_key(out_buf, place).unwrap().to_vec()).unwrap();
+    		found.push((key, Value::input_binary(out_buf, place).unwrap().to_uint()));
+    	}
+    	assert_eq!(out_buf[*place], constants::VBIN_OMAP_END);
+    	*place += 1;
+    	found
+    }
+
+    #[test]
+    fn range_works() {
+    	tlocal::set_epoch();
+    	let cont = Container::<Value>::new_map(10);
+    	let mut cmd_buf = Vec::<u8>::new();
+    	// an empty ordered map, then entries added into it
+    	cmd_buf.push(constants::CMD_SET_KV);
+    	cmd_buf.extend_from_slice(&1u64.to_le_bytes());
+    	keys::write_key(b"scores", &mut cmd_buf);
+    	cmd_buf.push(constants::VBIN_OMAP_BEGIN);
+    	cmd_buf.push(constants::VBIN_OMAP_END);
+    	for i in [5u64, 1, 4, 2, 3].iter() {
+    		cmd_buf.push(constants::CMD_SET_KV);
+    		cmd_buf.extend_from_slice(&2u64.to_le_bytes());
+    		keys::write_key(b"scores", &mut cmd_buf);
+    		keys::write_key(format!("user:{}", i).as_bytes(), &mut cmd_buf);
+    		cmd_buf.push(constants::VBIN_UINT);
+    		cmd_buf.extend_from_slice(&i.to_le_bytes());
+    	}
+    	let both = constants::RANGE_HAS_START | constants::RANGE_HAS_END;
+    	range_cmd(&mut cmd_buf, both | constants::RANGE_START_INCL, b"user:2", b"user:4", 0);
+    	range_cmd(&mut cmd_buf, both | constants::RANGE_END_INCL | constants::RANGE_REVERSE, b"user:2", b"user:4", 0);
+    	range_cmd(&mut cmd_buf, 0, b"", b"", 2);
+    	range_cmd(&mut cmd_buf, constants::RANGE_REVERSE, b"", b"", 2);
+    	cmd_buf.push(constants::CMD_STOP);
+    	let mut out_buf = Vec::<u8>::new();
+    	run_cmd(cmd_buf.as_slice(), &cont, &mut out_buf);
+    	let mut place = 0;
+    	assert_eq!(range_keys(&out_buf, &mut place), vec![("user:2".to_string(), 2), ("user:3".to_string(), 3)]);
+    	assert_eq!(range_keys(&out_buf, &mut place), vec![("user:4".to_string(), 4), ("user:3".to_string(), 3)]);
+    	assert_eq!(range_keys(&out_buf, &mut place), vec![("user:1".to_string(), 1), ("user:2".to_string(), 2)]);
+    	assert_eq!(range_keys(&out_buf, &mut place), vec![("user:5".to_string(), 5), ("user:4".to_string(), 4)]);
+    	assert_eq!(place, out_buf.len());
+    }
+
+    #[test]
+    fn range_not_ordered_works() {
+    	tlocal::set_epoch();
+    	let cont = Container::<Value>::new_map(10);
+    	cont.set_map(b"scores", Container::new_map(10));
+    	let mut cmd_buf = Vec::<u8>::new();
+    	range_cmd(&mut cmd_buf, constants::RANGE_HAS_START | constants::RANGE_START_INCL, b"a", b"", 0);
+    	cmd_buf.push(constants::CMD_STOP);
+    	let mut out_buf = Vec::<u8>::new();
+    	run_cmd(cmd_buf.as_slice(), &cont, &mut out_buf);
+    	assert_eq!(out_buf[0], constants::VBIN_ERROR);
+    	assert_eq!(out_buf[1], constants::ERR_OPER_NOT_SUPPORTED);
+    	assert_eq!(out_buf[2], constants::VBIN_CMAP_BEGIN);
+    	assert_eq!(&out_buf[3..5], &(constants::CMD_RANGE as u16).to_le_bytes());
+    	// the bounds were skipped even though the range failed
+    	let mut out_buf = Vec::<u8>::new();
+    	let mut cmd_buf = Vec::<u8>::new();
+    	range_cmd(&mut cmd_buf, constants::RANGE_HAS_END, b"", b"zz", 0);
+    	cmd_buf.push(constants::CMD_RETURN_KV);
+    	cmd_buf.extend_from_slice(&1u64.to_le_bytes());
+    	keys::write_key(b"missing", &mut cmd_buf);
+    	cmd_buf.push(constants::CMD_STOP);
+    	let cont = Container::<Value>::new_map(10);
+    	run_cmd(cmd_buf.as_slice(), &cont, &mut out_buf);
+    	assert_eq!(out_buf.iter().filter(|b| **b == constants::VBIN_ERROR).count(), 2);
+    }
+
     #[test]
     fn setkv_nested_works() {
     	tlocal::set_epoch();
@@ -394,4 +810,366 @@ mod tests {
         unsafe { assert_eq!(*(out_ptr.offset(26) as *const u64), 8); }
         unsafe { assert_eq!(*(out_ptr.offset(34) as *const u64), keym_n); }
     }
-}
\ No newline at end of file
+
+    fn queue_cmd(cmd_buf:&mut Vec<u8>, cmd_type:u8) {
+    	cmd_buf.push(cmd_type);
+    	cmd_buf.extend_from_slice(&1u64.to_le_bytes());
+    	keys::write_key(b"jobs", cmd_buf);
+    }
+
+    fn push_uint_val(cmd_buf:&mut Vec<u8>, val:u64) {
+    	cmd_buf.push(constants::VBIN_UINT);
+    	cmd_buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn push_uint(cmd_buf:&mut Vec<u8>, val:u64) {
+    	queue_cmd(cmd_buf, constants::CMD_QPUSH);
+    	push_uint_val(cmd_buf, val);
+    }
+
+    #[test]
+    fn queue_works() {
+    	tlocal::set_epoch();
+    	let cont = Container::<Value>::new_map(10);
+    	let mut cmd_buf = Vec::<u8>::new();
+    	cmd_buf.push(constants::CMD_SET_KV);
+    	cmd_buf.extend_from_slice(&1u64.to_le_bytes());
+    	keys::write_key(b"jobs", &mut cmd_buf);
+    	cmd_buf.push(constants::VBIN_QUEUE);
+    	cmd_buf.extend_from_slice(&2u64.to_le_bytes());
+    	push_uint(&mut cmd_buf, 1);
+    	push_uint(&mut cmd_buf, 2);
+    	push_uint(&mut cmd_buf, 3);
+    	queue_cmd(&mut cmd_buf, constants::CMD_QPOP);
+    	// QBPOP can't block here, so an empty queue gives nothing back
+    	for _ in 0..2 {
+    		queue_cmd(&mut cmd_buf, constants::CMD_QBPOP);
+    		cmd_buf.extend_from_slice(&50u64.to_le_bytes());
+    	}
+    	cmd_buf.push(constants::CMD_STOP);
+    	let mut out_buf = Vec::<u8>::new();
+    	run_cmd(cmd_buf.as_slice(), &cont, &mut out_buf);
+    	let mut place = 0;
+    	match FlotonErr::input_binary(&out_buf, &mut place) {
+    		Ok(FlotonErr::QueueFull(_)) => (),
+    		other => panic!("Expected queue full error, got {:?}", other)
+    	}
+    	assert_eq!(Value::input_binary(&out_buf, &mut place).unwrap().to_uint(), 1);
+    	assert_eq!(Value::input_binary(&out_buf, &mut place).unwrap().to_uint(), 2);
+    	assert_eq!(&out_buf[place..], &[constants::VBIN_NOTHING]);
+    }
+
+    #[test]
+    fn queue_too_long_works() {
+    	tlocal::set_epoch();
+    	let cont = Container::<Value>::new_map(10);
+    	let mut cmd_buf = Vec::<u8>::new();
+    	cmd_buf.push(constants::CMD_SET_KV);
+    	cmd_buf.extend_from_slice(&1u64.to_le_bytes());
+    	keys::write_key(b"jobs", &mut cmd_buf);
+    	cmd_buf.push(constants::VBIN_QUEUE);
+    	cmd_buf.extend_from_slice(&u64::MAX.to_le_bytes());
+    	cmd_buf.push(constants::CMD_STOP);
+    	let mut out_buf = Vec::<u8>::new();
+    	run_cmd(cmd_buf.as_slice(), &cont, &mut out_buf);
+    	match FlotonErr::input_binary(&out_buf, &mut 0) {
+    		Ok(FlotonErr::QueueTooLong(max)) => assert_eq!(max, 1 << 20),
+    		other => panic!("Expected queue too long error, got {:?}", other)
+    	}
+    	assert!(cont.get_map(b"jobs").is_none());
+    }
+
+    #[test]
+    fn bad_request_works() {
+    	tlocal::set_epoch();
+    	let cont = Container::<Value>::new_map(10);
+    	// a path of no keys, the pop after it isn't run
+    	let mut depth_zero = vec![constants::CMD_RETURN_KV];
+    	depth_zero.extend_from_slice(&0u64.to_le_bytes());
+    	queue_cmd(&mut depth_zero, constants::CMD_QPOP);
+    	depth_zero.push(constants::CMD_STOP);
+    	let mut long_key = vec![constants::CMD_RETURN_KV];
+    	long_key.extend_from_slice(&1u64.to_le_bytes());
+    	long_key.extend_from_slice(&u64::MAX.to_le_bytes());
+    	long_key.extend_from_slice(b"jobs\0\0\0\0");
+    	long_key.push(constants::CMD_STOP);
+    	// cut off before their last field
+    	let mut no_timeout = vec![];
+    	queue_cmd(&mut no_timeout, constants::CMD_QBPOP);
+    	let no_limit = vec![constants::CMD_SLOWLOG_GET, 1, 2];
+    	let mut no_range_limit = vec![constants::CMD_RANGE];
+    	no_range_limit.extend_from_slice(&1u64.to_le_bytes());
+    	keys::write_key(b"scores", &mut no_range_limit);
+    	no_range_limit.push(0);
+    	for (cmd_buf, at) in [(depth_zero, 1), (long_key, 9), (no_timeout, 25), (no_limit, 1), (no_range_limit, 26)].iter() {
+    		let mut out_buf = Vec::<u8>::new();
+    		run_cmd(cmd_buf.as_slice(), &cont, &mut out_buf);
+    		let mut place = 0;
+    		match FlotonErr::input_binary(&out_buf, &mut place) {
+    			Ok(FlotonErr::BadRequest(got)) => assert_eq!(got, *at),
+    			other => panic!("Expected bad request error, got {:?}", other)
+    		}
+    		assert_eq!(place, out_buf.len());
+    	}
+    }
+
+    #[test]
+    fn queue_blocks_works() {
+    	tlocal::set_epoch();
+    	let cont = Container::<Value>::new_map(10);
+    	cont.set_map(b"jobs", Container::Queue(Queue::new(4)));
+    	let mut cmd_buf = Vec::<u8>::new();
+    	push_uint(&mut cmd_buf, 7);
+    	queue_cmd(&mut cmd_buf, constants::CMD_QBPOP);
+    	cmd_buf.extend_from_slice(&100u64.to_le_bytes());
+    	let block_at = cmd_buf.len();
+    	queue_cmd(&mut cmd_buf, constants::CMD_QBPOP);
+    	cmd_buf.extend_from_slice(&100u64.to_le_bytes());
+    	cmd_buf.push(constants::CMD_STOP);
+    	let mut out_buf = Vec::<u8>::new();
+    	let blocked = run_cmd_at(0, false, cmd_buf.as_slice(), &cont, &mut out_buf, true).expect("Expected the second pop to block");
+    	assert_eq!(blocked.place, block_at);
+    	assert_eq!(blocked.timeout, 100);
+    	assert!(blocked.queue.push(Container::Val(Value::UInt(8))));
+    	assert!(run_cmd_at(blocked.place, true, cmd_buf.as_slice(), &cont, &mut out_buf, true).is_none());
+    	let mut place = 0;
+    	assert_eq!(Value::input_binary(&out_buf, &mut place).unwrap().to_uint(), 7);
+    	assert_eq!(Value::input_binary(&out_buf, &mut place).unwrap().to_uint(), 8);
+    	// a pop on something that isn't a queue
+    	cont.set_map(b"jobs", Container::Val(Value::UInt(0)));
+    	let mut out_buf = Vec::<u8>::new();
+    	assert!(run_cmd_at(block_at, false, cmd_buf.as_slice(), &cont, &mut out_buf, true).is_none());
+    	assert_eq!(out_buf[1], constants::ERR_OPER_NOT_SUPPORTED);
+    	assert_eq!(out_buf[2], constants::VBIN_UINT);
+    }
+
+    #[test]
+    fn resumed_pop_counts_once() {
+    	tlocal::set_epoch();
+    	let mut db = Database::new_from_settings(Settings::new());
+    	tlocal::set_db(&mut db);
+    	let cont = Container::<Value>::new_map(10);
+    	cont.set_map(b"jobs", Container::Queue(Queue::new(4)));
+    	let mut cmd_buf = Vec::<u8>::new();
+    	queue_cmd(&mut cmd_buf, constants::CMD_QBPOP);
+    	cmd_buf.extend_from_slice(&0u64.to_le_bytes());
+    	cmd_buf.push(constants::CMD_STOP);
+    	let mut out_buf = Vec::<u8>::new();
+    	let blocked = run_cmd_at(0, false, cmd_buf.as_slice(), &cont, &mut out_buf, true).expect("Expected the pop to block");
+    	assert_eq!(blocked.place, 0);
+    	// woken with nothing to pop, then again once there is
+    	assert!(run_cmd_at(0, true, cmd_buf.as_slice(), &cont, &mut out_buf, true).is_some());
+    	assert!(blocked.queue.push(Container::Val(Value::UInt(8))));
+    	assert!(run_cmd_at(0, true, cmd_buf.as_slice(), &cont, &mut out_buf, true).is_none());
+    	tlocal::set_db(ptr::null_mut());
+    	assert_eq!(db.cmd_stats().runs(constants::CMD_QBPOP), 1);
+    	let mut place = 0;
+    	assert_eq!(Value::input_binary(&out_buf, &mut place).unwrap().to_uint(), 8);
+    }
+
+    fn set_members(out_buf:&[u8], place:&mut usize) -> Vec<String> {
+    	assert_eq!(out_buf[*place], constants::VBIN_SET_BEGIN);
+    	*place += 1;
+    	let mut found = vec![];
+    	while out_buf[*place] != constants::VBIN_SET_END {
+    		found.push(String::from_utf8(keys::read_key(out_buf, place).unwrap().to_vec()).unwrap());
+    	}
+    	*place += 1;
+    	found.sort();
+    	found
+    }
+
+    #[test]
+    fn set_algebra_works() {
+    	tlocal::set_epoch();
+    	let cont = Container::<Value>::new_map(10);
+    	let mut cmd_buf = Vec::<u8>::new();
+    	for (name, members) in [("a", ["x", "y"]), ("b", ["y", "z"])].iter() {
+    		cmd_buf.push(constants::CMD_SET_KV);
+    		cmd_buf.extend_from_slice(&2u64.to_le_bytes());
+    		keys::write_key(b"tags", &mut cmd_buf);
+    		keys::write_key(name.as_bytes(), &mut cmd_buf);
+    		cmd_buf.push(constants::VBIN_SET_BEGIN);
+    		for m in members.iter() {
+    			keys::write_key(m.as_bytes(), &mut cmd_buf);
+    		}
+    		cmd_buf.push(constants::VBIN_SET_END);
+    	}
+    	for op in [constants::SET_UNION, constants::SET_INTERSECT, constants::SET_DIFF].iter() {
+    		cmd_buf.push(constants::CMD_SET_ALGEBRA);
+    		cmd_buf.push(*op);
+    		for name in [b"a", b"b"].iter() {
+    			cmd_buf.extend_from_slice(&2u64.to_le_bytes());
+    			keys::write_key(b"tags", &mut cmd_buf);
+    			keys::write_key(*name, &mut cmd_buf);
+    		}
+    	}
+    	// a missing second set
+    	cmd_buf.push(constants::CMD_SET_ALGEBRA);
+    	cmd_buf.push(constants::SET_UNION);
+    	cmd_buf.extend_from_slice(&1u64.to_le_bytes());
+    	keys::write_key(b"tags", &mut cmd_buf);
+    	cmd_buf.extend_from_slice(&1u64.to_le_bytes());
+    	keys::write_key(b"missing", &mut cmd_buf);
+    	cmd_buf.push(constants::CMD_STOP);
+    	let mut out_buf = Vec::<u8>::new();
+    	run_cmd(cmd_buf.as_slice(), &cont, &mut out_buf);
+    	let mut place = 0;
+    	assert_eq!(set_members(&out_buf, &mut place), vec!["x", "y", "z"]);
+    	assert_eq!(set_members(&out_buf, &mut place), vec!["y"]);
+    	assert_eq!(set_members(&out_buf, &mut place), vec!["x"]);
+    	// the first path is a map, which fails before the missing one
+    	assert_eq!(out_buf[place], constants::VBIN_ERROR);
+    	assert_eq!(out_buf[place + 1], constants::ERR_OPER_NOT_SUPPORTED);
+    	assert_eq!(out_buf[place + 2], constants::VBIN_CMAP_BEGIN);
+    }
+
+    #[test]
+    fn qpush_evicts_queue_works() {
+    	tlocal::set_epoch();
+    	let mut settings = Settings::new();
+    	settings.max_memory = 12 * 1024;
+    	settings.evict_policy = EvictPolicy::Lru;
+    	let mut db = Database::new_from_settings(settings);
+    	tlocal::set_db(&mut db);
+    	let cont = Container::<Value>::new_map(10);
+    	// the queue takes 8KiB, so pushing 4KiB has to evict it
+    	let mut cmd_buf = Vec::<u8>::new();
+    	cmd_buf.push(constants::CMD_SET_KV);
+    	cmd_buf.extend_from_slice(&1u64.to_le_bytes());
+    	keys::write_key(b"jobs", &mut cmd_buf);
+    	cmd_buf.push(constants::VBIN_QUEUE);
+    	cmd_buf.extend_from_slice(&1024u64.to_le_bytes());
+    	queue_cmd(&mut cmd_buf, constants::CMD_QPUSH);
+    	cmd_buf.push(constants::VBIN_BLOOM);
+    	cmd_buf.push(3);
+    	cmd_buf.extend_from_slice(&512u64.to_le_bytes());
+    	cmd_buf.extend_from_slice(&[0; 512 * 8]);
+    	cmd_buf.push(constants::CMD_STOP);
+    	let mut out_buf = Vec::<u8>::new();
+    	run_cmd(cmd_buf.as_slice(), &cont, &mut out_buf);
+    	tlocal::set_db(ptr::null_mut());
+    	let mut place = 0;
+    	match FlotonErr::input_binary(&out_buf, &mut place) {
+    		Ok(FlotonErr::ReturnNotFound(_)) => (),
+    		other => panic!("Expected the evicted queue to be gone, got {:?}", other)
+    	}
+    	assert_eq!(place, out_buf.len());
+    	assert!(cont.get_map(b"jobs").is_none());
+    }
+
+    #[test]
+    fn setkv_out_of_memory_works() {
+    	tlocal::set_epoch();
+    	let mut settings = Settings::new();
+    	settings.max_memory = 64 * 1024;
+    	let mut db = Database::new_from_settings(settings);
+    	tlocal::set_db(&mut db);
+    	let cont = Container::<Value>::new_map(10);
+    	let mut cmd_buf = Vec::<u8>::new();
+    	cmd_buf.push(constants::CMD_SET_KV);
+    	cmd_buf.extend_from_slice(&1u64.to_le_bytes());
+    	keys::write_key(b"small", &mut cmd_buf);
+    	push_uint_val(&mut cmd_buf, 1);
+    	// a 128KiB bloom filter can't fit, and there is nothing to evict for it
+    	cmd_buf.push(constants::CMD_SET_KV);
+    	cmd_buf.extend_from_slice(&2u64.to_le_bytes());
+    	keys::write_key(b"filters", &mut cmd_buf);
+    	keys::write_key(b"big", &mut cmd_buf);
+    	cmd_buf.push(constants::VBIN_BLOOM);
+    	cmd_buf.push(3);
+    	cmd_buf.extend_from_slice(&16384u64.to_le_bytes());
+    	cmd_buf.extend_from_slice(&[0; 16384 * 8]);
+    	cmd_buf.push(constants::CMD_STOP);
+    	let mut out_buf = Vec::<u8>::new();
+    	run_cmd(cmd_buf.as_slice(), &cont, &mut out_buf);
+    	tlocal::set_db(ptr::null_mut());
+    	let mut place = 0;
+    	match FlotonErr::input_binary(&out_buf, &mut place) {
+    		Ok(FlotonErr::OutOfMemory(key)) => assert_eq!(unsafe { key.read_unaligned() }, 2),
+    		other => panic!("Expected out of memory error, got {:?}", other)
+    	}
+    	assert_eq!(place, out_buf.len());
+    	assert!(cont.get_map(b"small").is_some());
+    	// nothing along the path is made for a write turned away
+    	assert!(cont.get_map(b"filters").is_none());
+    	assert_eq!(db.memory().live(), memory::entry_size(b"small") + memory::version_size(&Container::Val(Value::UInt(1))));
+    }
+
+    #[test]
+    fn slowlog_works() {
+    	tlocal::set_epoch();
+    	let mut settings = Settings::new();
+    	settings.slowlog_threshold_us = 1;
+    	let mut db = Database::new_from_settings(settings);
+    	let client = "127.0.0.1:4000".parse().unwrap();
+    	tlocal::set_db(&mut db);
+    	tlocal::set_client(Some(client));
+    	let cont = Container::<Value>::new_map(10);
+    	// copying in a 128KiB bloom filter takes well over a microsecond
+    	let mut cmd_buf = Vec::<u8>::new();
+    	cmd_buf.push(constants::CMD_SET_KV);
+    	cmd_buf.extend_from_slice(&2u64.to_le_bytes());
+    	keys::write_key(b"filters", &mut cmd_buf);
+    	keys::write_key(b"big", &mut cmd_buf);
+    	cmd_buf.push(constants::VBIN_BLOOM);
+    	cmd_buf.push(3);
+    	cmd_buf.extend_from_slice(&16384u64.to_le_bytes());
+    	cmd_buf.extend_from_slice(&[0; 16384 * 8]);
+    	cmd_buf.push(constants::CMD_STOP);
+    	let mut out_buf = Vec::<u8>::new();
+    	run_cmd(cmd_buf.as_slice(), &cont, &mut out_buf);
+    	let entries = db.slowlog().entries(0);
+    	assert_eq!(entries.len(), 1);
+    	assert_eq!(entries[0].cmd, constants::CMD_SET_KV);
+    	assert_eq!(entries[0].key_text(), "filters/big");
+    	assert_eq!(entries[0].client, Some(client));
+    	assert!(entries[0].took_us > 1);
+    	let mut cmd_buf = vec![constants::CMD_SLOWLOG_GET];
+    	cmd_buf.extend_from_slice(&0u64.to_le_bytes());
+    	cmd_buf.push(constants::CMD_SLOWLOG_RESET);
+    	cmd_buf.push(constants::CMD_STOP);
+    	let mut out_buf = Vec::<u8>::new();
+    	run_cmd(cmd_buf.as_slice(), &cont, &mut out_buf);
+    	tlocal::set_client(None);
+    	tlocal::set_db(ptr::null_mut());
+    	assert_eq!(out_buf[0], constants::VBIN_OMAP_BEGIN);
+    	// the entry's id, then its fields
+    	assert_eq!(out_buf[1], constants::CMAPB_KEY);
+    	let end = out_buf.len() - 9;
+    	assert_eq!(out_buf[end], constants::VBIN_UINT);
+    	// the GET may have been slow enough to log as well
+    	assert!(u64::from_le_bytes(out_buf[(end + 1)..].try_into().unwrap()) >= 1);
+    	assert_eq!(db.slowlog().entries(0).iter().filter(|e| e.cmd == constants::CMD_SET_KV).count(), 0);
+    }
+
+    #[test]
+    fn config_works() {
+    	let mut db = Database::new_from_settings(Settings::new());
+    	tlocal::set_db(&mut db);
+    	let cont = Container::<Value>::new_map(10);
+    	let mut cmd_buf = Vec::<u8>::new();
+    	for arg in ["--db-map-slots=64", "--port=9000", "--nope=1"] {
+    		cmd_buf.push(constants::CMD_CONFIG_SET);
+    		cmd_buf.extend_from_slice(&1u64.to_le_bytes());
+    		keys::write_key(arg.as_bytes(), &mut cmd_buf);
+    	}
+    	cmd_buf.push(constants::CMD_CONFIG_GET);
+    	cmd_buf.push(constants::CMD_STOP);
+    	let mut out_buf = Vec::<u8>::new();
+    	run_cmd(cmd_buf.as_slice(), &cont, &mut out_buf);
+    	tlocal::set_db(ptr::null_mut());
+    	assert_eq!(db.get_map_slots(), 64);
+    	assert_eq!(db.settings().db_port, 8080);
+    	let mut place = 0;
+    	match FlotonErr::input_binary(&out_buf, &mut place) {
+    		Ok(FlotonErr::ConfigReadOnly(key)) => assert_eq!(unsafe { key.read_unaligned() }, 1),
+    		other => panic!("Expected read only error, got {:?}", other)
+    	}
+    	assert!(matches!(FlotonErr::input_binary(&out_buf, &mut place), Ok(FlotonErr::ConfigInvalid(_))));
+    	let settings = Container::<Value>::input_binary(&out_buf, &mut place).unwrap();
+    	assert!(settings.get_map(b"--db-map-slots=64").is_some());
+    	assert!(settings.get_map(b"--port=8080").is_some());
+    	assert_eq!(place, out_buf.len());
+    }
+}
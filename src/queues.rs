@@ -0,0 +1,165 @@
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::mem;
+use crate::containers::Container;
+use crate::threading::MpMc;
+use crate::traits::Wake;
+
+/**
+ * A bounded first in first out queue of containers, any number of
+ * threads may push and pop. Anything waiting on the queue to be given
+ * an item is woken by the next push. Every waiter is woken, and those
+ * that find the queue empty again wait again, so a waiter that gave up
+ * can't swallow a wake meant for another.
+ */
+#[derive(Debug)]
+pub struct Queue<T> {
+	items:MpMc<Container<T>>,
+	waiters:Mutex<Vec<Box<dyn Wake>>>
+}
+
+impl<T> Drop for Queue<T> {
+	fn drop(&mut self) {
+		while let Some(item) = self.items.pop() {
+			free!(item);
+		}
+		// waiters look again, finding whatever replaced the queue
+		for waiter in self.waiters.get_mut().unwrap().drain(..) {
+			waiter.wake();
+		}
+	}
+}
+
+impl<T: Debug> Queue<T> {
+	// The capacity is rounded up to a power of two
+	pub fn new(size:usize) -> Queue<T> {
+		Queue{items:MpMc::new(size), waiters:Mutex::new(vec![])}
+	}
+
+	pub fn capacity(&self) -> usize {
+		self.items.size
+	}
+
+	pub fn len(&self) -> usize {
+		self.items.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.items.is_empty()
+	}
+
+	// Returns false, dropping val, if the queue is full
+	pub fn push(&self, val:Container<T>) -> bool {
+		let item = alloc!(val);
+		if !self.items.push(item) {
+			free!(item);
+			return false;
+		}
+		self.wake_all();
+		true
+	}
+
+	pub fn pop(&self) -> Option<Box<Container<T>>> {
+		self.items.pop().map(|item| unsafe { Box::from_raw(item) })
+	}
+
+	// Wakes waiter on the next push. A waiter added while items are
+	// queued is woken straight away, as the push may have been missed.
+	pub fn wait(&self, waiter:Box<dyn Wake>) {
+		self.waiters.lock().unwrap().push(waiter);
+		if !self.is_empty() {
+			self.wake_all();
+		}
+	}
+
+	pub fn waiting(&self) -> usize {
+		self.waiters.lock().unwrap().len()
+	}
+
+	fn wake_all(&self) {
+		let waiters = mem::take(&mut *self.waiters.lock().unwrap());
+		for waiter in waiters {
+			waiter.wake();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[derive(Debug, Copy, Clone)]
+    struct TestType(u32);
+
+    #[derive(Debug)]
+    struct TestWake(Arc<AtomicUsize>);
+
+    impl Wake for TestWake {
+    	fn wake(&self) {
+    		self.0.fetch_add(1, Ordering::SeqCst);
+    	}
+    }
+
+    #[test]
+    fn push_pop_works() {
+    	let queue = Queue::<TestType>::new(3);
+    	assert_eq!(queue.capacity(), 4);
+    	assert!(queue.pop().is_none());
+    	for i in 0..4 {
+    		assert!(queue.push(Container::Val(TestType(i))));
+    	}
+    	assert!(!queue.push(Container::Val(TestType(4))));
+    	assert_eq!(queue.len(), 4);
+    	for i in 0..4 {
+    		assert_eq!(queue.pop().unwrap().value().unwrap().0, i);
+    	}
+    	assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn wait_wakes_works() {
+    	let queue = Queue::<TestType>::new(4);
+    	let woken = Arc::new(AtomicUsize::new(0));
+    	queue.wait(Box::new(TestWake(woken.clone())));
+    	queue.wait(Box::new(TestWake(woken.clone())));
+    	assert_eq!(queue.waiting(), 2);
+    	assert!(queue.push(Container::Val(TestType(1))));
+    	assert_eq!(woken.load(Ordering::SeqCst), 2);
+    	assert_eq!(queue.waiting(), 0);
+    	// an item is already there, so there's nothing to wait for
+    	queue.wait(Box::new(TestWake(woken.clone())));
+    	assert_eq!(woken.load(Ordering::SeqCst), 3);
+    	assert_eq!(queue.waiting(), 0);
+    }
+
+    #[test]
+    fn mt_push_pop_works() {
+    	let queue = Arc::new(Queue::<TestType>::new(64));
+    	let mut handles = vec![];
+    	for t in 0..4 {
+    		let tqueue = queue.clone();
+    		handles.push(thread::spawn(move || {
+    			let mut popped = vec![];
+    			for i in 0..1000 {
+    				while !tqueue.push(Container::Val(TestType(t * 1000 + i))) {
+    					thread::yield_now();
+    				}
+    				if let Some(item) = tqueue.pop() {
+    					popped.push(item.value().unwrap().0);
+    				}
+    			}
+    			popped
+    		}));
+    	}
+    	let mut seen:Vec<u32> = handles.into_iter().flat_map(|h| h.join().unwrap()).collect();
+    	while let Some(item) = queue.pop() {
+    		seen.push(item.value().unwrap().0);
+    	}
+    	seen.sort();
+    	seen.dedup();
+    	assert_eq!(seen.len(), 4000);
+    }
+}
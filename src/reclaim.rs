@@ -0,0 +1,315 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use std::{cmp, mem};
+use crate::threading::Switch;
+use crate::slab;
+use crate::traits::*;
+use crate::logging::*;
+
+/**
+ * Epoch based reclamation for anything unlinked while other threads may
+ * still be reading it. There is one epoch for the whole process. A thread
+ * pins itself at the current epoch while it holds references into shared
+ * data, and whatever it retires is stamped with the epoch it was unlinked
+ * in. The epoch only advances once every pinned thread has seen it, so
+ * two epochs after a stamp nothing can still reach what was retired.
+ * Each thread keeps its own bag of retired things, handed over to a
+ * global one when the thread exits, and a Collector frees from all of
+ * them, so nothing waits on the thread that retired it.
+ */
+const UNPINNED:u64 = u64::MAX;
+
+static EPOCH:AtomicU64 = AtomicU64::new(0);
+// Every live thread that has pinned or retired
+static THREADS:Mutex<Vec<Arc<Participant>>> = Mutex::new(Vec::new());
+// Retired by threads that have since exited
+static ORPHANS:Mutex<Vec<Garbage>> = Mutex::new(Vec::new());
+// Approximate bytes retired and not yet freed, for those retired with a size
+static PENDING_BYTES:AtomicUsize = AtomicUsize::new(0);
+// The fewest a thread retires before it frees from its own bag, as
+// --thread-free-limit until a database sets it
+static FREE_LIM:AtomicUsize = AtomicUsize::new(5);
+
+#[derive(Debug)]
+struct Garbage {
+	ptr:*mut u8,
+	free:fn(*mut u8),
+	epoch:u64,
+	// as counted towards pending_bytes()
+	bytes:usize
+}
+
+// Garbage is only freed once no thread can reach it, by whichever thread
+unsafe impl Send for Garbage {}
+
+fn free_boxed<T>(ptr:*mut u8) {
+	free!(ptr as *mut T);
+}
+
+fn free_slab<T>(ptr:*mut u8) {
+	unsafe { slab::free(ptr as *mut T); }
+}
+
+#[derive(Debug)]
+struct Participant {
+	// The epoch this thread is pinned at, UNPINNED if it isn't
+	epoch:AtomicU64,
+	bag:Mutex<Vec<Garbage>>,
+	// the bag size at which this thread next tries to free from it
+	collect_at:AtomicUsize
+}
+
+#[derive(Debug)]
+struct Local(Arc<Participant>);
+
+impl NewType for Local {
+	fn new() -> Self {
+		let made = Arc::new(Participant{epoch:AtomicU64::new(UNPINNED), bag:Mutex::new(vec![]), collect_at:AtomicUsize::new(0)});
+		THREADS.lock().unwrap().push(made.clone());
+		Local(made)
+	}
+}
+
+impl Drop for Local {
+	// An exited thread holds nothing back, and leaves what it retired behind
+	fn drop(&mut self) {
+		THREADS.lock().unwrap().retain(|p| !Arc::ptr_eq(p, &self.0));
+		let left = mem::take(&mut *self.0.bag.lock().unwrap());
+		ORPHANS.lock().unwrap().extend(left);
+	}
+}
+
+thread_local!(static LOCAL:Local = Local::new());
+
+pub fn epoch() -> u64 {
+	EPOCH.load(Ordering::SeqCst)
+}
+
+// References got after this stay valid until the next pin() or unpin()
+pub fn pin() {
+	LOCAL.with(|local| local.0.epoch.store(EPOCH.load(Ordering::SeqCst), Ordering::SeqCst));
+}
+
+pub fn unpin() {
+	LOCAL.with(|local| local.0.epoch.store(UNPINNED, Ordering::SeqCst));
+}
+
+// As unpin, for a thread that is exiting and may have dropped its state
+pub fn try_unpin() {
+	let _ = LOCAL.try_with(|local| local.0.epoch.store(UNPINNED, Ordering::SeqCst));
+}
+
+pub fn free_lim() -> usize {
+	FREE_LIM.load(Ordering::Relaxed)
+}
+
+pub fn set_free_lim(lim:usize) {
+	FREE_LIM.store(lim, Ordering::Relaxed);
+}
+
+// Frees ptr, a Box made by alloc!, once no pinned thread can reach it.
+// It has to be unlinked from everything first.
+pub fn retire<T>(ptr:*mut T) {
+	retire_with(ptr as *mut u8, free_boxed::<T>, 0);
+}
+
+// As retire, for ptr made by slab::alloc
+pub fn retire_slab<T>(ptr:*mut T) {
+	retire_with(ptr as *mut u8, free_slab::<T>, 0);
+}
+
+// As retire_slab, counting bytes as pending until it is freed
+pub fn retire_slab_sized<T>(ptr:*mut T, bytes:usize) {
+	retire_with(ptr as *mut u8, free_slab::<T>, bytes);
+}
+
+fn retire_with(ptr:*mut u8, free:fn(*mut u8), bytes:usize) {
+	PENDING_BYTES.fetch_add(bytes, Ordering::Relaxed);
+	let mut garbage = Some(Garbage{ptr, free, epoch:EPOCH.load(Ordering::SeqCst), bytes});
+	let full = LOCAL.try_with(|local| {
+		let mut bag = local.0.bag.lock().unwrap();
+		bag.extend(garbage.take());
+		bag.len() >= local.0.collect_at.load(Ordering::Relaxed)
+	}).unwrap_or(false);
+	// retired while the thread exits, after its bag was handed over
+	if let Some(left) = garbage {
+		ORPHANS.lock().unwrap().push(left);
+	}
+	// keeps up with heavy writes between collections
+	if full {
+		let epoch = try_advance();
+		free_all(LOCAL.with(|local| {
+			let freeable = take_freeable(&local.0.bag, epoch);
+			// while a pinned thread holds the epoch back, the bag isn't
+			// scanned again until it has doubled
+			let left = local.0.bag.lock().unwrap().len();
+			local.0.collect_at.store(cmp::max(left * 2, FREE_LIM.load(Ordering::Relaxed)), Ordering::Relaxed);
+			freeable
+		}));
+	}
+}
+
+// Moves the epoch on if every pinned thread has seen it, returning the epoch
+pub fn try_advance() -> u64 {
+	let current = EPOCH.load(Ordering::SeqCst);
+	let all_seen = THREADS.lock().unwrap().iter().all(|p| {
+		let pinned = p.epoch.load(Ordering::SeqCst);
+		pinned == UNPINNED || pinned == current
+	});
+	if !all_seen {
+		return current;
+	}
+	match EPOCH.compare_exchange(current, current + 1, Ordering::SeqCst, Ordering::SeqCst) {
+		Ok(_) => current + 1,
+		Err(now) => now
+	}
+}
+
+fn take_freeable(bag:&Mutex<Vec<Garbage>>, epoch:u64) -> Vec<Garbage> {
+	let mut bag = bag.lock().unwrap();
+	let (freeable, kept) = mem::take(&mut *bag).into_iter().partition(|g| g.epoch + 2 <= epoch);
+	*bag = kept;
+	freeable
+}
+
+// Called with no bag locked, as freeing something may retire more
+fn free_all(freeable:Vec<Garbage>) -> u32 {
+	let freed = freeable.len() as u32;
+	for garbage in freeable {
+		(garbage.free)(garbage.ptr);
+		PENDING_BYTES.fetch_sub(garbage.bytes, Ordering::Relaxed);
+	}
+	freed
+}
+
+// How many retired things are waiting to be freed, across every thread
+pub fn pending() -> usize {
+	let held:usize = THREADS.lock().unwrap().iter().map(|p| p.bag.lock().unwrap().len()).sum();
+	held + ORPHANS.lock().unwrap().len()
+}
+
+pub fn pending_bytes() -> usize {
+	PENDING_BYTES.load(Ordering::Relaxed)
+}
+
+// Frees everything no pinned thread can reach, returning how many were freed
+pub fn collect() -> u32 {
+	let epoch = try_advance();
+	let mut freeable = take_freeable(&ORPHANS, epoch);
+	for p in THREADS.lock().unwrap().iter() {
+		freeable.extend(take_freeable(&p.bag, epoch));
+	}
+	free_all(freeable)
+}
+
+/**
+ * A background thread that runs collect() every interval, until stopped
+ */
+#[derive(Debug)]
+pub struct Collector {
+	handle:Option<JoinHandle<()>>,
+	switch:Switch
+}
+
+impl Collector {
+	pub fn start(interval:Duration) -> Collector {
+		let switch = Switch::new();
+		switch.set(true);
+		let tswitch = switch.clone();
+		let handle = thread::spawn(move || {
+			while tswitch.get() {
+				thread::park_timeout(interval);
+				let freed = collect();
+				if freed > 0 {
+					log_debug!(Collector, "Freed {} retired, {} still pending", freed, pending());
+				}
+			}
+		});
+		Collector{handle:Some(handle), switch}
+	}
+
+	pub fn stop(&mut self) {
+		self.switch.set(false);
+		if let Some(handle) = self.handle.take() {
+			handle.thread().unpark();
+			handle.join().unwrap();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Instant;
+
+    #[derive(Debug)]
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+    	fn drop(&mut self) {
+    		self.0.fetch_add(1, Ordering::SeqCst);
+    	}
+    }
+
+    // Other tests pin as well, so collect until they move on
+    fn collect_until(freed:&AtomicUsize, count:usize) {
+    	let start = Instant::now();
+    	while freed.load(Ordering::SeqCst) < count {
+    		assert!(start.elapsed() < Duration::from_secs(5), "Retired values were never freed");
+    		collect();
+    		thread::yield_now();
+    	}
+    }
+
+    #[test]
+    fn pin_holds_back_works() {
+    	let freed = Arc::new(AtomicUsize::new(0));
+    	let (pinned_tx, pinned_rx) = mpsc::channel();
+    	let (done_tx, done_rx) = mpsc::channel::<()>();
+    	let reader = thread::spawn(move || {
+    		pin();
+    		pinned_tx.send(()).unwrap();
+    		done_rx.recv().unwrap();
+    		unpin();
+    	});
+    	pinned_rx.recv().unwrap();
+    	retire(alloc!(Counted(freed.clone())));
+    	for _ in 0..4 {
+    		collect();
+    	}
+    	// the reader never saw the epoch move, so it can only move once
+    	assert_eq!(freed.load(Ordering::SeqCst), 0);
+    	done_tx.send(()).unwrap();
+    	reader.join().unwrap();
+    	collect_until(&freed, 1);
+    }
+
+    #[test]
+    fn exited_thread_works() {
+    	let freed = Arc::new(AtomicUsize::new(0));
+    	let tfreed = freed.clone();
+    	thread::spawn(move || {
+    		pin();
+    		retire(alloc!(Counted(tfreed.clone())));
+    		retire(alloc!(Counted(tfreed)));
+    	}).join().unwrap();
+    	// handed over when the thread exited, though it never unpinned
+    	collect_until(&freed, 2);
+    }
+
+    #[test]
+    fn collector_works() {
+    	let freed = Arc::new(AtomicUsize::new(0));
+    	let mut collector = Collector::start(Duration::from_millis(1));
+    	retire(alloc!(Counted(freed.clone())));
+    	let start = Instant::now();
+    	while freed.load(Ordering::SeqCst) < 1 {
+    		assert!(start.elapsed() < Duration::from_secs(5), "The collector never freed it");
+    		thread::sleep(Duration::from_millis(1));
+    	}
+    	collector.stop();
+    }
+}
@@ -2,11 +2,11 @@ use std::net::{TcpStream, Shutdown};
 use std::sync::atomic::Ordering;
 use std::io::prelude::*;
 use std::convert::TryInto;
-use std::thread;
-use std::time::Duration;
 use std::io;
 use crate::traits::*;
-use crate::ports::next_local_addr;
+use crate::tcp::TcpServerStream;
+use crate::responses::Response;
+use crate::errors::FlotonErr;
 use crate::logging::*;
 
 #[derive(Debug, Clone)]
@@ -27,60 +27,82 @@ impl NewType for Request {
 }
 
 impl Request {
-
-	pub fn parse(stream:&mut TcpStream) -> Option<Request> {
-		let mut req = Request::new();
-		let mut head_buf:[u8;8] = [0;8];
-		loop {
-			match stream.read_exact(&mut head_buf) {
-	    		Ok(_) => break,
-	    		Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::yield_now(),
-				Err(e) => {
-					log_error!(Connections, "Failed to read request header with err: {}", e);
-					return None;
-				}
-			}
+	// Takes one complete request off the front of the buffer, if it has
+	// fully arrived. Partial requests are left in place. The size is checked
+	// as soon as the header arrives, before anything is allocated for it.
+	pub fn from_buf(buf:&mut Vec<u8>, max_size:usize) -> Result<Option<Request>, FlotonErr> {
+		if buf.len() < 8 {
+			return Ok(None);
+		}
+		let total_size = u64::from_le_bytes(buf[0..8].try_into().unwrap());
+		if total_size > max_size as u64 {
+			return Err(FlotonErr::RequestTooLarge(max_size as u64));
 		}
-		req.header.total_size = u64::from_le_bytes(head_buf);
-		req.body.resize(req.header.total_size as usize, 0);
-		loop {
-			match stream.read_exact(req.body.as_mut_slice()) {
-	    		Ok(_) => break,
-	    		Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::yield_now(),
-				Err(e) => {
-					log_error!(Connections, "Failed to read request body with err: {}", e);
-					return None;
-				}
+		if ((buf.len() - 8) as u64) < total_size {
+			return Ok(None);
+		}
+		let end = 8 + total_size as usize;
+		let mut req = Request::new();
+		req.header.total_size = total_size;
+		req.body.extend_from_slice(&buf[8..end]);
+		buf.drain(0..end);
+		Ok(Some(req))
+	}
+
+	// A request over the size limit gets an error back, and ends the connection
+	pub fn parse<T>(stream:&mut TcpServerStream<T>) -> Option<Request> {
+		let max_size = stream.limits().max_request_size;
+		match Request::from_buf(stream.read_buf(), max_size) {
+			Ok(req) => req,
+			Err(err) => {
+				log_warn!(Requests, "Closing connection from {:?}, {:?}", stream.0.peer_addr(), err);
+				Response::error(err).to_server_stream(stream);
+				stream.finish();
+				None
 			}
 		}
-		Some(req)
 	}
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::net::{SocketAddr, TcpListener};
 
     #[test]
     fn request_parse_works() {
-		let addrs = [
-		    next_local_addr()
-		];
-		let listener = TcpListener::bind(&addrs[..]).unwrap();
-		let mut client = TcpStream::connect(&addrs[..]).unwrap();
 		let sizer:u64 = 4;
 		let bytes:[u8;4] = [55, 22, 33, 44];
-		client.write(&sizer.to_le_bytes()).unwrap();
-		client.write(&bytes).unwrap();
-		let (mut received, _addr) = listener.accept().unwrap();
-		println!("Got req from {:?}", _addr);
-		let req = Request::parse(&mut received).unwrap();
+		let mut buf = Vec::<u8>::new();
+		buf.extend_from_slice(&sizer.to_le_bytes()[0..5]);
+		assert!(Request::from_buf(&mut buf, 64).unwrap().is_none());
+		buf.extend_from_slice(&sizer.to_le_bytes()[5..8]);
+		buf.extend_from_slice(&bytes[0..2]);
+		assert!(Request::from_buf(&mut buf, 64).unwrap().is_none());
+		assert_eq!(buf.len(), 10);
+		buf.extend_from_slice(&bytes[2..4]);
+		// start of the next request
+		buf.push(9);
+		let req = Request::from_buf(&mut buf, 64).unwrap().unwrap();
 		assert_eq!(req.header.total_size, 4);
 		assert_eq!(req.body.len(), 4);
 		assert_eq!(req.body[0], bytes[0]);
 		assert_eq!(req.body[1], bytes[1]);
 		assert_eq!(req.body[2], bytes[2]);
 		assert_eq!(req.body[3], bytes[3]);
+		assert_eq!(buf.len(), 1);
+		assert_eq!(buf[0], 9);
+    }
+
+    #[test]
+    fn request_too_large_works() {
+		let mut buf = Vec::<u8>::new();
+		buf.extend_from_slice(&(1u64 << 60).to_le_bytes());
+		match Request::from_buf(&mut buf, 1024) {
+			Err(FlotonErr::RequestTooLarge(max)) => assert_eq!(max, 1024),
+			other => panic!("Expected request too large error, got {:?}", other)
+		}
+		buf.clear();
+		buf.extend_from_slice(&1024u64.to_le_bytes());
+		assert!(Request::from_buf(&mut buf, 1024).unwrap().is_none());
     }
-}
\ No newline at end of file
+}
@@ -4,6 +4,9 @@ use std::io::prelude::*;
 use std::io;
 use std::thread;
 use crate::ports;
+use crate::tcp::TcpServerStream;
+use crate::errors::FlotonErr;
+use crate::traits::*;
 use crate::logging::*;
 
 #[derive(Debug)]
@@ -23,30 +26,37 @@ impl Response {
 		Response{header:ResponseHeader{total_size:hsize}, body:output}
 	}
 
+	// A response carrying only an error, for failures outside of commands
+	pub fn error(err:FlotonErr) -> Response {
+		let mut body = vec![];
+		err.output_binary(&mut body);
+		Response::from_vec(body)
+	}
+
 	pub fn size(&self) -> u64 {
 		self.header.total_size
 	}
 
+	// Writes to a blocking stream
 	pub fn to_tcp_stream(&self, stream:&mut TcpStream) -> bool {
-		let resp_head_buf = self.header.total_size.to_le_bytes();
-		loop {
-			match stream.write_all(&resp_head_buf) {
-	    		Ok(_) => break,
-	    		Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::yield_now(),
-	    		Err(e) => { log_error!(Connections, "Got Error for writing response header: {}", e); return false; }
-			}
+		if let Err(e) = stream.write_all(&self.header.total_size.to_le_bytes()) {
+			log_error!(Connections, "Got Error for writing response header: {}", e);
+			return false;
 		}
 		if self.header.total_size > 0 {
-			loop {
-				match stream.write_all(self.body.as_slice()) {
-		    		Ok(_) => break,
-		    		Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::yield_now(),
-		    		Err(e) => { log_error!(Connections, "Got Error for writing response body {}", e); return false; }
-				}
+			if let Err(e) = stream.write_all(self.body.as_slice()) {
+				log_error!(Connections, "Got Error for writing response body {}", e);
+				return false;
 			}
 		}
 		true
 	}
+
+	// Queues onto a server connection, written out as the socket allows
+	pub fn to_server_stream<T>(&self, stream:&mut TcpServerStream<T>) {
+		stream.send(&self.header.total_size.to_le_bytes());
+		stream.send(self.body.as_slice());
+	}
 }
 
 #[cfg(test)]
@@ -62,6 +72,14 @@ mod tests {
     	assert_eq!(resp.size(), 4);
     }
 
+    #[test]
+    fn error_works() {
+    	let resp = Response::error(FlotonErr::IdleTimeout);
+    	assert_eq!(resp.size(), 2);
+    	let mut i = 0;
+    	assert!(matches!(FlotonErr::input_binary(&resp.body, &mut i), Ok(FlotonErr::IdleTimeout)));
+    }
+
     #[test]
     fn to_tcp_stream_works() {
     	logging_test_set(LOG_LEVEL_TRACE);
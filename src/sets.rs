@@ -0,0 +1,153 @@
+use crate::hashtree::{HashTree, HashScheme};
+use crate::keys;
+
+/**
+ * A set of byte string members, kept as the keys of a table with no
+ * values. Like the maps, it is safe to add, remove and read members from
+ * any number of threads, and whole set reads are weakly consistent.
+ * Members read out are borrowed from the set, so only stay valid while
+ * the reading thread stays pinned.
+ */
+#[derive(Debug)]
+pub struct Set(HashTree<()>);
+
+impl Set {
+	pub fn new(slot_count:usize) -> Set {
+		Set(HashTree::new_table(HashScheme::default(), slot_count))
+	}
+
+	pub fn len(&self) -> usize {
+		self.0.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+
+	// Returns false if member was already in the set
+	pub fn add(&self, member:&[u8]) -> bool {
+		self.0.add_bytes(member, keys::key_align(member))
+	}
+
+	// Returns false if member wasn't in the set
+	pub fn remove(&self, member:&[u8]) -> bool {
+		self.0.remove_bytes(member, keys::key_align(member))
+	}
+
+	pub fn contains(&self, member:&[u8]) -> bool {
+		self.0.find_bytes(member, keys::key_align(member)).is_some()
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+		self.0.iter().map(|(member, _)| member)
+	}
+
+	// A page of members after cursor, and the cursor for the next page,
+	// 0 once every member has been seen. See HashTree::page.
+	pub fn members(&self, cursor:u64, limit:usize) -> (Vec<&[u8]>, u64) {
+		let (page, next) = self.0.page(cursor, limit);
+		(page.into_iter().map(|(member, _)| member).collect(), next)
+	}
+
+	// Each of these reads both sets under the caller's one pin
+	pub fn union<'a>(&'a self, other:&'a Set) -> Vec<&'a [u8]> {
+		self.iter().chain(other.iter().filter(|m| !self.contains(m))).collect()
+	}
+
+	pub fn intersection<'a>(&'a self, other:&Set) -> Vec<&'a [u8]> {
+		self.iter().filter(|m| other.contains(m)).collect()
+	}
+
+	// The members of this set that aren't in other
+	pub fn difference<'a>(&'a self, other:&Set) -> Vec<&'a [u8]> {
+		self.iter().filter(|m| !other.contains(m)).collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::tlocal;
+    use crate::reclaim;
+    use crate::threading::TVal;
+
+    fn sorted(members:Vec<&[u8]>) -> Vec<String> {
+    	let mut found:Vec<String> = members.iter().map(|m| String::from_utf8(m.to_vec()).unwrap()).collect();
+    	found.sort();
+    	found
+    }
+
+    fn make_set(members:&[&str]) -> Set {
+    	let set = Set::new(4);
+    	for m in members {
+    		set.add(m.as_bytes());
+    	}
+    	set
+    }
+
+    #[test]
+    fn add_remove_works() {
+    	tlocal::set_epoch();
+    	let set = Set::new(4);
+    	assert!(set.add(b"red"));
+    	assert!(!set.add(b"red"));
+    	// word sized members are compared a word at a time
+    	assert!(set.add(b"12345678"));
+    	assert!(set.contains(b"12345678"));
+    	assert!(!set.contains(b"1234567"));
+    	assert_eq!(set.len(), 2);
+    	assert!(set.remove(b"red"));
+    	assert!(!set.remove(b"red"));
+    	assert!(!set.contains(b"red"));
+    	assert_eq!(sorted(set.iter().collect()), vec!["12345678"]);
+    }
+
+    #[test]
+    fn algebra_works() {
+    	tlocal::set_epoch();
+    	let a = make_set(&["a", "b", "c"]);
+    	let b = make_set(&["b", "c", "d"]);
+    	assert_eq!(sorted(a.union(&b)), vec!["a", "b", "c", "d"]);
+    	assert_eq!(sorted(a.intersection(&b)), vec!["b", "c"]);
+    	assert_eq!(sorted(a.difference(&b)), vec!["a"]);
+    	assert_eq!(sorted(b.difference(&a)), vec!["d"]);
+    }
+
+    #[test]
+    fn algebra_pinned_works() {
+    	tlocal::set_epoch();
+    	let a = TVal::new(make_set(&["a", "b"]));
+    	let b = TVal::new(make_set(&["b", "c"]));
+    	reclaim::pin();
+    	let union = a.union(&b);
+    	// members removed and collected elsewhere stay readable until unpinned
+    	let (ta, tb) = (a.clone(), b.clone());
+    	thread::spawn(move || {
+    		for m in ["a", "b", "c"].iter() {
+    			ta.remove(m.as_bytes());
+    			tb.remove(m.as_bytes());
+    		}
+    		for _ in 0..4 {
+    			reclaim::collect();
+    		}
+    	}).join().unwrap();
+    	assert_eq!(sorted(union), vec!["a", "b", "c"]);
+    	reclaim::unpin();
+    	assert!(a.is_empty() && b.is_empty());
+    }
+
+    #[test]
+    fn mt_add_works() {
+    	tlocal::set_epoch();
+    	let set = TVal::new(Set::new(2));
+    	let handles:Vec<_> = (0..4).map(|_| {
+    		let tset = set.clone();
+    		thread::spawn(move || (0..500).filter(|i| tset.add(format!("m{}", i).as_bytes())).count())
+    	}).collect();
+    	let added:usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+    	// each member is only added once, whoever gets there first
+    	assert_eq!(added, 500);
+    	assert_eq!(set.len(), 500);
+    }
+}
@@ -1,32 +1,129 @@
 use crate::traits::*;
 use crate::ports::next_port;
-use crate::db_args::{check_args, ArgRule};
+use crate::db_args::{check_args, ArgRule, ParseResult};
+use crate::logging::{LOG_LEVEL_FATAL, LOG_LEVEL_TRACE};
+use crate::auto_scale::{AutoScale, AutoScalePolicy};
+use crate::hashtree::HashKind;
+use crate::memory::EvictPolicy;
+use std::str::FromStr;
+use std::{fmt, fs, io};
+
+/**
+ * Why a setting couldn't be changed while running
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigErr {
+	// not a setting, or not a valid value for it
+	Invalid,
+	// can only be given at startup
+	ReadOnly
+}
+
+/**
+ * Why a config file couldn't be used, lines are counted from 1
+ */
+#[derive(Debug)]
+pub enum ConfigFileErr {
+	Read(String /*path*/, io::Error),
+	BadLine(usize),
+	UnknownKey(usize, String)
+}
+
+impl fmt::Display for ConfigFileErr {
+	fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ConfigFileErr::Read(path, e) => write!(f, "could not read config file {}, got {}", path, e),
+			ConfigFileErr::BadLine(line) => write!(f, "line {} of the config file isn't a key = value", line),
+			ConfigFileErr::UnknownKey(line, key) => write!(f, "line {} of the config file has unknown key {}", line, key)
+		}
+	}
+}
+
+// Flags taken at startup that aren't settings themselves
+pub const CONFIG_FLAG:&str = "--config";
+pub const PRINT_CONFIG_FLAG:&str = "--print-config";
+
+// Environment variables with this prefix give settings, FLOTON_TCP_PARK_MAX
+// gives --tcp-park-max
+const ENV_PREFIX:&str = "FLOTON_";
 
 
 #[derive(Debug, Clone)]
 pub struct Settings {
 	pub db_map_slots:usize,
+	pub hash_kind:HashKind,
+	// 0 picks a random seed at startup
+	pub hash_seed:u64,
 	pub db_port:u16,
 	pub conn_th_count:usize,
 	pub conn_queue_size:usize,
+	pub conn_backlog:usize,
+	pub conn_retry_after:u32,
+	pub conn_max_threads:usize,
+	pub conn_scale_depth:usize,
+	pub conn_scale_step:usize,
+	pub conn_idle_shrink_ms:u64,
+	pub max_conns:usize,
+	pub max_request_size:usize,
+	pub read_timeout_ms:u64,
+	pub write_timeout_ms:u64,
+	pub idle_timeout_ms:u64,
 	pub serv_addr:String,
 	pub tcp_park_min:u64,
 	pub tcp_park_max:u64,
 	pub tcp_park_seg:u64,
-	pub th_free_lim:u32
+	pub th_free_lim:u32,
+	// how often old versions are collected, 0 leaves it to writers
+	pub reclaim_ms:u64,
+	// bytes the keyspace is kept under, 0 leaves it unlimited
+	pub max_memory:usize,
+	pub evict_policy:EvictPolicy,
+	// keys looked at to pick each one to evict
+	pub evict_samples:usize,
+	// serves Prometheus metrics over http, 0 leaves it off
+	pub metrics_port:u16,
+	// commands slower than this are logged, 0 leaves it off
+	pub slowlog_threshold_us:u64,
+	pub slowlog_len:usize,
+	// the most a queue can be made to hold
+	pub max_queue_len:usize,
+	// from LOG_LEVEL_FATAL to LOG_LEVEL_TRACE
+	pub log_level:i32
 }
 
 impl NewType for Settings {
 	fn new() -> Self {
 		Settings{db_map_slots:100,
+		         hash_kind:HashKind::Sip,
+		         hash_seed:0,
 		         db_port:8080,
 		         conn_th_count:4,
 		         conn_queue_size:50,
+		         conn_backlog:128,
+		         conn_retry_after:100,
+		         conn_max_threads:64,
+		         conn_scale_depth:0,
+		         conn_scale_step:1,
+		         conn_idle_shrink_ms:30000,
+		         max_conns:10000,
+		         max_request_size:64 * 1024 * 1024,
+		         read_timeout_ms:10000,
+		         write_timeout_ms:10000,
+		         idle_timeout_ms:300000,
 		         serv_addr:String::from("127.0.0.1"),
 		         tcp_park_min:0,
 		         tcp_park_max:1000,
 		         tcp_park_seg:50,
-		         th_free_lim:5
+		         th_free_lim:5,
+		         reclaim_ms:100,
+		         max_memory:0,
+		         evict_policy:EvictPolicy::Reject,
+		         evict_samples:5,
+		         metrics_port:0,
+		         slowlog_threshold_us:10000,
+		         slowlog_len:128,
+		         max_queue_len:1 << 20,
+		         log_level:LOG_LEVEL_FATAL
 		     }
 	}
 }
@@ -38,40 +135,275 @@ impl Settings {
 		self.db_port = next_port();
 	}
 
+	pub fn conn_scale(&self) -> AutoScale {
+		let policy = match self.conn_scale_depth {
+			0 => AutoScalePolicy::WhenAllFull(self.conn_scale_step),
+			depth => AutoScalePolicy::WhenQueueDeeper(depth, self.conn_scale_step)
+		};
+		AutoScale{policy, max_workers:self.conn_max_threads, idle_shrink:self.conn_idle_shrink_ms}
+	}
+
 	pub fn from_args(args:&Vec<String>) -> Settings {
 		let mut port_rule = ArgRule::<u16>("--port", 8080);
 		let mut serv_addr_rule = ArgRule::<String>("--host", String::from("127.0.0.1"));
 		let mut conn_th_rule = ArgRule::<usize>("--conn-threads", 4);
 		let mut conn_queue_size_rule = ArgRule::<usize>("--conn-queue-size", 50);
+		let mut conn_backlog_rule = ArgRule::<usize>("--conn-backlog", 128);
+		let mut conn_retry_after_rule = ArgRule::<u32>("--conn-retry-after", 100);
+		let mut conn_max_threads_rule = ArgRule::<usize>("--conn-max-threads", 64);
+		// 0 only grows when the queue is full
+		let mut conn_scale_depth_rule = ArgRule::<usize>("--conn-scale-depth", 0);
+		let mut conn_scale_step_rule = ArgRule::<usize>("--conn-scale-step", 1);
+		let mut conn_idle_shrink_rule = ArgRule::<u64>("--conn-idle-shrink-ms", 30000);
+		let mut max_conns_rule = ArgRule::<usize>("--max-conns", 10000);
+		let mut max_request_size_rule = ArgRule::<usize>("--max-request-size", 64 * 1024 * 1024);
+		let mut read_timeout_rule = ArgRule::<u64>("--read-timeout-ms", 10000);
+		let mut write_timeout_rule = ArgRule::<u64>("--write-timeout-ms", 10000);
+		let mut idle_timeout_rule = ArgRule::<u64>("--idle-timeout-ms", 300000);
 		let mut db_map_slots_rule = ArgRule::<usize>("--db-map-slots", 100);
+		let mut hash_kind_rule = ArgRule::<HashKind>("--hash", HashKind::Sip);
+		let mut hash_seed_rule = ArgRule::<u64>("--hash-seed", 0);
 		let mut tcp_park_min_rule = ArgRule::<u64>("--tcp-park-min", 0);
 		let mut tcp_park_max_rule = ArgRule::<u64>("--tcp-park-max", 1000);
 		let mut tcp_park_seg_rule = ArgRule::<u64>("--tcp-park-seg", 50);
 		let mut th_free_lim_rule =  ArgRule::<u32>("--thread-free-limit", 5);
+		let mut reclaim_rule = ArgRule::<u64>("--reclaim-ms", 100);
+		let mut max_memory_rule = ArgRule::<usize>("--max-memory", 0);
+		let mut evict_policy_rule = ArgRule::<EvictPolicy>("--evict-policy", EvictPolicy::Reject);
+		let mut evict_samples_rule = ArgRule::<usize>("--evict-samples", 5);
+		let mut metrics_port_rule = ArgRule::<u16>("--metrics-port", 0);
+		let mut slowlog_threshold_rule = ArgRule::<u64>("--slowlog-threshold-us", 10000);
+		let mut slowlog_len_rule = ArgRule::<usize>("--slowlog-len", 128);
+		let mut max_queue_len_rule = ArgRule::<usize>("--max-queue-len", 1 << 20);
+		let mut log_level_rule = ArgRule::<i32>("--log-level", LOG_LEVEL_FATAL);
 
 		check_args(&mut port_rule, args);
 		check_args(&mut serv_addr_rule, args);
 		check_args(&mut conn_th_rule, args);
 		check_args(&mut conn_queue_size_rule, args);
+		check_args(&mut conn_backlog_rule, args);
+		check_args(&mut conn_retry_after_rule, args);
+		check_args(&mut conn_max_threads_rule, args);
+		check_args(&mut conn_scale_depth_rule, args);
+		check_args(&mut conn_scale_step_rule, args);
+		check_args(&mut conn_idle_shrink_rule, args);
+		check_args(&mut max_conns_rule, args);
+		check_args(&mut max_request_size_rule, args);
+		check_args(&mut read_timeout_rule, args);
+		check_args(&mut write_timeout_rule, args);
+		check_args(&mut idle_timeout_rule, args);
 		check_args(&mut db_map_slots_rule, args);
+		check_args(&mut hash_kind_rule, args);
+		check_args(&mut hash_seed_rule, args);
 		check_args(&mut tcp_park_max_rule, args);
 		check_args(&mut tcp_park_min_rule, args);
 		check_args(&mut tcp_park_seg_rule, args);
 		check_args(&mut th_free_lim_rule, args);
+		check_args(&mut reclaim_rule, args);
+		check_args(&mut max_memory_rule, args);
+		check_args(&mut evict_policy_rule, args);
+		check_args(&mut evict_samples_rule, args);
+		check_args(&mut metrics_port_rule, args);
+		check_args(&mut slowlog_threshold_rule, args);
+		check_args(&mut slowlog_len_rule, args);
+		check_args(&mut max_queue_len_rule, args);
+		check_args(&mut log_level_rule, args);
 
 		Settings{
 			db_map_slots:db_map_slots_rule.1,
+			hash_kind:hash_kind_rule.1,
+			hash_seed:hash_seed_rule.1,
 		    db_port:port_rule.1,
 		    conn_th_count:conn_th_rule.1,
 		    conn_queue_size:conn_queue_size_rule.1,
+		    conn_backlog:conn_backlog_rule.1,
+		    conn_retry_after:conn_retry_after_rule.1,
+		    conn_max_threads:conn_max_threads_rule.1,
+		    conn_scale_depth:conn_scale_depth_rule.1,
+		    conn_scale_step:conn_scale_step_rule.1,
+		    conn_idle_shrink_ms:conn_idle_shrink_rule.1,
+		    max_conns:max_conns_rule.1,
+		    max_request_size:max_request_size_rule.1,
+		    read_timeout_ms:read_timeout_rule.1,
+		    write_timeout_ms:write_timeout_rule.1,
+		    idle_timeout_ms:idle_timeout_rule.1,
 		    serv_addr:serv_addr_rule.1.clone(),
 		    tcp_park_min:tcp_park_min_rule.1,
 		    tcp_park_max:tcp_park_max_rule.1,
 		    tcp_park_seg:tcp_park_seg_rule.1,
-		    th_free_lim:th_free_lim_rule.1
+		    th_free_lim:th_free_lim_rule.1,
+		    reclaim_ms:reclaim_rule.1,
+		    max_memory:max_memory_rule.1,
+		    evict_policy:evict_policy_rule.1,
+		    evict_samples:evict_samples_rule.1,
+		    metrics_port:metrics_port_rule.1,
+		    slowlog_threshold_us:slowlog_threshold_rule.1,
+		    slowlog_len:slowlog_len_rule.1,
+		    max_queue_len:max_queue_len_rule.1,
+		    log_level:log_level_rule.1
 		}
 		
 	}
+
+	// The arguments that would give these settings, one per setting
+	pub fn to_args(&self) -> Vec<String> {
+		vec![format!("--port={}", self.db_port),
+		     format!("--host={}", self.serv_addr),
+		     format!("--conn-threads={}", self.conn_th_count),
+		     format!("--conn-queue-size={}", self.conn_queue_size),
+		     format!("--conn-backlog={}", self.conn_backlog),
+		     format!("--conn-retry-after={}", self.conn_retry_after),
+		     format!("--conn-max-threads={}", self.conn_max_threads),
+		     format!("--conn-scale-depth={}", self.conn_scale_depth),
+		     format!("--conn-scale-step={}", self.conn_scale_step),
+		     format!("--conn-idle-shrink-ms={}", self.conn_idle_shrink_ms),
+		     format!("--max-conns={}", self.max_conns),
+		     format!("--max-request-size={}", self.max_request_size),
+		     format!("--read-timeout-ms={}", self.read_timeout_ms),
+		     format!("--write-timeout-ms={}", self.write_timeout_ms),
+		     format!("--idle-timeout-ms={}", self.idle_timeout_ms),
+		     format!("--db-map-slots={}", self.db_map_slots),
+		     format!("--hash={}", self.hash_kind),
+		     format!("--hash-seed={}", self.hash_seed),
+		     format!("--tcp-park-min={}", self.tcp_park_min),
+		     format!("--tcp-park-max={}", self.tcp_park_max),
+		     format!("--tcp-park-seg={}", self.tcp_park_seg),
+		     format!("--thread-free-limit={}", self.th_free_lim),
+		     format!("--reclaim-ms={}", self.reclaim_ms),
+		     format!("--max-memory={}", self.max_memory),
+		     format!("--evict-policy={}", self.evict_policy),
+		     format!("--evict-samples={}", self.evict_samples),
+		     format!("--metrics-port={}", self.metrics_port),
+		     format!("--slowlog-threshold-us={}", self.slowlog_threshold_us),
+		     format!("--slowlog-len={}", self.slowlog_len),
+		     format!("--max-queue-len={}", self.max_queue_len),
+		     format!("--log-level={}", self.log_level)]
+	}
+
+	// Settings from every source, each over the one before it: the
+	// defaults, then the --config file, then FLOTON_ environment variables,
+	// then the command line. Also gives back the unknown flags and
+	// environment variables, which are left out. Unknown keys in the
+	// file are an error instead.
+	pub fn from_sources(args:&[String], env:impl Iterator<Item=(String, String)>) -> Result<(Settings, Vec<String>), ConfigFileErr> {
+		let mut unknown = vec![];
+		let mut env_args = vec![];
+		for (var, val) in env {
+			if let Some(name) = var.strip_prefix(ENV_PREFIX) {
+				let flag = format!("--{}", name.to_lowercase().replace('_', "-"));
+				if Settings::is_setting(&flag) || flag == CONFIG_FLAG {
+					env_args.push(format!("{}={}", flag, val));
+				} else {
+					unknown.push(var);
+				}
+			}
+		}
+		for arg in args.iter().filter(|arg| arg.starts_with("--")) {
+			let name = arg.split('=').next().unwrap_or(arg);
+			if !Settings::is_setting(name) && name != CONFIG_FLAG && name != PRINT_CONFIG_FLAG {
+				unknown.push(arg.clone());
+			}
+		}
+		// the first match is taken, so the strongest source goes first
+		let mut all_args = args.to_vec();
+		all_args.extend(env_args);
+		let mut config_rule = ArgRule::<String>(CONFIG_FLAG, String::new());
+		check_args(&mut config_rule, &all_args);
+		if !config_rule.1.is_empty() {
+			let text = fs::read_to_string(&config_rule.1).map_err(|e| ConfigFileErr::Read(config_rule.1.clone(), e))?;
+			all_args.extend(Settings::config_file_args(&text)?);
+		}
+		Ok((Settings::from_args(&all_args), unknown))
+	}
+
+	// Reads a config file as arguments. Each line is a key = value, where
+	// keys are the flags without their dashes, and may use underscores in
+	// place of dashes. Values may be quoted, # starts a comment.
+	pub fn config_file_args(text:&str) -> Result<Vec<String>, ConfigFileErr> {
+		let mut args = vec![];
+		for (i, line) in text.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			let (key, val) = match line.split_once('=') {
+				Some((key, val)) => (key.trim(), val.trim()),
+				None => return Err(ConfigFileErr::BadLine(i + 1))
+			};
+			let val = match val.strip_prefix('"').and_then(|quoted| quoted.split_once('"')) {
+				Some((inner, _)) => inner,
+				None => val.split('#').next().unwrap_or(val).trim()
+			};
+			let flag = format!("--{}", key.trim_start_matches('-').replace('_', "-"));
+			if key.is_empty() || val.is_empty() {
+				return Err(ConfigFileErr::BadLine(i + 1));
+			}
+			if !Settings::is_setting(&flag) {
+				return Err(ConfigFileErr::UnknownKey(i + 1, String::from(key)));
+			}
+			args.push(format!("{}={}", flag, val));
+		}
+		Ok(args)
+	}
+
+	// The settings as a config file, which reads back the same
+	pub fn to_config(&self) -> String {
+		let mut text = String::new();
+		for arg in self.to_args() {
+			let (flag, val) = arg.split_once('=').unwrap();
+			text.push_str(&format!("{} = {}\n", &flag[2..], val));
+		}
+		text
+	}
+
+	fn is_setting(name:&str) -> bool {
+		Settings::new().to_args().iter().any(|known| known.split('=').next() == Some(name))
+	}
+
+	// Changes one setting from its argument, like --tcp-park-max=500. Only
+	// the settings safe to change while running can be, and the change is
+	// only kept if every setting is still valid with it.
+	pub fn set_live(&mut self, arg:&str) -> Result<(), ConfigErr> {
+		fn parsed<T: FromStr>(name:&str, arg:&str, default:T) -> Result<T, ConfigErr> {
+			let mut rule = ArgRule::<T>(name, default);
+			match check_args(&mut rule, &vec![String::from(arg)]) {
+				ParseResult::Match => Ok(rule.1),
+				_ => Err(ConfigErr::Invalid)
+			}
+		}
+		let name = arg.split('=').next().unwrap_or(arg);
+		let mut next = self.clone();
+		match name {
+			"--log-level" => next.log_level = parsed(name, arg, next.log_level)?,
+			"--thread-free-limit" => next.th_free_lim = parsed(name, arg, next.th_free_lim)?,
+			"--db-map-slots" => next.db_map_slots = parsed(name, arg, next.db_map_slots)?,
+			"--tcp-park-min" => next.tcp_park_min = parsed(name, arg, next.tcp_park_min)?,
+			"--tcp-park-max" => next.tcp_park_max = parsed(name, arg, next.tcp_park_max)?,
+			"--tcp-park-seg" => next.tcp_park_seg = parsed(name, arg, next.tcp_park_seg)?,
+			"--conn-max-threads" => next.conn_max_threads = parsed(name, arg, next.conn_max_threads)?,
+			"--conn-scale-depth" => next.conn_scale_depth = parsed(name, arg, next.conn_scale_depth)?,
+			"--conn-scale-step" => next.conn_scale_step = parsed(name, arg, next.conn_scale_step)?,
+			"--conn-idle-shrink-ms" => next.conn_idle_shrink_ms = parsed(name, arg, next.conn_idle_shrink_ms)?,
+			"--slowlog-threshold-us" => next.slowlog_threshold_us = parsed(name, arg, next.slowlog_threshold_us)?,
+			"--max-queue-len" => next.max_queue_len = parsed(name, arg, next.max_queue_len)?,
+			_ if Settings::is_setting(name) => return Err(ConfigErr::ReadOnly),
+			_ => return Err(ConfigErr::Invalid)
+		}
+		if !next.live_valid() {
+			return Err(ConfigErr::Invalid);
+		}
+		*self = next;
+		Ok(())
+	}
+
+	fn live_valid(&self) -> bool {
+		(LOG_LEVEL_FATAL..=LOG_LEVEL_TRACE).contains(&self.log_level) &&
+		self.db_map_slots > 0 &&
+		self.tcp_park_min <= self.tcp_park_max &&
+		// the pool never shrinks below the size it started with
+		self.conn_max_threads >= self.conn_th_count &&
+		self.conn_scale_step > 0
+	}
 }
 
 #[cfg(test)]
@@ -94,8 +426,128 @@ mod tests {
     	args.push(String::from("8900"));
     	args.push(String::from("--conn-threads=5"));
     	args.push(String::from("--foobar")); // unrelated, shouldn't show as a val
+    	args.push(String::from("--idle-timeout-ms=0"));
+    	args.push(String::from("--hash=fx"));
     	let settings = Settings::from_args(&args);
     	assert_eq!(settings.db_port, 8900);
     	assert_eq!(settings.conn_th_count, 5);
+    	assert_eq!(settings.idle_timeout_ms, 0);
+    	assert_eq!(settings.read_timeout_ms, 10000);
+    	assert_eq!(settings.hash_kind, HashKind::Fx);
+    	assert_eq!(settings.hash_seed, 0);
+    	assert_eq!(settings.max_memory, 0);
+    	assert_eq!(settings.evict_policy, EvictPolicy::Reject);
+    	assert_eq!(settings.metrics_port, 0);
+    	assert_eq!(settings.slowlog_threshold_us, 10000);
+    }
+
+    #[test]
+    fn memory_args_works() {
+    	let args = vec![String::from("--max-memory=1048576"), String::from("--evict-policy=lfu"), String::from("--evict-samples"), String::from("10")];
+    	let settings = Settings::from_args(&args);
+    	assert_eq!(settings.max_memory, 1048576);
+    	assert_eq!(settings.evict_policy, EvictPolicy::Lfu);
+    	assert_eq!(settings.evict_samples, 10);
+    	// not a policy, so left at the default
+    	let settings = Settings::from_args(&vec![String::from("--evict-policy=random")]);
+    	assert_eq!(settings.evict_policy, EvictPolicy::Reject);
+    }
+
+    #[test]
+    fn to_args_works() {
+    	let mut settings = Settings::new();
+    	settings.db_port = 9100;
+    	settings.hash_kind = HashKind::Fx;
+    	settings.evict_policy = EvictPolicy::Lru;
+    	let args = settings.to_args();
+    	assert!(args.contains(&String::from("--port=9100")));
+    	assert!(args.contains(&String::from("--host=127.0.0.1")));
+    	// read back, they give the same settings
+    	let parsed = Settings::from_args(&args);
+    	assert_eq!(parsed.to_args(), args);
+    	assert_eq!(parsed.hash_kind, HashKind::Fx);
+    	assert_eq!(parsed.evict_policy, EvictPolicy::Lru);
+    }
+
+    #[test]
+    fn set_live_works() {
+    	let mut settings = Settings::new();
+    	assert_eq!(settings.set_live("--tcp-park-max=500"), Ok(()));
+    	assert_eq!(settings.tcp_park_max, 500);
+    	assert_eq!(settings.set_live("--db-map-slots=64"), Ok(()));
+    	assert_eq!(settings.db_map_slots, 64);
+    	assert_eq!(settings.set_live("--log-level=3"), Ok(()));
+    	assert_eq!(settings.log_level, 3);
+    	assert_eq!(settings.set_live("--max-queue-len=4096"), Ok(()));
+    	assert_eq!(settings.max_queue_len, 4096);
+    	// only given at startup
+    	assert_eq!(settings.set_live("--port=9000"), Err(ConfigErr::ReadOnly));
+    	assert_eq!(settings.set_live("--max-memory=100"), Err(ConfigErr::ReadOnly));
+    	assert_eq!(settings.db_port, 8080);
+    	// not settings, or not valid values
+    	assert_eq!(settings.set_live("--foobar=1"), Err(ConfigErr::Invalid));
+    	assert_eq!(settings.set_live("--tcp-park-max"), Err(ConfigErr::Invalid));
+    	assert_eq!(settings.set_live("--tcp-park-max=soon"), Err(ConfigErr::Invalid));
+    	assert_eq!(settings.set_live("--log-level=9"), Err(ConfigErr::Invalid));
+    	assert_eq!(settings.set_live("--db-map-slots=0"), Err(ConfigErr::Invalid));
+    	assert_eq!(settings.set_live("--conn-max-threads=2"), Err(ConfigErr::Invalid));
+    	// the minimum can't go past the maximum
+    	assert_eq!(settings.set_live("--tcp-park-min=600"), Err(ConfigErr::Invalid));
+    	assert_eq!(settings.tcp_park_min, 0);
+    	assert_eq!(settings.tcp_park_max, 500);
+    	assert_eq!(settings.log_level, 3);
+    }
+
+    #[test]
+    fn config_file_works() {
+    	let text = "# a comment\n\nport = 9001\ntcp_park_max = 300 # trailing\n--hash = \"fx\"\nhost=\"0.0.0.0\"\n";
+    	let args = Settings::config_file_args(text).unwrap();
+    	assert_eq!(args, vec!["--port=9001", "--tcp-park-max=300", "--hash=fx", "--host=0.0.0.0"]);
+    	assert!(matches!(Settings::config_file_args("port = 1\nfoobar = 2"), Err(ConfigFileErr::UnknownKey(2, _))));
+    	assert!(matches!(Settings::config_file_args("port"), Err(ConfigFileErr::BadLine(1))));
+    	assert!(matches!(Settings::config_file_args("port ="), Err(ConfigFileErr::BadLine(1))));
+    	// the dump reads back as the same settings
+    	let mut settings = Settings::new();
+    	settings.db_port = 9002;
+    	settings.evict_policy = EvictPolicy::Lfu;
+    	let parsed = Settings::from_args(&Settings::config_file_args(&settings.to_config()).unwrap());
+    	assert_eq!(parsed.to_args(), settings.to_args());
+    }
+
+    #[test]
+    fn from_sources_works() {
+    	let path = std::env::temp_dir().join(format!("floton_settings_{}.conf", std::process::id()));
+    	fs::write(&path, "port = 9001\nconn_threads = 6\nmax_conns = 50\n").unwrap();
+    	let args = vec![format!("--config={}", path.display()), String::from("--port=9003"), String::from("--foobar")];
+    	let env = vec![(String::from("FLOTON_PORT"), String::from("9002")),
+    	               (String::from("FLOTON_CONN_THREADS"), String::from("7")),
+    	               (String::from("FLOTON_NOPE"), String::from("1")),
+    	               (String::from("HOME"), String::from("/root"))];
+    	let (settings, unknown) = Settings::from_sources(&args, env.into_iter()).unwrap();
+    	// the command line over the environment over the file
+    	assert_eq!(settings.db_port, 9003);
+    	assert_eq!(settings.conn_th_count, 7);
+    	assert_eq!(settings.max_conns, 50);
+    	assert_eq!(settings.read_timeout_ms, 10000);
+    	assert_eq!(unknown, vec!["FLOTON_NOPE", "--foobar"]);
+    	// the file can come from the environment too
+    	let env = vec![(String::from("FLOTON_CONFIG"), path.display().to_string())];
+    	let (settings, _) = Settings::from_sources(&[], env.into_iter()).unwrap();
+    	assert_eq!(settings.db_port, 9001);
+    	fs::write(&path, "port = 9001\nprot = 9002\n").unwrap();
+    	assert!(matches!(Settings::from_sources(&args, std::iter::empty()), Err(ConfigFileErr::UnknownKey(2, _))));
+    	fs::remove_file(&path).unwrap();
+    	assert!(matches!(Settings::from_sources(&args, std::iter::empty()), Err(ConfigFileErr::Read(_, _))));
+    }
+
+    #[test]
+    fn conn_scale_works() {
+    	let mut settings = Settings::new();
+    	assert_eq!(settings.conn_scale().policy, AutoScalePolicy::WhenAllFull(1));
+    	settings = Settings::from_args(&vec![String::from("--conn-scale-depth=20"), String::from("--conn-scale-step=2")]);
+    	let scale = settings.conn_scale();
+    	assert_eq!(scale.policy, AutoScalePolicy::WhenQueueDeeper(20, 2));
+    	assert_eq!(scale.max_workers, 64);
+    	assert_eq!(scale.idle_shrink, 30000);
     }
 }
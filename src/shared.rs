@@ -1,15 +1,26 @@
 use std::sync::atomic::{AtomicPtr, AtomicUsize, AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::{thread, ptr};
 use crate::tlocal;
+use crate::reclaim;
+use crate::slab;
+use crate::memory;
 use crate::traits::NewType;
-use crate::trie::IntTrie;
 
+/**
+ * A version of a value, with the time it was made and a stamp of when
+ * and how often it has been accessed, for eviction.
+ */
 #[derive(Debug)]
-pub struct TimePtr<T>(pub T, pub u64);
+pub struct TimePtr<T>(pub T, pub u64, pub AtomicU64 /*access stamp*/);
 
 impl<T> TimePtr<T> {
     pub fn make(val:T) -> *mut TimePtr<T> {
-        alloc!(TimePtr(val, tlocal::time()))
+        slab::alloc(TimePtr(val, tlocal::time(), AtomicU64::new(memory::new_stamp())))
+    }
+
+    // Marks the version as accessed now
+    pub fn touch(&self) {
+        memory::touch(&self.2);
     }
     
     pub fn get_time(ptr:*mut TimePtr<T>) -> Option<u64> {
@@ -29,11 +40,11 @@ struct FreeNode<T>(AtomicPtr<TimePtr<T>>, AtomicPtr<FreeNode<T>>);
 
 impl<T> FreeNode<T> {
     fn new() -> *mut FreeNode<T> {
-        alloc!(FreeNode(AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut())))
+        slab::alloc(FreeNode(AtomicPtr::new(ptr::null_mut()), AtomicPtr::new(ptr::null_mut())))
     }
 
     fn new_ptr(ptr:*mut TimePtr<T>) -> *mut FreeNode<T> {
-        alloc!(FreeNode(AtomicPtr::new(ptr), AtomicPtr::new(ptr::null_mut())))
+        slab::alloc(FreeNode(AtomicPtr::new(ptr), AtomicPtr::new(ptr::null_mut())))
     }
 }
 // This is not actually thread safe, this should only be called by a specific thread
@@ -48,10 +59,10 @@ impl<T> Drop for FreeList<T> {
             let old_ptr = cur_ptr;
             let old_time_ptr = noderef.0.load(Ordering::Relaxed);
             if nonull!(old_time_ptr) {
-                free!(old_time_ptr);
+                unsafe { slab::free(old_time_ptr); }
             }
             cur_ptr = noderef.1.load(Ordering::Relaxed);
-            free!(old_ptr);
+            unsafe { slab::free(old_ptr); }
         }
     }
 }
@@ -94,21 +105,13 @@ impl<T> FreeList<T> {
     }
 }
 
-#[derive(Debug)]
-struct ThreadStorage<T> {
-    cur_time:AtomicU64,
-    free_list:FreeList<T>
-}
-
-impl<T> NewType for ThreadStorage<T> {
-    fn new() -> Self {
-        ThreadStorage{cur_time:AtomicU64::new(0), free_list:FreeList::new()}
-    }
-}
-
+/**
+ * A value that can be swapped out from under readers, a single pointer.
+ * Each write retires the version it replaced to reclaim, which frees it
+ * once no pinned thread can still be reading it.
+ */
 #[derive(Debug)]
 pub struct Shared<T> {
-    time_keeps:IntTrie<ThreadStorage<T>>,
     cur_ptr:AtomicPtr<TimePtr<T>>
 }
 
@@ -116,15 +119,14 @@ impl<T> Drop for Shared<T> {
     fn drop(&mut self) {
         let current = self.cur_ptr.load(Ordering::SeqCst);
         if nonull!(current) {
-            free!(current);
+            unsafe { slab::free(current); }
         }
     }
 }
 
 impl<T> NewType for Shared<T> {
     fn new() -> Shared<T> {
-        // todo make configurable
-        Shared{time_keeps:IntTrie::new(5), cur_ptr:newptr!()}
+        Shared{cur_ptr:newptr!()}
     }
 }
 
@@ -139,95 +141,58 @@ impl<T> Shared<T> {
     pub fn is_empty(&self) -> bool {
         isnull!(self.cur_ptr.load(Ordering::SeqCst))
     }
-    
-    pub fn time_check(&self, ctime:u64) -> bool {
-        // Checks if all threads have advanced past some time, allowing safe freeing.
 
-        fn check_time_keep<T>(stor:&ThreadStorage<T>, op:&u64) -> bool {
-            stor.cur_time.load(Ordering::SeqCst) < *op
-        }
-        return self.time_keeps.check_if_one(check_time_keep, &ctime);
-    }
-
-    pub fn free_run(&self) -> u32  {
-        let flist = &self.time_keeps.get_by_tid().free_list;
-        if flist.count() < tlocal::get_free_lim() {
-            return 0;
-        }
-        //println!("Running free list on thread: {}", tid);
-        let mut freed = 0;
-        let mut cur_ptr = flist.0.load(Ordering::SeqCst);
-        loop {
-            unsafe {
-                match cur_ptr.as_ref() {
-                    Some(r) => {
-                        let inner_ptr = r.0.load(Ordering::SeqCst);
-                        match inner_ptr.as_ref()  {
-                            Some(rp) => {
-                                if self.time_check(rp.1) {
-                                    free!(inner_ptr);
-                                    freed += 1;
-                                    r.0.store(ptr::null_mut(), Ordering::SeqCst);
-                                }
-                            },
-                            None => ()
-                        }
-                        cur_ptr = r.1.load(Ordering::SeqCst); 
-                    },
-                    None => { break; }
-                }
-            }
-        }
-        flist.1.fetch_sub(freed, Ordering::SeqCst);
-        return freed;
-    }
-    
     pub fn write(&self, ptr:*mut TimePtr<T>) {
         let swapped_out = self.cur_ptr.swap(ptr, Ordering::SeqCst);
-        match TimePtr::get_time(swapped_out) {
-            Some(ti) => {
-                let time_slot = & self.time_keeps.get_by_tid();
-                time_slot.cur_time.store(ti, Ordering::SeqCst);
-                time_slot.free_list.add(swapped_out);
-            },
-            None => ()
+        if !swapped_out.is_null() {
+            reclaim::retire_slab(swapped_out);
         }
     }
-    
-    pub fn read(&self) -> *mut TimePtr<T> {
-        self.free_run();
-        let read_ptr = self.cur_ptr.load(Ordering::SeqCst);
-        let time_slot = & self.time_keeps.get_by_tid();
-        match TimePtr::get_time(read_ptr) {
-            Some(ti) => {
-                time_slot.cur_time.store(ti, Ordering::SeqCst);
-                read_ptr
-            },
-            None => ptr::null_mut()
+
+    // As write, the version replaced counting as size bytes until freed
+    pub fn write_sized(&self, ptr:*mut TimePtr<T>, size:impl FnOnce(&T) -> usize) {
+        let swapped_out = self.cur_ptr.swap(ptr, Ordering::SeqCst);
+        if !swapped_out.is_null() {
+            reclaim::retire_slab_sized(swapped_out, size(&ptref!(swapped_out).0));
         }
     }
 
-    pub fn update_time(&self) -> bool {
-        match TimePtr::get_time(self.cur_ptr.load(Ordering::SeqCst)) {
-            Some(ti) => {
-                self.time_keeps.get_by_tid().cur_time.store(ti, Ordering::SeqCst);
-                true
-            },
-            None => false
-        }
+    // Only valid while the reading thread stays pinned
+    pub fn read(&self) -> *mut TimePtr<T> {
+        self.cur_ptr.load(Ordering::SeqCst)
     }
 }
 
-
-
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::threading::*;
+    use std::sync::Arc;
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
     //use std::sync::atomic::{AtomicPtr, AtomicI64, Ordering};
     #[derive(Debug, Copy, Clone)]
     struct TestType(u32);
 
+    #[derive(Debug)]
+    struct Counted(u32, Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.1.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    // Other tests pin as well, so collect until they move on
+    fn collect_until(freed:&AtomicUsize, count:usize) {
+        let start = Instant::now();
+        while freed.load(Ordering::SeqCst) < count {
+            assert!(start.elapsed() < Duration::from_secs(5), "Old versions were never freed");
+            reclaim::collect();
+            thread::yield_now();
+        }
+    }
+
     #[test]
     fn freenode_works() {
         tlocal::set_epoch();
@@ -265,36 +230,63 @@ mod tests {
     }
 
     #[test]
-    fn shared_freerun_works() {
-        // We want control of free list just for this test
-        tlocal::set_free_lim(50);
+    fn shared_retire_works() {
         tlocal::set_epoch();
-        let mut shared = Shared::<TestType>::new();
-        // init test
-        assert!(!shared.time_check(0));
-        let t1 = thcall!(5, shared.write(TimePtr::make(TestType(5))));
-        assert!(shared.free_run() == 0);
-        let t2 = thcall!(5, shared.write(TimePtr::make(TestType(5))));
-        assert!(shared.free_run() == 0);
-        tlocal::set_free_lim(1);
-        // still shouldn't free since other thread slots 
-        assert!(shared.free_run() == 0);
-        tlocal::set_free_lim(3);
+        let freed = Arc::new(AtomicUsize::new(0));
+        let mut shared = Shared::new_val(Counted(1, freed.clone()));
+        shared.write(TimePtr::make(Counted(2, freed.clone())));
+        // the writing thread has exited, its versions aren't stranded
+        let tfreed = freed.clone();
+        let t1 = thcall!(2, shared.write(TimePtr::make(Counted(3, tfreed.clone()))));
         t1.join().unwrap();
-        t2.join().unwrap();
+        collect_until(&freed, 3);
+        unsafe { assert_eq!(shared.read().as_ref().unwrap().0.0, 3); }
+        drop(shared);
+        assert_eq!(freed.load(Ordering::SeqCst), 4);
     }
 
     #[test]
-    fn shared_timecheck_works() {
+    fn shared_pinned_works() {
         tlocal::set_epoch();
-        let mut shared = Shared::<TestType>::new();
-        let to_write = TimePtr::make(TestType(5));
-        shared.write(to_write);
-        let t1 = thcall!(5, shared.write(TimePtr::make(TestType(5))));
-        let t2 = thcall!(5, shared.write(TimePtr::make(TestType(5))));
-        assert!(!shared.time_check(TimePtr::get_time(to_write).unwrap()));
-        t1.join().unwrap();
-        t2.join().unwrap();
+        let freed = Arc::new(AtomicUsize::new(0));
+        let shared = TVal::new(Shared::new_val(Counted(1, freed.clone())));
+        let tshared = shared.clone();
+        let (pinned_tx, pinned_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        let reader = thread::spawn(move || {
+            reclaim::pin();
+            unsafe { assert_eq!(tshared.read().as_ref().unwrap().0.0, 1); }
+            pinned_tx.send(()).unwrap();
+            done_rx.recv().unwrap();
+            reclaim::unpin();
+        });
+        pinned_rx.recv().unwrap();
+        shared.write(TimePtr::make(Counted(2, freed.clone())));
+        for _ in 0..4 {
+            reclaim::collect();
+        }
+        // the reader may still have the first version
+        assert_eq!(freed.load(Ordering::SeqCst), 0);
+        done_tx.send(()).unwrap();
+        reader.join().unwrap();
+        collect_until(&freed, 1);
+    }
+
+    #[test]
+    fn collector_works() {
+        tlocal::set_epoch();
+        let freed = Arc::new(AtomicUsize::new(0));
+        let mut collector = reclaim::Collector::start(Duration::from_millis(1));
+        let shared = Shared::new_val(Counted(0, freed.clone()));
+        shared.write(TimePtr::make(Counted(1, freed.clone())));
+        // never read or written again, the collector still frees the old one
+        let start = Instant::now();
+        while freed.load(Ordering::SeqCst) < 1 {
+            assert!(start.elapsed() < Duration::from_secs(5), "Old versions were never freed");
+            thread::sleep(Duration::from_millis(1));
+        }
+        collector.stop();
+        unsafe { assert_eq!(shared.read().as_ref().unwrap().0.0, 1); }
     }
 
     #[test]
@@ -306,34 +298,4 @@ mod tests {
         assert_eq!(to_write, shared.cur_ptr.load(Ordering::SeqCst));
         assert_eq!(to_write, shared.read());
     }
-
-    #[test]
-    fn shared_rw_time_works() {
-        tlocal::set_epoch();
-        let shared = Shared::<TestType>::new();
-        let to_write1 = TimePtr::make(TestType(5));
-        let to_write2 = TimePtr::make(TestType(1));
-        let wtime1 = TimePtr::get_time(to_write1).unwrap();
-        let wtime2 = TimePtr::get_time(to_write2).unwrap();
-        shared.write(to_write1);
-        shared.read();
-        let seen_time1 = shared.time_keeps.get_by_tid().cur_time.load(Ordering::SeqCst);
-        shared.write(to_write2);
-        shared.read();
-        let seen_time2 = shared.time_keeps.get_by_tid().cur_time.load(Ordering::SeqCst);
-        assert_eq!(seen_time1, wtime1);
-        assert_eq!(seen_time2, wtime2);
-        assert!(seen_time1 < seen_time2);
-    }
-
-    #[test]
-    fn shared_update_time_works() {
-        tlocal::set_epoch();
-        let shared = Shared::<TestType>::new();
-        shared.write(TimePtr::make(TestType(5)));
-        let seen_time1 = shared.time_keeps.get_by_tid().cur_time.load(Ordering::SeqCst);
-        assert!(shared.update_time());
-        let seen_time2 = shared.time_keeps.get_by_tid().cur_time.load(Ordering::SeqCst);
-        assert!(seen_time1 < seen_time2);
-    }
 }
@@ -0,0 +1,394 @@
+use std::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use std::convert::TryInto;
+use crate::hashtree::{HashKind, HashScheme};
+use crate::constants::{VBIN_HLL, VBIN_BLOOM};
+use crate::errors::FlotonErr;
+use crate::keys;
+use crate::traits::*;
+
+// Sketches are written out and merged across processes, so every one
+// hashes with the same fixed key
+const SKETCH_SEED:u64 = 0x5ca1ab1e0ddba11;
+
+pub const HLL_MIN_PRECISION:u8 = 4;
+pub const HLL_MAX_PRECISION:u8 = 16;
+pub const BLOOM_MAX_HASHES:u8 = 32;
+
+#[inline]
+fn sketch_hash(item:&[u8]) -> u64 {
+	HashScheme::new(HashKind::Sip, SKETCH_SEED).hash(item, 1)
+}
+
+/**
+ * A HyperLogLog estimate of how many distinct items were added, kept in
+ * 2^precision byte registers. Each register only ever grows, so adds and
+ * merges are a single fetch_max and never wait on another thread.
+ */
+#[derive(Debug)]
+pub struct Hll {
+	precision:u8,
+	regs:Box<[AtomicU8]>
+}
+
+impl Hll {
+	pub fn new(precision:u8) -> Hll {
+		assert!((HLL_MIN_PRECISION..=HLL_MAX_PRECISION).contains(&precision), "Unsupported HLL precision: {}", precision);
+		Hll{precision, regs:(0..(1usize << precision)).map(|_| AtomicU8::new(0)).collect()}
+	}
+
+	pub fn precision(&self) -> u8 {
+		self.precision
+	}
+
+	// Returns true if the estimate may have changed
+	pub fn add(&self, item:&[u8]) -> bool {
+		let hash = sketch_hash(item);
+		let idx = (hash >> (64 - self.precision)) as usize;
+		let rank = ((hash << self.precision).leading_zeros().min(64 - self.precision as u32) + 1) as u8;
+		self.regs[idx].fetch_max(rank, Ordering::AcqRel) < rank
+	}
+
+	pub fn count(&self) -> u64 {
+		let m = self.regs.len() as f64;
+		let mut sum = 0.0;
+		let mut zeros = 0;
+		for reg in self.regs.iter() {
+			let r = reg.load(Ordering::Acquire);
+			sum += 1.0 / ((1u64 << r) as f64);
+			if r == 0 {
+				zeros += 1;
+			}
+		}
+		let alpha = match self.regs.len() {
+			16 => 0.673,
+			32 => 0.697,
+			64 => 0.709,
+			_ => 0.7213 / (1.0 + 1.079 / m)
+		};
+		let estimate = alpha * m * m / sum;
+		// small counts are better judged by how many registers are unused
+		if estimate <= 2.5 * m && zeros > 0 {
+			(m * (m / zeros as f64).ln()).round() as u64
+		} else {
+			estimate.round() as u64
+		}
+	}
+
+	// Folds other in, false if the precisions differ
+	pub fn merge(&self, other:&Hll) -> bool {
+		if self.precision != other.precision {
+			return false;
+		}
+		for (reg, theirs) in self.regs.iter().zip(other.regs.iter()) {
+			reg.fetch_max(theirs.load(Ordering::Acquire), Ordering::AcqRel);
+		}
+		true
+	}
+}
+
+/**
+ * A Bloom filter over words of atomic bits. An item sets one bit for each
+ * hash, so adds and merges are fetch_ors, and a check may wrongly say an
+ * item was seen but never that it wasn't.
+ */
+#[derive(Debug)]
+pub struct Bloom {
+	hashes:u8,
+	words:Box<[AtomicU64]>
+}
+
+impl Bloom {
+	pub fn new(word_count:usize, hashes:u8) -> Bloom {
+		assert!(word_count > 0, "A bloom filter needs at least one word");
+		assert!((1..=BLOOM_MAX_HASHES).contains(&hashes), "Unsupported bloom hash count: {}", hashes);
+		Bloom{hashes, words:(0..word_count).map(|_| AtomicU64::new(0)).collect()}
+	}
+
+	pub fn word_count(&self) -> usize {
+		self.words.len()
+	}
+
+	pub fn hashes(&self) -> u8 {
+		self.hashes
+	}
+
+	// The bits for item, the hashes are made from two halves of one hash
+	fn bits(&self, item:&[u8]) -> impl Iterator<Item = (usize, u64)> {
+		let hash = sketch_hash(item);
+		let (h1, h2) = (hash & 0xffffffff, (hash >> 32) | 1);
+		let bit_count = self.words.len() as u64 * 64;
+		(0..self.hashes as u64).map(move |i| {
+			let bit = h1.wrapping_add(i.wrapping_mul(h2)) % bit_count;
+			((bit / 64) as usize, 1u64 << (bit % 64))
+		})
+	}
+
+	// Returns true if item wasn't seen before
+	pub fn add(&self, item:&[u8]) -> bool {
+		let mut added = false;
+		for (word, bit) in self.bits(item) {
+			added |= self.words[word].fetch_or(bit, Ordering::AcqRel) & bit == 0;
+		}
+		added
+	}
+
+	pub fn check(&self, item:&[u8]) -> bool {
+		self.bits(item).all(|(word, bit)| self.words[word].load(Ordering::Acquire) & bit != 0)
+	}
+
+	// An estimate of the items added, from how many bits are set
+	pub fn count(&self) -> u64 {
+		let bit_count = self.words.len() as f64 * 64.0;
+		let set:u32 = self.words.iter().map(|w| w.load(Ordering::Acquire).count_ones()).sum();
+		(-(bit_count / self.hashes as f64) * (1.0 - set as f64 / bit_count).ln()).round() as u64
+	}
+
+	// Folds other in, false if the sizes or hash counts differ
+	pub fn merge(&self, other:&Bloom) -> bool {
+		if self.hashes != other.hashes || self.words.len() != other.words.len() {
+			return false;
+		}
+		for (word, theirs) in self.words.iter().zip(other.words.iter()) {
+			word.fetch_or(theirs.load(Ordering::Acquire), Ordering::AcqRel);
+		}
+		true
+	}
+}
+
+#[derive(Debug)]
+pub enum Sketch {
+	Hll(Hll),
+	Bloom(Bloom)
+}
+
+impl Sketch {
+	// The VBIN_ tag this sketch is written with
+	pub fn vbin_type(&self) -> u8 {
+		match self {
+			Sketch::Hll(_) => VBIN_HLL,
+			Sketch::Bloom(_) => VBIN_BLOOM
+		}
+	}
+
+	pub fn add(&self, item:&[u8]) -> bool {
+		match self {
+			Sketch::Hll(h) => h.add(item),
+			Sketch::Bloom(b) => b.add(item)
+		}
+	}
+
+	pub fn count(&self) -> u64 {
+		match self {
+			Sketch::Hll(h) => h.count(),
+			Sketch::Bloom(b) => b.count()
+		}
+	}
+
+	// None if the sketch can't tell whether an item was seen
+	pub fn check(&self, item:&[u8]) -> Option<bool> {
+		match self {
+			Sketch::Hll(_) => None,
+			Sketch::Bloom(b) => Some(b.check(item))
+		}
+	}
+
+	// Folds other in, false if it is a different kind or shape of sketch
+	pub fn merge(&self, other:&Sketch) -> bool {
+		match (self, other) {
+			(Sketch::Hll(h), Sketch::Hll(theirs)) => h.merge(theirs),
+			(Sketch::Bloom(b), Sketch::Bloom(theirs)) => b.merge(theirs),
+			_ => false
+		}
+	}
+}
+
+impl InPutOutPut for Sketch {
+	fn output_binary(&self, output:&mut Vec<u8>) {
+		match self {
+			Sketch::Hll(h) => {
+				output.push(VBIN_HLL);
+				output.push(h.precision);
+				output.extend(h.regs.iter().map(|r| r.load(Ordering::Acquire)));
+			},
+			Sketch::Bloom(b) => {
+				output.push(VBIN_BLOOM);
+				output.push(b.hashes);
+				output.extend_from_slice(&(b.words.len() as u64).to_le_bytes());
+				for word in b.words.iter() {
+					output.extend_from_slice(&word.load(Ordering::Acquire).to_le_bytes());
+				}
+			}
+		}
+	}
+
+	fn input_binary(input:&[u8], place:&mut usize) -> Result<Self, FlotonErr> {
+		let in_type = *input.get(*place).ok_or(FlotonErr::BadRequest(*place as u64))?;
+		*place += 1;
+		match in_type {
+			VBIN_HLL => {
+				let precision = *input.get(*place).ok_or(FlotonErr::BadRequest(*place as u64))?;
+				if !(HLL_MIN_PRECISION..=HLL_MAX_PRECISION).contains(&precision) {
+					return Err(FlotonErr::UnexpectedByte(precision));
+				}
+				*place += 1;
+				let regs = input.get(*place..(*place + (1usize << precision))).ok_or(FlotonErr::BadRequest(*place as u64))?;
+				let hll = Hll::new(precision);
+				for (reg, byte) in hll.regs.iter().zip(regs) {
+					reg.store(*byte, Ordering::Relaxed);
+				}
+				*place += regs.len();
+				Ok(Sketch::Hll(hll))
+			},
+			VBIN_BLOOM => {
+				let hashes = *input.get(*place).ok_or(FlotonErr::BadRequest(*place as u64))?;
+				if !(1..=BLOOM_MAX_HASHES).contains(&hashes) {
+					return Err(FlotonErr::UnexpectedByte(hashes));
+				}
+				*place += 1;
+				let word_count = keys::read_u64(input, place)?;
+				if word_count == 0 {
+					return Err(FlotonErr::UnexpectedByte(0));
+				}
+				// the words have to be there before any are made for them
+				if word_count > ((input.len() - *place) / 8) as u64 {
+					return Err(FlotonErr::BadRequest(*place as u64));
+				}
+				let bloom = Bloom::new(word_count as usize, hashes);
+				for word in bloom.words.iter() {
+					word.store(u64::from_le_bytes(input[*place..(*place + 8)].try_into().unwrap()), Ordering::Relaxed);
+					*place += 8;
+				}
+				Ok(Sketch::Bloom(bloom))
+			},
+			_ => Err(FlotonErr::UnexpectedByte(in_type))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn hll_count_works() {
+    	let hll = Hll::new(14);
+    	assert_eq!(hll.count(), 0);
+    	for i in 0..100000u32 {
+    		hll.add(&i.to_le_bytes());
+    	}
+    	// adding again changes nothing
+    	assert!(!hll.add(&7u32.to_le_bytes()));
+    	let count = hll.count() as f64;
+    	assert!((count - 100000.0).abs() < 100000.0 * 0.03, "Estimate too far off: {}", count);
+    	let small = Hll::new(14);
+    	for i in 0..100u32 {
+    		small.add(&i.to_le_bytes());
+    	}
+    	assert!((small.count() as i64 - 100).abs() <= 2, "Estimate too far off: {}", small.count());
+    }
+
+    #[test]
+    fn hll_merge_works() {
+    	let a = Hll::new(12);
+    	let b = Hll::new(12);
+    	for i in 0..5000u32 {
+    		a.add(&i.to_le_bytes());
+    		b.add(&(i + 2500).to_le_bytes());
+    	}
+    	assert!(a.merge(&b));
+    	let count = a.count() as f64;
+    	assert!((count - 7500.0).abs() < 7500.0 * 0.05, "Estimate too far off: {}", count);
+    	assert!(!a.merge(&Hll::new(10)));
+    }
+
+    #[test]
+    fn bloom_check_works() {
+    	let bloom = Bloom::new(1024, 7);
+    	for i in 0..5000u32 {
+    		assert!(bloom.add(&i.to_le_bytes()) || bloom.check(&i.to_le_bytes()));
+    	}
+    	assert!((0..5000u32).all(|i| bloom.check(&i.to_le_bytes())));
+    	let false_pos = (5000..15000u32).filter(|i| bloom.check(&i.to_le_bytes())).count();
+    	// about 1% for this size and count
+    	assert!(false_pos < 300, "Too many false positives: {}", false_pos);
+    	let count = bloom.count() as f64;
+    	assert!((count - 5000.0).abs() < 5000.0 * 0.05, "Estimate too far off: {}", count);
+    	let other = Bloom::new(1024, 7);
+    	other.add(b"late");
+    	assert!(!bloom.check(b"late"));
+    	assert!(bloom.merge(&other));
+    	assert!(bloom.check(b"late"));
+    	assert!(!bloom.merge(&Bloom::new(1024, 3)));
+    }
+
+    #[test]
+    fn sketch_binary_works() {
+    	let hll = Sketch::Hll(Hll::new(4));
+    	let bloom = Sketch::Bloom(Bloom::new(2, 3));
+    	for i in 0..20u32 {
+    		hll.add(&i.to_le_bytes());
+    		bloom.add(&i.to_le_bytes());
+    	}
+    	let mut out = vec![];
+    	hll.output_binary(&mut out);
+    	bloom.output_binary(&mut out);
+    	assert_eq!(out.len(), 2 + 16 + 2 + 8 + 16);
+    	let mut i = 0;
+    	let hll_in = Sketch::input_binary(&out, &mut i).unwrap();
+    	let bloom_in = Sketch::input_binary(&out, &mut i).unwrap();
+    	assert_eq!(i, out.len());
+    	assert_eq!(hll_in.count(), hll.count());
+    	assert_eq!(bloom_in.count(), bloom.count());
+    	assert_eq!(bloom_in.check(&3u32.to_le_bytes()), Some(true));
+    	assert_eq!(hll_in.check(&3u32.to_le_bytes()), None);
+    	assert!(!hll_in.merge(&bloom_in));
+    	match Sketch::input_binary(&[VBIN_HLL, 30], &mut 0) {
+    		Err(FlotonErr::UnexpectedByte(30)) => (),
+    		other => panic!("Expected bad precision, got {:?}", other)
+    	}
+    	// cut short, or claiming more words than were sent
+    	match Sketch::input_binary(&[], &mut 0) {
+    		Err(FlotonErr::BadRequest(0)) => (),
+    		other => panic!("Expected a missing sketch, got {:?}", other)
+    	}
+    	match Sketch::input_binary(&[VBIN_HLL, 4, 0, 0], &mut 0) {
+    		Err(FlotonErr::BadRequest(2)) => (),
+    		other => panic!("Expected a short HLL, got {:?}", other)
+    	}
+    	let mut huge = vec![VBIN_BLOOM, 3];
+    	huge.extend_from_slice(&u64::MAX.to_le_bytes());
+    	huge.extend_from_slice(&[0;8]);
+    	match Sketch::input_binary(&huge, &mut 0) {
+    		Err(FlotonErr::BadRequest(10)) => (),
+    		other => panic!("Expected a short bloom filter, got {:?}", other)
+    	}
+    	match Sketch::input_binary(&[VBIN_BLOOM, 3, 1, 0], &mut 0) {
+    		Err(FlotonErr::BadRequest(2)) => (),
+    		other => panic!("Expected a short bloom filter, got {:?}", other)
+    	}
+    }
+
+    #[test]
+    fn mt_add_works() {
+    	let hll = Arc::new(Hll::new(12));
+    	let bloom = Arc::new(Bloom::new(512, 5));
+    	let mut handles = vec![];
+    	for t in 0..4u32 {
+    		let (thll, tbloom) = (hll.clone(), bloom.clone());
+    		handles.push(thread::spawn(move || {
+    			for i in 0..2000u32 {
+    				thll.add(&(t * 2000 + i).to_le_bytes());
+    				tbloom.add(&(t * 2000 + i).to_le_bytes());
+    			}
+    		}));
+    	}
+    	for h in handles {
+    		h.join().unwrap();
+    	}
+    	assert!((0..8000u32).all(|i| bloom.check(&i.to_le_bytes())));
+    	let count = hll.count() as f64;
+    	assert!((count - 8000.0).abs() < 8000.0 * 0.05, "Estimate too far off: {}", count);
+    }
+}
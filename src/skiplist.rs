@@ -0,0 +1,337 @@
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::cell::Cell;
+use std::ops::{Bound, Deref};
+use std::ptr;
+use crate::traits::NewType;
+
+// Enough levels for far more items than a map will ever hold
+const MAX_LEVEL:usize = 24;
+
+thread_local!(static LEVEL_RNG:Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1));
+
+// Each level up holds a quarter of the nodes of the one below
+fn random_height() -> usize {
+	LEVEL_RNG.with(|rng| {
+		let mut x = rng.get();
+		x ^= x << 13;
+		x ^= x >> 7;
+		x ^= x << 17;
+		rng.set(x);
+		((x.trailing_zeros() as usize) / 2 + 1).min(MAX_LEVEL)
+	})
+}
+
+#[derive(Debug)]
+struct SkipNode<T> {
+	key:Box<[u8]>,
+	val:T,
+	next:Box<[AtomicPtr<SkipNode<T>>]>
+}
+
+/**
+ * A lock free skip list, keeping keys in byte order. Nodes are linked
+ * into the bottom level first, which alone decides membership, and the
+ * levels above are only shortcuts that are filled in after. Nothing is
+ * ever unlinked, so references into the list stay valid for its life.
+ */
+#[derive(Debug)]
+pub struct SkipList<T> {
+	head:Box<[AtomicPtr<SkipNode<T>>]>,
+	count:AtomicUsize
+}
+
+impl<T> Drop for SkipList<T> {
+	fn drop(&mut self) {
+		let mut cur = self.head[0].load(Ordering::SeqCst);
+		while !cur.is_null() {
+			let next = ptref!(cur).next[0].load(Ordering::SeqCst);
+			free!(cur);
+			cur = next;
+		}
+	}
+}
+
+impl<T> NewType for SkipList<T> {
+	fn new() -> Self {
+		SkipList{head:(0..MAX_LEVEL).map(|_| AtomicPtr::new(ptr::null_mut())).collect(), count:AtomicUsize::new(0)}
+	}
+}
+
+impl<T> SkipList<T> {
+	// The link to follow at a level, from the head when node is null
+	#[inline]
+	fn link(&self, node:*mut SkipNode<T>, level:usize) -> &AtomicPtr<SkipNode<T>> {
+		if node.is_null() { &self.head[level] } else { &ptref!(node).next[level] }
+	}
+
+	// For every level, the last node before key, or null for the head,
+	// and the first node at or after it
+	fn search(&self, key:&[u8]) -> ([*mut SkipNode<T>; MAX_LEVEL], [*mut SkipNode<T>; MAX_LEVEL]) {
+		let mut preds = [ptr::null_mut(); MAX_LEVEL];
+		let mut succs = [ptr::null_mut(); MAX_LEVEL];
+		let mut pred:*mut SkipNode<T> = ptr::null_mut();
+		for level in (0..MAX_LEVEL).rev() {
+			let mut cur = self.link(pred, level).load(Ordering::SeqCst);
+			while !cur.is_null() && ptref!(cur).key.deref() < key {
+				pred = cur;
+				cur = ptref!(cur).next[level].load(Ordering::SeqCst);
+			}
+			preds[level] = pred;
+			succs[level] = cur;
+		}
+		(preds, succs)
+	}
+
+	// The last node with a key below the bound, or null if there is none
+	fn last_below(&self, bound:Bound<&[u8]>) -> *mut SkipNode<T> {
+		let mut pred:*mut SkipNode<T> = ptr::null_mut();
+		for level in (0..MAX_LEVEL).rev() {
+			let mut cur = self.link(pred, level).load(Ordering::SeqCst);
+			while !cur.is_null() && match bound {
+				Bound::Included(k) => ptref!(cur).key.deref() <= k,
+				Bound::Excluded(k) => ptref!(cur).key.deref() < k,
+				Bound::Unbounded => true
+			} {
+				pred = cur;
+				cur = ptref!(cur).next[level].load(Ordering::SeqCst);
+			}
+		}
+		pred
+	}
+
+	// The first node with a key above the bound, or null if there is none
+	fn first_above(&self, bound:Bound<&[u8]>) -> *mut SkipNode<T> {
+		match bound {
+			Bound::Unbounded => self.head[0].load(Ordering::SeqCst),
+			Bound::Included(k) => self.search(k).1[0],
+			Bound::Excluded(k) => {
+				let found = self.search(k).1[0];
+				if !found.is_null() && ptref!(found).key.deref() == k {
+					ptref!(found).next[0].load(Ordering::SeqCst)
+				} else {
+					found
+				}
+			}
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.count.load(Ordering::SeqCst)
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	pub fn find_bytes(&self, key:&[u8]) -> Option<&T> {
+		let found = self.search(key).1[0];
+		if !found.is_null() && ptref!(found).key.deref() == key {
+			Some(&ptref!(found).val)
+		} else {
+			None
+		}
+	}
+
+	// Items between the bounds in key order, or in reverse key order
+	pub fn range<'a>(&'a self, start:Bound<&[u8]>, end:Bound<&'a [u8]>) -> Range<'a, T> {
+		Range{cur:self.first_above(start), end, list:self}
+	}
+
+	pub fn range_rev<'a>(&'a self, start:Bound<&'a [u8]>, end:Bound<&[u8]>) -> RevRange<'a, T> {
+		RevRange{cur:self.last_below(end), start, list:self}
+	}
+
+	pub fn iter(&self) -> Range<'_, T> {
+		self.range(Bound::Unbounded, Bound::Unbounded)
+	}
+}
+
+impl<T: NewType> SkipList<T> {
+	pub fn insert_bytes(&self, key:&[u8]) -> &T {
+		let height = random_height();
+		let node = alloc!(SkipNode{key:key.into(), val:T::new(),
+		                           next:(0..height).map(|_| AtomicPtr::new(ptr::null_mut())).collect()});
+		loop {
+			let (preds, succs) = self.search(key);
+			if !succs[0].is_null() && ptref!(succs[0]).key.deref() == key {
+				free!(node);
+				return &ptref!(succs[0]).val;
+			}
+			for (next, succ) in ptref!(node).next.iter().zip(succs.iter()) {
+				next.store(*succ, Ordering::SeqCst);
+			}
+			if self.link(preds[0], 0).compare_exchange(succs[0], node, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+				continue;
+			}
+			self.count.fetch_add(1, Ordering::SeqCst);
+			// the node is in the list now, the upper levels only speed up searches
+			let (mut preds, mut succs) = (preds, succs);
+			for level in 1..height {
+				while self.link(preds[level], level).compare_exchange(succs[level], node, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+					let (p, s) = self.search(key);
+					preds = p;
+					succs = s;
+					ptref!(node).next[level].store(succs[level], Ordering::SeqCst);
+				}
+			}
+			return &ptref!(node).val;
+		}
+	}
+}
+
+/**
+ * Walks a skip list in key order up to an end bound. Like the list
+ * itself it is weakly consistent, items inserted ahead of it during the
+ * walk may or may not be seen.
+ */
+#[derive(Debug)]
+pub struct Range<'a, T> {
+	cur:*mut SkipNode<T>,
+	end:Bound<&'a [u8]>,
+	list:&'a SkipList<T>
+}
+
+impl<'a, T> Iterator for Range<'a, T> {
+	type Item = (&'a [u8], &'a T);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.cur.is_null() {
+			return None;
+		}
+		let node:&'a SkipNode<T> = ptref!(self.cur);
+		let in_range = match self.end {
+			Bound::Included(k) => node.key.deref() <= k,
+			Bound::Excluded(k) => node.key.deref() < k,
+			Bound::Unbounded => true
+		};
+		if !in_range {
+			self.cur = ptr::null_mut();
+			return None;
+		}
+		self.cur = node.next[0].load(Ordering::SeqCst);
+		Some((node.key.deref(), &node.val))
+	}
+}
+
+/**
+ * Walks a skip list in reverse key order down to a start bound. The list
+ * only links forward, so each step searches again for the item before.
+ */
+#[derive(Debug)]
+pub struct RevRange<'a, T> {
+	cur:*mut SkipNode<T>,
+	start:Bound<&'a [u8]>,
+	list:&'a SkipList<T>
+}
+
+impl<'a, T> Iterator for RevRange<'a, T> {
+	type Item = (&'a [u8], &'a T);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.cur.is_null() {
+			return None;
+		}
+		let node:&'a SkipNode<T> = ptref!(self.cur);
+		let in_range = match self.start {
+			Bound::Included(k) => node.key.deref() >= k,
+			Bound::Excluded(k) => node.key.deref() > k,
+			Bound::Unbounded => true
+		};
+		if !in_range {
+			self.cur = ptr::null_mut();
+			return None;
+		}
+		self.cur = self.list.last_below(Bound::Excluded(node.key.deref()));
+		Some((node.key.deref(), &node.val))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+    use std::thread;
+    use crate::threading::TVal;
+
+    #[derive(Debug)]
+    struct TestType(AtomicU32);
+
+    impl NewType for TestType {
+    	fn new() -> Self {
+    		TestType(AtomicU32::new(0))
+    	}
+    }
+
+    fn keys_of<'a>(items:impl Iterator<Item = (&'a [u8], &'a TestType)>) -> Vec<String> {
+    	items.map(|(k, _)| String::from_utf8(k.to_vec()).unwrap()).collect()
+    }
+
+    #[test]
+    fn insert_find_works() {
+    	let list = SkipList::<TestType>::new();
+    	assert!(list.is_empty());
+    	list.insert_bytes(b"banana").0.store(2, Ordering::SeqCst);
+    	list.insert_bytes(b"apple").0.store(1, Ordering::SeqCst);
+    	// inserting again finds the same item
+    	assert_eq!(list.insert_bytes(b"banana").0.load(Ordering::SeqCst), 2);
+    	assert_eq!(list.len(), 2);
+    	assert_eq!(list.find_bytes(b"apple").unwrap().0.load(Ordering::SeqCst), 1);
+    	assert!(list.find_bytes(b"appl").is_none());
+    	assert!(list.find_bytes(b"cherry").is_none());
+    }
+
+    #[test]
+    fn range_works() {
+    	let list = SkipList::<TestType>::new();
+    	for i in (0..100).rev() {
+    		list.insert_bytes(format!("user:{:03}", i).as_bytes());
+    	}
+    	assert_eq!(keys_of(list.iter()).len(), 100);
+    	assert_eq!(keys_of(list.iter())[0], "user:000");
+    	assert_eq!(keys_of(list.range(Bound::Included(b"user:010"), Bound::Excluded(b"user:013"))),
+    	           vec!["user:010", "user:011", "user:012"]);
+    	assert_eq!(keys_of(list.range(Bound::Excluded(b"user:010"), Bound::Included(b"user:013"))),
+    	           vec!["user:011", "user:012", "user:013"]);
+    	// bounds need not be keys in the list
+    	assert_eq!(keys_of(list.range(Bound::Included(b"user:0985"), Bound::Unbounded)),
+    	           vec!["user:099"]);
+    	assert_eq!(keys_of(list.range_rev(Bound::Included(b"user:010"), Bound::Excluded(b"user:013"))),
+    	           vec!["user:012", "user:011", "user:010"]);
+    	assert_eq!(keys_of(list.range_rev(Bound::Unbounded, Bound::Unbounded).take(2)),
+    	           vec!["user:099", "user:098"]);
+    	assert_eq!(keys_of(list.range_rev(Bound::Excluded(b"user:001"), Bound::Included(b"user:002"))),
+    	           vec!["user:002"]);
+    	assert!(keys_of(list.range(Bound::Included(b"z"), Bound::Unbounded)).is_empty());
+    	assert!(keys_of(list.range_rev(Bound::Unbounded, Bound::Excluded(b"user:000"))).is_empty());
+    }
+
+    #[test]
+    fn mt_insert_works() {
+    	let list = TVal::new(SkipList::<TestType>::new());
+    	let mut handles = vec![];
+    	for t in 0..4 {
+    		let tlist = list.clone();
+    		handles.push(thread::spawn(move || {
+    			for i in 0..3000 {
+    				tlist.insert_bytes(format!("{:05}", i * 4 + t).as_bytes());
+    				// every thread races on the shared keys
+    				tlist.insert_bytes(format!("shared{:05}", i).as_bytes()).0.fetch_add(1, Ordering::SeqCst);
+    			}
+    		}));
+    	}
+    	for h in handles {
+    		h.join().unwrap();
+    	}
+    	assert_eq!(list.len(), 12000 + 3000);
+    	let keys = keys_of(list.iter());
+    	assert_eq!(keys.len(), 15000);
+    	let mut sorted = keys.clone();
+    	sorted.sort();
+    	assert_eq!(keys, sorted);
+    	for i in 0..3000 {
+    		assert_eq!(list.find_bytes(format!("shared{:05}", i).as_bytes()).unwrap().0.load(Ordering::SeqCst), 4);
+    	}
+    }
+}
@@ -0,0 +1,270 @@
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::alloc::{self, Layout};
+use std::{mem, ptr};
+use crate::tlocal;
+use crate::traits::NewType;
+use crate::trie::IntTrie;
+
+/**
+ * A per thread slab allocator for the small values made and freed on
+ * every write. Each thread has its own heap with a list of free blocks
+ * for every size class, which only it takes from, so allocating never
+ * waits on another thread. A block freed by another thread is pushed on
+ * a lock free list of its owner's, which the owner takes whole once its
+ * own list runs dry. Heaps are kept by thread ID, so a thread given a
+ * recycled ID takes over the blocks of the thread that had it. Values
+ * too large or too aligned for a size class go to the global allocator.
+ */
+// Payload sizes of the size classes
+const CLASSES:[usize;5] = [16, 32, 64, 128, 256];
+// Blocks carved out of the global allocator at once
+const CHUNK_BLOCKS:usize = 64;
+const BLOCK_ALIGN:usize = 16;
+
+// In front of every block, padded so the payload stays aligned
+#[repr(C, align(16))]
+#[derive(Debug)]
+struct Header {
+	owner:usize,
+	next:*mut Header
+}
+
+const HEADER_SIZE:usize = mem::size_of::<Header>();
+
+#[derive(Debug)]
+struct SizeClass {
+	// Only the owning thread uses this list
+	local:AtomicPtr<Header>,
+	// Blocks freed by other threads
+	remote:AtomicPtr<Header>
+}
+
+impl NewType for SizeClass {
+	fn new() -> Self {
+		SizeClass{local:newptr!(), remote:newptr!()}
+	}
+}
+
+#[derive(Debug)]
+struct Heap {
+	classes:[SizeClass;CLASSES.len()]
+}
+
+impl NewType for Heap {
+	fn new() -> Self {
+		Heap{classes:[(); CLASSES.len()].map(|_| SizeClass::new())}
+	}
+}
+
+static HEAPS:OnceLock<IntTrie<Heap>> = OnceLock::new();
+// Bytes taken from the global allocator for chunks, never given back
+static RESERVED:AtomicUsize = AtomicUsize::new(0);
+
+fn heaps() -> &'static IntTrie<Heap> {
+	HEAPS.get_or_init(|| IntTrie::new(8))
+}
+
+// The size class a T is made in, if any
+#[inline]
+fn class_of<T>() -> Option<usize> {
+	if cfg!(feature = "box-alloc") || mem::align_of::<T>() > BLOCK_ALIGN {
+		return None;
+	}
+	CLASSES.iter().position(|size| mem::size_of::<T>() <= *size)
+}
+
+impl SizeClass {
+	fn pop(&self, class:usize, owner:usize) -> *mut Header {
+		let mut head = self.local.load(Ordering::Relaxed);
+		if head.is_null() {
+			head = self.remote.swap(ptr::null_mut(), Ordering::Acquire);
+		}
+		if head.is_null() {
+			head = SizeClass::carve(class, owner);
+		}
+		self.local.store(ptref!(head).next, Ordering::Relaxed);
+		head
+	}
+
+	// A new chunk, threaded into a list of free blocks
+	fn carve(class:usize, owner:usize) -> *mut Header {
+		let block = HEADER_SIZE + CLASSES[class];
+		let layout = Layout::from_size_align(block * CHUNK_BLOCKS, BLOCK_ALIGN).unwrap();
+		let base = unsafe { alloc::alloc(layout) };
+		if base.is_null() {
+			alloc::handle_alloc_error(layout);
+		}
+		RESERVED.fetch_add(layout.size(), Ordering::Relaxed);
+		for i in 0..CHUNK_BLOCKS {
+			let next = if i + 1 < CHUNK_BLOCKS { unsafe { base.add((i + 1) * block) as *mut Header } } else { ptr::null_mut() };
+			unsafe { (base.add(i * block) as *mut Header).write(Header{owner, next}); }
+		}
+		base as *mut Header
+	}
+
+	fn push_local(&self, block:*mut Header) {
+		unsafe { (*block).next = self.local.load(Ordering::Relaxed); }
+		self.local.store(block, Ordering::Relaxed);
+	}
+
+	fn push_remote(&self, block:*mut Header) {
+		let mut head = self.remote.load(Ordering::Relaxed);
+		loop {
+			unsafe { (*block).next = head; }
+			match self.remote.compare_exchange_weak(head, block, Ordering::Release, Ordering::Relaxed) {
+				Ok(_) => return,
+				Err(seen) => head = seen
+			}
+		}
+	}
+}
+
+// Moves val into a block of the calling thread's heap
+pub fn alloc<T>(val:T) -> *mut T {
+	match class_of::<T>() {
+		Some(class) => {
+			let owner = tlocal::tid();
+			let block = heaps().get_by_tid().classes[class].pop(class, owner);
+			let made = unsafe { (block as *mut u8).add(HEADER_SIZE) as *mut T };
+			unsafe { made.write(val); }
+			made
+		},
+		None => alloc!(val)
+	}
+}
+
+/**
+ * Drops and frees a value made by alloc, from any thread
+ *
+ * # Safety
+ * made has to come from alloc, and nothing may use or free it after
+ */
+pub unsafe fn free<T>(made:*mut T) {
+	match class_of::<T>() {
+		Some(class) => {
+			unsafe { ptr::drop_in_place(made); }
+			let block = unsafe { (made as *mut u8).sub(HEADER_SIZE) as *mut Header };
+			let owner = ptref!(block).owner;
+			let heap = heaps().get_made(owner).unwrap();
+			// a thread exiting has no ID left, so frees as another thread would
+			if tlocal::try_tid() == Some(owner) {
+				heap.classes[class].push_local(block);
+			} else {
+				heap.classes[class].push_remote(block);
+			}
+		},
+		None => free!(made)
+	}
+}
+
+/**
+ * Owns a value made by alloc, freeing it when dropped
+ */
+#[derive(Debug)]
+pub struct SlabBox<T>(pub *mut T);
+
+impl<T> Drop for SlabBox<T> {
+	fn drop(&mut self) {
+		unsafe { free(self.0); }
+	}
+}
+
+// Bytes the slab holds, in use or free
+pub fn reserved() -> usize {
+	RESERVED.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicU32;
+    use std::thread;
+
+    #[derive(Debug)]
+    struct Counted(u64, Arc<AtomicU32>);
+
+    impl Drop for Counted {
+    	fn drop(&mut self) {
+    		self.1.fetch_add(1, Ordering::SeqCst);
+    	}
+    }
+
+    // box-alloc leaves no size classes to test
+    #[test]
+    #[cfg(not(feature = "box-alloc"))]
+    fn class_of_works() {
+    	assert_eq!(class_of::<u8>(), Some(0));
+    	assert_eq!(class_of::<[u64;4]>(), Some(1));
+    	assert_eq!(class_of::<[u8;256]>(), Some(4));
+    	assert_eq!(class_of::<[u8;257]>(), None);
+    }
+
+    #[test]
+    #[cfg(not(feature = "box-alloc"))]
+    fn alloc_reuse_works() {
+    	let first = alloc([7u64;3]);
+    	assert_eq!(unsafe { *first }, [7;3]);
+    	unsafe { free(first); }
+    	// the last block freed is the first given out again
+    	let second = alloc([9u64;3]);
+    	assert_eq!(first, second);
+    	assert_eq!(unsafe { *second }, [9;3]);
+    	unsafe { free(second); }
+    	let large = alloc([1u8;300]);
+    	assert_eq!(unsafe { (*large)[299] }, 1);
+    	unsafe { free(large); }
+    }
+
+    // Blocks on the calling thread's list of those freed by others
+    fn remote_blocks(class:usize) -> Vec<usize> {
+    	let mut found = vec![];
+    	let mut cur = heaps().get_by_tid().classes[class].remote.load(Ordering::SeqCst);
+    	while nonull!(cur) {
+    		found.push(cur as usize + HEADER_SIZE);
+    		cur = ptref!(cur).next;
+    	}
+    	found
+    }
+
+    #[test]
+    #[cfg(not(feature = "box-alloc"))]
+    fn remote_free_works() {
+    	let dropped = Arc::new(AtomicU32::new(0));
+    	let made:Vec<usize> = (0..200).map(|i| alloc(Counted(i, dropped.clone())) as usize).collect();
+    	let tmade = made.clone();
+    	thread::spawn(move || {
+    		for each in tmade {
+    			unsafe { free(each as *mut Counted); }
+    		}
+    	}).join().unwrap();
+    	assert_eq!(dropped.load(Ordering::SeqCst), 200);
+    	// handed back to this thread, to be given out again
+    	let remote = remote_blocks(class_of::<Counted>().unwrap());
+    	assert!(made.iter().all(|each| remote.contains(each)));
+    }
+
+    #[test]
+    fn mt_alloc_free_works() {
+    	let handles:Vec<_> = (0..4u64).map(|t| thread::spawn(move || {
+    		let mut held = vec![];
+    		for i in 0..5000 {
+    			held.push(alloc((t, i)));
+    			if i % 3 == 0 {
+    				let (ht, hi) = unsafe { *held[0] };
+    				assert_eq!(ht, t);
+    				assert!(hi <= i);
+    				unsafe { free(held.swap_remove(0)); }
+    			}
+    		}
+    		held.into_iter().map(|p| p as usize).collect::<Vec<usize>>()
+    	})).collect();
+    	// freed by a thread other than the ones that made them
+    	for handle in handles {
+    		for each in handle.join().unwrap() {
+    			unsafe { free(each as *mut (u64, u64)); }
+    		}
+    	}
+    }
+}
@@ -0,0 +1,157 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::containers::Container;
+use crate::values::Value;
+
+/**
+ * The newest commands that took longer than --slowlog-threshold-us, up
+ * to --slowlog-len of them, the oldest dropping off first. Only slow
+ * commands take the lock, so it stays out of the way of the rest.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowEntry {
+	pub id:u64,
+	// ms since the unix epoch
+	pub time_ms:u64,
+	pub cmd:u8,
+	pub key:Vec<Vec<u8>>,
+	pub took_us:u64,
+	pub client:Option<SocketAddr>
+}
+
+impl SlowEntry {
+	// The key path as text, each key escaped and split by a '/'
+	pub fn key_text(&self) -> String {
+		let keys:Vec<String> = self.key.iter().map(|key| key.escape_ascii().to_string()).collect();
+		keys.join("/")
+	}
+}
+
+#[derive(Debug)]
+pub struct SlowLog {
+	threshold_us:AtomicU64,
+	cap:usize,
+	next_id:AtomicU64,
+	entries:Mutex<VecDeque<SlowEntry>>
+}
+
+impl SlowLog {
+	// A threshold of 0 leaves it off
+	pub fn new(threshold_us:u64, cap:usize) -> SlowLog {
+		SlowLog{threshold_us:AtomicU64::new(threshold_us), cap, next_id:AtomicU64::new(0), entries:Mutex::new(VecDeque::with_capacity(cap))}
+	}
+
+	#[inline]
+	pub fn is_slow(&self, took:Duration) -> bool {
+		let threshold_us = self.threshold_us.load(Ordering::Relaxed);
+		threshold_us > 0 && self.cap > 0 && took.as_micros() > threshold_us as u128
+	}
+
+	pub fn set_threshold(&self, threshold_us:u64) {
+		self.threshold_us.store(threshold_us, Ordering::Relaxed);
+	}
+
+	pub fn record(&self, cmd:u8, key:Vec<Vec<u8>>, took:Duration, client:Option<SocketAddr>) {
+		let time_ms = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64);
+		let entry = SlowEntry{id:self.next_id.fetch_add(1, Ordering::Relaxed), time_ms, cmd, key,
+		                      took_us:took.as_micros() as u64, client};
+		let mut entries = self.entries.lock().unwrap();
+		if entries.len() >= self.cap {
+			entries.pop_front();
+		}
+		entries.push_back(entry);
+	}
+
+	// The newest first, a limit of 0 gives all of them
+	pub fn entries(&self, limit:usize) -> Vec<SlowEntry> {
+		let entries = self.entries.lock().unwrap();
+		let limit = if limit == 0 { entries.len() } else { limit };
+		entries.iter().rev().take(limit).cloned().collect()
+	}
+
+	// Gives back how many were dropped
+	pub fn reset(&self) -> usize {
+		let mut entries = self.entries.lock().unwrap();
+		let dropped = entries.len();
+		entries.clear();
+		dropped
+	}
+
+	pub fn len(&self) -> usize {
+		self.entries.lock().unwrap().len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	// An ordered map keyed by the big endian id of each entry. Text can't
+	// be a value, so the key path and client are each the only key of a map.
+	pub fn output(&self, limit:usize) -> Container<Value> {
+		let log = Container::new_ordered_map();
+		for entry in self.entries(limit) {
+			let fields = Container::new_map(8);
+			fields.set_map(b"time_ms", Container::Val(Value::UInt(entry.time_ms)));
+			fields.set_map(b"cmd", Container::Val(Value::UInt(entry.cmd as u64)));
+			fields.set_map(b"took_us", Container::Val(Value::UInt(entry.took_us)));
+			let key = Container::new_map(1);
+			key.set_map(entry.key_text().as_bytes(), Container::Val(Value::Nothing));
+			fields.set_map(b"key", key);
+			if let Some(client) = entry.client {
+				let addr = Container::new_map(1);
+				addr.set_map(client.to_string().as_bytes(), Container::Val(Value::Nothing));
+				fields.set_map(b"client", addr);
+			}
+			log.set_map(&entry.id.to_be_bytes(), fields);
+		}
+		log
+	}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::*;
+
+    #[test]
+    fn threshold_works() {
+    	let log = SlowLog::new(1000, 4);
+    	assert!(!log.is_slow(Duration::from_micros(1000)));
+    	assert!(log.is_slow(Duration::from_micros(1001)));
+    	assert!(!SlowLog::new(0, 4).is_slow(Duration::from_secs(1)));
+    	assert!(!SlowLog::new(1000, 0).is_slow(Duration::from_secs(1)));
+    	log.set_threshold(0);
+    	assert!(!log.is_slow(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn key_text_works() {
+    	let entry = SlowEntry{id:0, time_ms:0, cmd:CMD_RETURN_KV, key:vec![b"users".to_vec(), b"\x01id".to_vec()], took_us:0, client:None};
+    	assert_eq!(entry.key_text(), "users/\\x01id");
+    }
+
+    #[test]
+    fn ring_works() {
+    	let log = SlowLog::new(1, 3);
+    	let client:SocketAddr = "127.0.0.1:4000".parse().unwrap();
+    	for i in 0..5u8 {
+    		log.record(CMD_SET_KV, vec![vec![b'a' + i]], Duration::from_micros(10 + i as u64), Some(client));
+    	}
+    	assert_eq!(log.len(), 3);
+    	let entries = log.entries(0);
+    	assert_eq!(entries.iter().map(|e| e.id).collect::<Vec<_>>(), vec![4, 3, 2]);
+    	assert_eq!(entries[0].key, vec![b"e".to_vec()]);
+    	assert_eq!(entries[0].took_us, 14);
+    	assert_eq!(entries[0].client, Some(client));
+    	assert!(entries[0].time_ms > 0);
+    	assert_eq!(log.entries(1).len(), 1);
+    	assert_eq!(log.reset(), 3);
+    	assert!(log.is_empty());
+    	// ids keep going after a reset
+    	log.record(CMD_QPOP, vec![], Duration::from_micros(2), None);
+    	assert_eq!(log.entries(0)[0].id, 5);
+    }
+}
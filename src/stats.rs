@@ -0,0 +1,277 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use crate::constants::{CMD_CONFIG_SET, OP_ATOMIC_SUB_FETCH};
+use crate::traits::NewType;
+use crate::trie::IntTrie;
+
+/**
+ * How many of each command were run, and how many gave an error. Every
+ * thread counts into its own counters, kept by thread ID, so counting
+ * never contends with another thread. Reading a total sums the counters
+ * of every thread that has counted. Latencies are kept the same way, in
+ * fixed buckets, so timing a command is a couple of loads and stores.
+ */
+// Commands are counted by their CMD_ byte
+pub const CMD_KINDS:usize = CMD_CONFIG_SET as usize + 1;
+
+// The names commands are given in INFO, by their CMD_ byte
+pub const CMD_NAMES:[&str;CMD_KINDS] = ["stop", "return_kv", "set_kv", "op_atomic", "op_normal", "range",
+                                        "qpush", "qpop", "qbpop", "set_algebra", "info", "slowlog_get", "slowlog_reset",
+                                        "config_get", "config_set"];
+
+// Atomic ops are timed by their OP_ATOMIC_ u16
+pub const OP_ATOMIC_KINDS:usize = OP_ATOMIC_SUB_FETCH as usize + 1;
+
+pub const OP_ATOMIC_NAMES:[&str;OP_ATOMIC_KINDS] = ["store", "store_relax", "swap", "swap_relax",
+                                                   "cond_store", "cond_store_relax", "cond_swap", "cond_swap_relax",
+                                                   "add", "add_fetch", "sub", "sub_fetch"];
+
+pub const LATENCY_BUCKETS:usize = 13;
+
+// Upper bounds of the latency buckets in ns, anything slower goes in the last
+pub const LATENCY_BOUNDS_NS:[u64;LATENCY_BUCKETS - 1] = [250, 1_000, 5_000, 25_000, 100_000, 250_000, 1_000_000,
+                                                         5_000_000, 25_000_000, 100_000_000, 500_000_000, 1_000_000_000];
+
+// Only the calling thread writes its counters, so a load and store will do
+#[inline]
+fn bump(counter:&AtomicU64, by:u64) {
+	counter.store(counter.load(Ordering::Relaxed) + by, Ordering::Relaxed);
+}
+
+#[derive(Debug)]
+struct Histogram {
+	buckets:[AtomicU64;LATENCY_BUCKETS],
+	sum_ns:AtomicU64
+}
+
+impl NewType for Histogram {
+	fn new() -> Self {
+		Histogram{buckets:[(); LATENCY_BUCKETS].map(|_| AtomicU64::new(0)), sum_ns:AtomicU64::new(0)}
+	}
+}
+
+impl Histogram {
+	fn record(&self, ns:u64) {
+		let bucket = LATENCY_BOUNDS_NS.iter().position(|bound| ns <= *bound).unwrap_or(LATENCY_BUCKETS - 1);
+		bump(&self.buckets[bucket], 1);
+		bump(&self.sum_ns, ns);
+	}
+
+	fn add_to(&self, total:&mut Latencies) {
+		for (sum, bucket) in total.buckets.iter_mut().zip(self.buckets.iter()) {
+			*sum += bucket.load(Ordering::Relaxed);
+		}
+		total.sum_ns += self.sum_ns.load(Ordering::Relaxed);
+	}
+}
+
+/**
+ * The latencies of every thread summed up. Each bucket only counts what
+ * fell in it, not what fell in the ones before.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Latencies {
+	pub buckets:[u64;LATENCY_BUCKETS],
+	pub sum_ns:u64
+}
+
+impl NewType for Latencies {
+	fn new() -> Self {
+		Latencies{buckets:[0;LATENCY_BUCKETS], sum_ns:0}
+	}
+}
+
+impl Latencies {
+	pub fn count(&self) -> u64 {
+		self.buckets.iter().sum()
+	}
+
+	// Each bucket with everything faster added in, as Prometheus has them
+	pub fn cumulative(&self) -> [u64;LATENCY_BUCKETS] {
+		let mut total = 0;
+		self.buckets.map(|count| { total += count; total })
+	}
+}
+
+#[derive(Debug)]
+struct ThreadCounts {
+	runs:[AtomicU64;CMD_KINDS],
+	errors:[AtomicU64;CMD_KINDS],
+	latency:[Histogram;CMD_KINDS],
+	op_latency:[Histogram;OP_ATOMIC_KINDS],
+	bytes_in:AtomicU64,
+	bytes_out:AtomicU64
+}
+
+impl NewType for ThreadCounts {
+	fn new() -> Self {
+		ThreadCounts{runs:[(); CMD_KINDS].map(|_| AtomicU64::new(0)),
+		             errors:[(); CMD_KINDS].map(|_| AtomicU64::new(0)),
+		             latency:[(); CMD_KINDS].map(|_| Histogram::new()),
+		             op_latency:[(); OP_ATOMIC_KINDS].map(|_| Histogram::new()),
+		             bytes_in:AtomicU64::new(0),
+		             bytes_out:AtomicU64::new(0)}
+	}
+}
+
+#[derive(Debug)]
+pub struct CmdStats {
+	threads:IntTrie<ThreadCounts>
+}
+
+impl NewType for CmdStats {
+	fn new() -> Self {
+		CmdStats{threads:IntTrie::new(8)}
+	}
+}
+
+impl CmdStats {
+	pub fn count_run(&self, cmd:u8) {
+		if let Some(counter) = self.threads.get_by_tid().runs.get(cmd as usize) {
+			bump(counter, 1);
+		}
+	}
+
+	pub fn count_error(&self, cmd:u8) {
+		if let Some(counter) = self.threads.get_by_tid().errors.get(cmd as usize) {
+			bump(counter, 1);
+		}
+	}
+
+	pub fn time_cmd(&self, cmd:u8, took:Duration) {
+		if let Some(histogram) = self.threads.get_by_tid().latency.get(cmd as usize) {
+			histogram.record(took.as_nanos() as u64);
+		}
+	}
+
+	pub fn time_op(&self, op:u16, took:Duration) {
+		if let Some(histogram) = self.threads.get_by_tid().op_latency.get(op as usize) {
+			histogram.record(took.as_nanos() as u64);
+		}
+	}
+
+	// Bytes of requests read and responses written, headers included
+	pub fn count_bytes(&self, read:usize, written:usize) {
+		let counts = self.threads.get_by_tid();
+		bump(&counts.bytes_in, read as u64);
+		bump(&counts.bytes_out, written as u64);
+	}
+
+	fn total(&self, counters:fn(&ThreadCounts) -> &[AtomicU64;CMD_KINDS], cmd:u8) -> u64 {
+		let mut total = 0;
+		self.threads.for_each(|counts| total += counters(counts)[cmd as usize].load(Ordering::Relaxed));
+		total
+	}
+
+	pub fn runs(&self, cmd:u8) -> u64 {
+		self.total(|counts| &counts.runs, cmd)
+	}
+
+	pub fn errors(&self, cmd:u8) -> u64 {
+		self.total(|counts| &counts.errors, cmd)
+	}
+
+	pub fn latency(&self, cmd:u8) -> Latencies {
+		let mut total = Latencies::new();
+		self.threads.for_each(|counts| counts.latency[cmd as usize].add_to(&mut total));
+		total
+	}
+
+	pub fn op_latency(&self, op:u16) -> Latencies {
+		let mut total = Latencies::new();
+		self.threads.for_each(|counts| counts.op_latency[op as usize].add_to(&mut total));
+		total
+	}
+
+	pub fn bytes_in(&self) -> u64 {
+		let mut total = 0;
+		self.threads.for_each(|counts| total += counts.bytes_in.load(Ordering::Relaxed));
+		total
+	}
+
+	pub fn bytes_out(&self) -> u64 {
+		let mut total = 0;
+		self.threads.for_each(|counts| total += counts.bytes_out.load(Ordering::Relaxed));
+		total
+	}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use crate::constants::*;
+
+    #[test]
+    fn names_works() {
+    	assert_eq!(CMD_NAMES[CMD_SET_KV as usize], "set_kv");
+    	assert_eq!(CMD_NAMES[CMD_SET_ALGEBRA as usize], "set_algebra");
+    	assert_eq!(CMD_NAMES[CMD_INFO as usize], "info");
+    	assert_eq!(CMD_NAMES[CMD_SLOWLOG_RESET as usize], "slowlog_reset");
+    	assert_eq!(CMD_NAMES[CMD_CONFIG_SET as usize], "config_set");
+    }
+
+    #[test]
+    fn mt_count_works() {
+    	let stats = Arc::new(CmdStats::new());
+    	let handles:Vec<_> = (0..4).map(|_| {
+    		let tstats = stats.clone();
+    		thread::spawn(move || {
+    			for i in 0..1000 {
+    				tstats.count_run(CMD_RETURN_KV);
+    				if i % 10 == 0 {
+    					tstats.count_error(CMD_RETURN_KV);
+    				}
+    			}
+    		})
+    	}).collect();
+    	for handle in handles {
+    		handle.join().unwrap();
+    	}
+    	stats.count_run(CMD_SET_KV);
+    	// not a command, so not counted
+    	stats.count_run(200);
+    	assert_eq!(stats.runs(CMD_RETURN_KV), 4000);
+    	assert_eq!(stats.errors(CMD_RETURN_KV), 400);
+    	assert_eq!(stats.runs(CMD_SET_KV), 1);
+    	assert_eq!(stats.errors(CMD_SET_KV), 0);
+    }
+
+    #[test]
+    fn latency_works() {
+    	let stats = CmdStats::new();
+    	stats.time_cmd(CMD_RETURN_KV, Duration::from_nanos(100));
+    	stats.time_cmd(CMD_RETURN_KV, Duration::from_nanos(250));
+    	stats.time_cmd(CMD_RETURN_KV, Duration::from_micros(3));
+    	stats.time_cmd(CMD_RETURN_KV, Duration::from_secs(2));
+    	stats.time_op(OP_ATOMIC_ADD, Duration::from_micros(20));
+    	// neither is a command or an op
+    	stats.time_cmd(200, Duration::from_nanos(1));
+    	stats.time_op(500, Duration::from_nanos(1));
+    	let lat = stats.latency(CMD_RETURN_KV);
+    	assert_eq!(lat.count(), 4);
+    	assert_eq!(lat.buckets[0], 2);
+    	assert_eq!(lat.buckets[2], 1);
+    	assert_eq!(lat.buckets[LATENCY_BUCKETS - 1], 1);
+    	assert_eq!(lat.sum_ns, 2_000_003_350);
+    	let cumulative = lat.cumulative();
+    	assert_eq!(cumulative[1], 2);
+    	assert_eq!(cumulative[2], 3);
+    	assert_eq!(cumulative[LATENCY_BUCKETS - 1], 4);
+    	assert_eq!(stats.latency(CMD_SET_KV).count(), 0);
+    	let op_lat = stats.op_latency(OP_ATOMIC_ADD);
+    	assert_eq!(op_lat.count(), 1);
+    	assert_eq!(op_lat.buckets[3], 1);
+    	assert_eq!(OP_ATOMIC_NAMES[OP_ATOMIC_ADD_FETCH as usize], "add_fetch");
+    }
+
+    #[test]
+    fn bytes_works() {
+    	let stats = CmdStats::new();
+    	stats.count_bytes(20, 9);
+    	stats.count_bytes(5, 0);
+    	assert_eq!(stats.bytes_in(), 25);
+    	assert_eq!(stats.bytes_out(), 9);
+    }
+}
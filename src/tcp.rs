@@ -1,10 +1,19 @@
-use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, AtomicPtr, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, AtomicUsize, AtomicPtr, Ordering};
+use std::collections::{VecDeque, HashSet};
+use std::sync::Mutex;
 use std::thread;
-use std::net::{TcpListener, TcpStream, Shutdown, ToSocketAddrs};
-use std::time::Duration;
+use std::hint;
+use std::net::{TcpListener, TcpStream, Shutdown, SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
 use std::process::exit;
 use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
 use crate::threading::{Switch, TVal, ExecUnitGroup, Parker};
+use crate::auto_scale::AutoScale;
+use crate::epoll::{Epoll, EventFd, EV_READ, EV_WRITE, EV_ONESHOT};
+use crate::errors::FlotonErr;
+use crate::responses::Response;
 use crate::traits::*;
 use crate::ports::next_port;
 use crate::logging::*;
@@ -34,8 +43,222 @@ impl<T> Clone for TcpServerContext<T> {
     }
 }
 
+// Tokens below this are reserved for the reactor's own fds, connections
+// are keyed by the address of their TcpServerStream.
+const TOKEN_LISTENER:u64 = 0;
+const TOKEN_WAKER:u64 = 1;
+const EVENT_BATCH:usize = 64;
+const READ_CHUNK:usize = 4096;
+// Size of the u64 length header in front of every request
+const HEADER_SIZE:usize = 8;
+const MIN_SWEEP_MS:u64 = 10;
+
+// Who may touch a connection. Armed connections belong to the reactor,
+// taken ones to the backlog or a worker. Parked ones are waiting to be
+// woken, and are taken by the reactor when they are.
+const CONN_ARMED:u8 = 0;
+const CONN_TAKEN:u8 = 1;
+const CONN_PARKED:u8 = 2;
+
+/**
+ * Limits applied to each connection, timeouts are in ms and 0 disables them
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct ConnLimits {
+	pub max_request_size:usize,
+	// A partially received request must complete within this
+	pub read_timeout:u64,
+	// Pending output must make progress within this
+	pub write_timeout:u64,
+	// A connection with nothing in flight is closed after this
+	pub idle_timeout:u64
+}
+
+impl NewType for ConnLimits {
+	fn new() -> Self {
+		ConnLimits{max_request_size:64 * 1024 * 1024, read_timeout:10000, write_timeout:10000, idle_timeout:300000}
+	}
+}
+
+impl ConnLimits {
+	// How often the reactor needs to look for expired connections
+	fn sweep_interval(&self) -> Option<Duration> {
+		[self.read_timeout, self.write_timeout, self.idle_timeout].iter()
+		                                                          .filter(|t| **t > 0)
+		                                                          .min()
+		                                                          .map(|t| Duration::from_millis((t / 2).max(MIN_SWEEP_MS)))
+	}
+}
+
+#[derive(Debug)]
+pub struct TcpServerStats {
+	pub queued:AtomicU64,
+	pub rejected:AtomicU64,
+	pub backlog_len:AtomicUsize,
+	pub conns:AtomicUsize,
+	pub refused:AtomicU64,
+	pub timed_out:AtomicU64,
+	pub workers:AtomicUsize,
+	// connections waiting on the workers' queue
+	pub worker_queue:AtomicUsize,
+	pub parked:AtomicUsize
+}
+
+impl NewType for TcpServerStats {
+	fn new() -> Self {
+		TcpServerStats{queued:AtomicU64::new(0),
+		               rejected:AtomicU64::new(0),
+		               backlog_len:AtomicUsize::new(0),
+		               conns:AtomicUsize::new(0),
+		               refused:AtomicU64::new(0),
+		               timed_out:AtomicU64::new(0),
+		               workers:AtomicUsize::new(0),
+		               worker_queue:AtomicUsize::new(0),
+		               parked:AtomicUsize::new(0)}
+	}
+}
+
+/**
+ * State shared between the reactor and all connections of a server.
+ * Every open connection is kept in conns, so they can be swept for
+ * timeouts and freed on shutdown. Parked connections that were woken
+ * wait in wakes, by address and park id, until the reactor takes them.
+ * New park timings and worker scaling wait in tuning the same way.
+ */
+#[derive(Debug)]
+pub struct ServerShared {
+	poller:Epoll,
+	waker:EventFd,
+	conns:Mutex<HashSet<usize>>,
+	wakes:Mutex<Vec<(usize, u64)>>,
+	park_seq:AtomicU64,
+	tuning:Mutex<Option<(Parker, AutoScale)>>,
+	retuned:AtomicBool,
+	stats:TcpServerStats,
+	limits:ConnLimits
+}
+
+impl ServerShared {
+	fn new(poller:Epoll, waker:EventFd, limits:ConnLimits) -> ServerShared {
+		ServerShared{poller,
+		             waker,
+		             conns:Mutex::new(HashSet::new()),
+		             wakes:Mutex::new(vec![]),
+		             park_seq:AtomicU64::new(1),
+		             tuning:Mutex::new(None),
+		             retuned:AtomicBool::new(false),
+		             stats:TcpServerStats::new(),
+		             limits}
+	}
+
+	// Returns false if the server already holds max connections
+	fn register(&self, conn:usize, max:usize) -> bool {
+		let mut conns = self.conns.lock().unwrap();
+		if conns.len() >= max {
+			self.stats.refused.fetch_add(1, Ordering::Relaxed);
+			return false;
+		}
+		conns.insert(conn);
+		self.stats.conns.store(conns.len(), Ordering::Relaxed);
+		true
+	}
+
+	fn unregister(&self, conn:usize) {
+		let mut conns = self.conns.lock().unwrap();
+		conns.remove(&conn);
+		self.stats.conns.store(conns.len(), Ordering::Relaxed);
+	}
+
+	// Only the latest tuning is kept, if the reactor hasn't taken one yet
+	fn take_tuning(&self) -> Option<(Parker, AutoScale)> {
+		if !self.retuned.swap(false, Ordering::Acquire) {
+			return None;
+		}
+		self.tuning.lock().unwrap().take()
+	}
+}
+
+/**
+ * A request a handler stopped part way through, to carry on with once
+ * its connection is woken. Nothing after it on the connection is read
+ * until then, so responses stay in order.
+ */
+#[derive(Debug)]
+pub struct HeldRequest {
+	pub body:Vec<u8>,
+	// Output so far, sent as one response once the request finishes
+	pub output:Vec<u8>,
+	pub place:usize,
+	// The connection is woken by then even if nothing else wakes it
+	pub until:Option<Instant>
+}
+
+/**
+ * Per connection buffers, so a request or response that can't be
+ * fully read or written is resumed when the socket is ready again
+ */
 #[derive(Debug)]
-pub struct TcpServerStream<T>(pub TcpStream, TcpServerContext<T> /*Context type*/);
+pub struct StreamState<T> {
+	rbuf:Vec<u8>,
+	wbuf:Vec<u8>,
+	wpos:usize,
+	peer_closed:bool,
+	closing:bool,
+	failed:bool,
+	state:AtomicU8,
+	last_active:Instant,
+	last_write:Instant,
+	read_start:Option<Instant>,
+	held:Option<HeldRequest>,
+	// Non zero while parked, so stale wakes can be told apart
+	park_id:AtomicU64,
+	// kept from accept, so it's there for as long as the connection
+	peer:Option<SocketAddr>,
+	shared:TVal<ServerShared>,
+	handler:fn(*mut TcpServerStream<T>)
+}
+
+impl<T> StreamState<T> {
+	fn new(shared:TVal<ServerShared>, handler:fn(*mut TcpServerStream<T>), peer:Option<SocketAddr>) -> StreamState<T> {
+		let now = Instant::now();
+		StreamState{rbuf:Vec::new(),
+		            wbuf:Vec::new(),
+		            wpos:0,
+		            peer_closed:false,
+		            closing:false,
+		            failed:false,
+		            state:AtomicU8::new(CONN_ARMED),
+		            last_active:now,
+		            last_write:now,
+		            read_start:None,
+		            held:None,
+		            park_id:AtomicU64::new(0),
+		            peer,
+		            shared,
+		            handler}
+	}
+}
+
+/**
+ * Wakes a parked connection, from any thread. Waking a connection that
+ * has since been woken, parked again, or closed does nothing.
+ */
+#[derive(Debug, Clone)]
+pub struct StreamWaker {
+	conn:usize,
+	id:u64,
+	shared:TVal<ServerShared>
+}
+
+impl Wake for StreamWaker {
+	fn wake(&self) {
+		self.shared.wakes.lock().unwrap().push((self.conn, self.id));
+		self.shared.waker.wake();
+	}
+}
+
+#[derive(Debug)]
+pub struct TcpServerStream<T>(pub TcpStream, TcpServerContext<T> /*Context type*/, StreamState<T>);
 
 impl<T> TcpServerStream<T> {
 	#[inline]
@@ -47,6 +270,366 @@ impl<T> TcpServerStream<T> {
 	pub fn get_ptr(&self) -> *mut T {
 		self.1.get()
 	}
+
+	#[inline]
+	pub fn peer(&self) -> Option<SocketAddr> {
+		self.2.peer
+	}
+
+	#[inline]
+	pub fn limits(&self) -> &ConnLimits {
+		&self.2.shared.limits
+	}
+
+	// Bytes received but not yet consumed by the handler
+	#[inline]
+	pub fn read_buf(&mut self) -> &mut Vec<u8> {
+		&mut self.2.rbuf
+	}
+
+	// Queues bytes to be written back, they are flushed after the handler returns
+	#[inline]
+	pub fn send(&mut self, data:&[u8]) {
+		self.2.wbuf.extend_from_slice(data);
+	}
+
+	// Stops taking input, the connection is closed once queued output is written
+	pub fn finish(&mut self) {
+		self.2.closing = true;
+		self.2.rbuf.clear();
+	}
+
+	// Holds req until the returned waker is woken, or its until passes.
+	// The connection is parked once the handler returns, it isn't given
+	// to a worker or read from until then.
+	pub fn park(&mut self, req:HeldRequest) -> StreamWaker {
+		let id = self.2.shared.park_seq.fetch_add(1, Ordering::Relaxed);
+		self.2.held = Some(req);
+		self.2.park_id.store(id, Ordering::Release);
+		StreamWaker{conn:self as *mut TcpServerStream<T> as usize, id, shared:self.2.shared.clone()}
+	}
+
+	// The request held by park, once the connection is woken
+	pub fn take_held(&mut self) -> Option<HeldRequest> {
+		self.2.held.take()
+	}
+
+	pub fn is_closed(&self) -> bool {
+		self.2.failed || ((self.2.peer_closed || self.2.closing) && !self.has_pending())
+	}
+
+	#[inline]
+	fn has_pending(&self) -> bool {
+		self.2.wpos < self.2.wbuf.len()
+	}
+
+	// Reads what is available without blocking, up to room for one
+	// request of the maximum size. The rest is left in the socket.
+	fn fill(&mut self) {
+		let mut chunk = [0;READ_CHUNK];
+		let cap = self.limits().max_request_size + HEADER_SIZE;
+		while self.2.rbuf.len() < cap {
+			match self.0.read(&mut chunk) {
+				Ok(0) => { self.2.peer_closed = true; break; },
+				// a finishing connection only drains its input
+				Ok(_) if self.2.closing => (),
+				Ok(n) => self.2.rbuf.extend_from_slice(&chunk[..n]),
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+				Err(e) => {
+					log_debug!(Tcp, "Read failed on connection, err: {}", e);
+					self.2.failed = true;
+					break;
+				}
+			}
+		}
+	}
+
+	// Writes as much queued output as the socket accepts without blocking
+	fn flush(&mut self) {
+		while self.has_pending() {
+			match self.0.write(&self.2.wbuf[self.2.wpos..]) {
+				Ok(0) => { self.2.failed = true; return; },
+				Ok(n) => {
+					self.2.wpos += n;
+					self.2.last_write = Instant::now();
+				},
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+				Err(e) => {
+					log_debug!(Tcp, "Write failed on connection, err: {}", e);
+					self.2.failed = true;
+					return;
+				}
+			}
+		}
+		self.2.wbuf.clear();
+		self.2.wpos = 0;
+	}
+
+	// Hands the connection back to the reactor, waiting for input, or
+	// for the socket to drain if output is still pending. No more input
+	// is taken until then, so a client that doesn't read can't grow the
+	// output without bound. On success, the stream must not be touched afterwards.
+	fn rearm(&self, token:u64) -> bool {
+		let events = if self.2.peer_closed || self.2.closing || self.has_pending() {
+			EV_WRITE | EV_ONESHOT
+		} else {
+			EV_READ | EV_ONESHOT
+		};
+		match self.2.shared.poller.modify(self.0.as_raw_fd(), token, events) {
+			Ok(_) => {
+				self.2.state.store(CONN_ARMED, Ordering::Release);
+				true
+			},
+			Err(e) => {
+				log_error!(Tcp, "Could not re-arm connection, err: {}", e);
+				false
+			}
+		}
+	}
+
+	// Takes an armed connection from the reactor side. A worker may still
+	// be finishing its re-arm, which is only a few instructions.
+	fn take(ptr:*mut TcpServerStream<T>) {
+		let state = &ptref!(ptr).2.state;
+		while state.compare_exchange(CONN_ARMED, CONN_TAKEN, Ordering::AcqRel, Ordering::Relaxed).is_err() {
+			hint::spin_loop();
+		}
+	}
+
+	// Which limit the connection has gone past, if any
+	fn expired(&self, now:Instant) -> Option<FlotonErr> {
+		let limits = self.limits();
+		let past = |since:Instant, limit:u64| limit > 0 && now.duration_since(since) > Duration::from_millis(limit);
+		if self.has_pending() {
+			if past(self.2.last_write, limits.write_timeout) { Some(FlotonErr::WriteTimeout) } else { None }
+		} else if let Some(start) = self.2.read_start {
+			if past(start, limits.read_timeout) { Some(FlotonErr::ReadTimeout) } else { None }
+		} else if past(self.2.last_active, limits.idle_timeout) {
+			Some(FlotonErr::IdleTimeout)
+		} else {
+			None
+		}
+	}
+
+	fn close(ptr:*mut TcpServerStream<T>) {
+		let stream = ptref!(ptr);
+		if let Err(e) = stream.2.shared.poller.delete(stream.0.as_raw_fd()) {
+			log_debug!(Tcp, "Could not deregister connection, err: {}", e);
+		}
+		stream.2.shared.unregister(ptr as usize);
+		free!(ptr);
+	}
+
+	// Sends the client an error before closing, without waiting on the socket.
+	fn reject(ptr:*mut TcpServerStream<T>, err:FlotonErr) {
+		let stream = unsafe { ptr.as_mut().unwrap() };
+		// unread input would make the close reset the connection, losing the reply
+		stream.fill();
+		Response::error(err).to_server_stream(stream);
+		stream.flush();
+		TcpServerStream::close(ptr);
+	}
+
+	// The function run by the ExecUnit workers. The reactor guarantees only
+	// one worker holds a connection at a time, so it has exclusive access
+	// until it is re-armed.
+	fn serve(ptr:*mut TcpServerStream<T>) {
+		let (handler, received, had_pending) = unsafe {
+			let stream = ptr.as_mut().unwrap();
+			stream.fill();
+			(stream.2.handler, stream.2.rbuf.len(), stream.has_pending())
+		};
+		handler(ptr);
+		let stream = unsafe { ptr.as_mut().unwrap() };
+		let now = Instant::now();
+		stream.2.last_active = now;
+		let left = stream.2.rbuf.len();
+		// The read timeout runs from when the first part of a request arrived
+		if left == 0 {
+			stream.2.read_start = None;
+		} else if left < received || stream.2.read_start.is_none() {
+			stream.2.read_start = Some(now);
+		}
+		// The write timeout runs from the last progress on pending output
+		if !had_pending {
+			stream.2.last_write = now;
+		}
+		stream.flush();
+		if stream.is_closed() {
+			TcpServerStream::close(ptr);
+		} else if stream.2.held.is_some() {
+			// Pending output is written once the connection is woken. The
+			// reactor is woken too, to start checking when the wait runs out.
+			stream.2.shared.stats.parked.fetch_add(1, Ordering::Relaxed);
+			stream.2.shared.waker.wake();
+			stream.2.state.store(CONN_PARKED, Ordering::Release);
+		} else if !stream.rearm(ptr as u64) {
+			TcpServerStream::close(ptr);
+		}
+	}
+
+	// Takes a parked connection for the reactor, it stops being parked
+	fn unpark(ptr:*mut TcpServerStream<T>) -> bool {
+		let stream = ptref!(ptr);
+		if stream.2.state.compare_exchange(CONN_PARKED, CONN_TAKEN, Ordering::AcqRel, Ordering::Relaxed).is_err() {
+			return false;
+		}
+		stream.2.park_id.store(0, Ordering::Release);
+		stream.2.shared.stats.parked.fetch_sub(1, Ordering::Relaxed);
+		true
+	}
+
+	// Takes the woken connections, only called by the reactor. One woken
+	// while its worker is still parking it is retried on the next wait.
+	fn take_woken(shared:&ServerShared) -> Vec<*mut TcpServerStream<T>> {
+		let woken = mem::take(&mut *shared.wakes.lock().unwrap());
+		let mut taken = vec![];
+		let mut retry = vec![];
+		{
+			let conns = shared.conns.lock().unwrap();
+			for (addr, id) in woken {
+				let conn = addr as *mut TcpServerStream<T>;
+				if !conns.contains(&addr) || ptref!(conn).2.park_id.load(Ordering::Acquire) != id {
+					continue;
+				}
+				if TcpServerStream::unpark(conn) {
+					taken.push(conn);
+				} else {
+					retry.push((addr, id));
+				}
+			}
+		}
+		if !retry.is_empty() {
+			shared.wakes.lock().unwrap().extend(retry);
+			shared.waker.wake();
+		}
+		taken
+	}
+
+	// Takes parked connections whose wait has run out, only called by the reactor
+	fn take_expired_parks(shared:&ServerShared, now:Instant) -> Vec<*mut TcpServerStream<T>> {
+		let conns = shared.conns.lock().unwrap();
+		conns.iter().map(|addr| *addr as *mut TcpServerStream<T>)
+		            .filter(|conn| {
+		            	let stream = ptref!(*conn);
+		            	stream.2.state.load(Ordering::Acquire) == CONN_PARKED
+		            	&& matches!(stream.2.held, Some(HeldRequest{until:Some(until), ..}) if until <= now)
+		            })
+		            .filter(|conn| TcpServerStream::unpark(*conn))
+		            .collect()
+	}
+
+	// Closes armed connections past their limits, only called by the reactor
+	fn sweep(shared:&ServerShared) {
+		let now = Instant::now();
+		let mut expired = vec![];
+		{
+			let conns = shared.conns.lock().unwrap();
+			for addr in conns.iter() {
+				let conn = *addr as *mut TcpServerStream<T>;
+				let stream = ptref!(conn);
+				if stream.2.state.compare_exchange(CONN_ARMED, CONN_TAKEN, Ordering::AcqRel, Ordering::Relaxed).is_err() {
+					continue;
+				}
+				match stream.expired(now) {
+					Some(err) => expired.push((conn, err)),
+					None => stream.2.state.store(CONN_ARMED, Ordering::Release)
+				}
+			}
+		}
+		for (conn, err) in expired {
+			log_debug!(Tcp, "Closing connection from {:?}, {:?}", ptref!(conn).0.peer_addr(), err);
+			shared.stats.timed_out.fetch_add(1, Ordering::Relaxed);
+			TcpServerStream::reject(conn, err);
+		}
+	}
+
+	// Frees every connection, only safe once the workers have stopped
+	fn close_all(shared:&ServerShared) {
+		let conns:Vec<usize> = shared.conns.lock().unwrap().iter().cloned().collect();
+		for addr in conns {
+			TcpServerStream::close(addr as *mut TcpServerStream<T>);
+		}
+	}
+}
+
+/**
+ * Options for how the server behaves under load
+ */
+#[derive(Debug, Clone)]
+pub struct TcpServerOpts {
+	pub parker:Parker,
+	// How many ready connections may wait for a free worker
+	pub backlog:usize,
+	// Hint, in ms, sent to clients rejected for being too busy
+	pub retry_after:u32,
+	pub max_conns:usize,
+	pub limits:ConnLimits,
+	pub scale:AutoScale
+}
+
+impl NewType for TcpServerOpts {
+	fn new() -> Self {
+		TcpServerOpts{parker:Parker::new(0, 1000, 50), backlog:128, retry_after:100, max_conns:10000, limits:ConnLimits::new(), scale:AutoScale::new()}
+	}
+}
+
+/**
+ * Ready connections that could not be given to a worker yet. Only
+ * used from the reactor thread.
+ */
+#[derive(Debug)]
+struct ConnBacklog<T> {
+	queue:VecDeque<*mut T>,
+	cap:usize
+}
+
+impl<T> ConnBacklog<T> {
+	fn new(cap:usize) -> ConnBacklog<T> {
+		ConnBacklog{queue:VecDeque::with_capacity(cap), cap}
+	}
+
+	// Returns false if the backlog is full
+	fn push(&mut self, conn:*mut T, stats:&TcpServerStats) -> bool {
+		if self.queue.len() >= self.cap {
+			stats.rejected.fetch_add(1, Ordering::Relaxed);
+			return false;
+		}
+		self.queue.push_back(conn);
+		stats.queued.fetch_add(1, Ordering::Relaxed);
+		stats.backlog_len.store(self.queue.len(), Ordering::Relaxed);
+		true
+	}
+
+	// Hands out connections in arrival order until assign fails
+	fn drain(&mut self, assign:impl Fn(*mut T) -> bool, stats:&TcpServerStats) {
+		while let Some(conn) = self.queue.front() {
+			if !assign(*conn) {
+				break;
+			}
+			self.queue.pop_front();
+		}
+		stats.backlog_len.store(self.queue.len(), Ordering::Relaxed);
+	}
+
+	fn is_empty(&self) -> bool {
+		self.queue.is_empty()
+	}
+}
+
+// Gives a taken connection to a worker, or to the backlog if none is free
+fn dispatch<T: 'static>(conn:*mut TcpServerStream<T>,
+	                    egroup:&ExecUnitGroup<TcpServerStream<T>>,
+	                    backlog:&mut ConnBacklog<TcpServerStream<T>>,
+	                    stats:&TcpServerStats,
+	                    retry_after:u32) {
+	let waiting = !backlog.is_empty() || egroup.assign_retried(conn, 10).is_none();
+	if waiting && !backlog.push(conn, stats) {
+		log_warn!(Tcp, "Too busy to handle connection from {:?}", ptref!(conn).0.peer_addr());
+		TcpServerStream::reject(conn, FlotonErr::ServerBusy(retry_after));
+	}
 }
 
 #[derive(Debug)]
@@ -56,6 +639,7 @@ pub struct TcpServer<T> {
 	core:TcpListener,
 	ready:Switch,
 	shutter:Switch,
+	shared:TVal<ServerShared>,
 	acceptor:Option<thread::JoinHandle<()>>,
 	context:TcpServerContext<T>
 }
@@ -65,14 +649,14 @@ impl<T: 'static> TcpServer<T> {
 		       th_qsize:usize, 
 		       addr:&String, 
 		       port:u16, 
-		       parker:&Parker, 
+		       opts:&TcpServerOpts, 
 		       func:fn(*mut TcpServerStream<T>),
 		       context:TcpServerContext<T>) -> TcpServer<T> {
 		let ready = Switch::new();
 		let rswitch = ready.clone();
 		let shut = Switch::new();
 		let tshut = shut.clone();
-		let mut egroup = ExecUnitGroup::new(init_th_count, th_qsize, func);
+		let mut egroup = ExecUnitGroup::with_scale(init_th_count, th_qsize, TcpServerStream::<T>::serve, opts.scale);
 		let listener = match TcpListener::bind((addr.as_str(), port)) {
 			Ok(l) => l,
 			Err(_) => {
@@ -82,51 +666,133 @@ impl<T: 'static> TcpServer<T> {
 		};
 		log_info!(Tcp, "Will listen for connections on port {} at address: {}", port, addr);
 		let tlistener = listener.try_clone().unwrap();
-		match tlistener.set_nonblocking(true) {
-			Err(e) => log_fatal!(Tcp, "Could not set non-blocking mode for tcp server, got {}", e),
-			_ => ()
+		if let Err(e) = tlistener.set_nonblocking(true) {
+			log_fatal!(Tcp, "Could not set non-blocking mode for tcp server, got {}", e);
+		}
+		let shared = match (Epoll::new(), EventFd::new()) {
+			(Ok(p), Ok(w)) => TVal::new(ServerShared::new(p, w, opts.limits)),
+			(Err(e), _) | (_, Err(e)) => {
+				log_fatal!(Tcp, "Could not create the tcp reactor, got {}", e);
+				exit(1);
+			}
+		};
+		if let Err(e) = shared.poller.add(tlistener.as_raw_fd(), TOKEN_LISTENER, EV_READ)
+		                             .and(shared.poller.add(shared.waker.fd(), TOKEN_WAKER, EV_READ)) {
+			log_fatal!(Tcp, "Could not register the tcp listener, got {}", e);
+			exit(1);
 		}
+		let tshared = shared.clone();
 		let tcontext = context.clone();
-		let mut tparker = parker.clone();
+		let mut tparker = opts.parker.clone();
+		let retry_after = opts.retry_after;
+		let max_conns = opts.max_conns;
+		let backlog_cap = opts.backlog;
+		// Timeouts and idle workers are both checked on a periodic tick
+		let sweep_every = opts.limits.sweep_interval();
+		let tick_every_for = move |shrink_every:Option<Duration>| match (sweep_every, shrink_every) {
+			(Some(a), Some(b)) => Some(a.min(b)),
+			(a, b) => a.or(b)
+		};
+		let mut tick_every = tick_every_for(egroup.shrink_interval());
 		let handle = thread::spawn(move || {
 			while !rswitch.get() {
 				thread::park_timeout(Duration::from_millis(500));
 			};
+			let mut events = Vec::with_capacity(EVENT_BATCH);
+			let mut backlog = ConnBacklog::new(backlog_cap);
+			let mut last_tick = Instant::now();
+			let mut last_park_check = Instant::now();
 			loop {
 				if tshut.get() {
 					//shutdown logic
 					egroup.stop_all();
+					TcpServerStream::<T>::close_all(&tshared);
 					break;
 				}
-				match tlistener.accept() {
-					Ok((_socket, client_addr)) => {
-						log_trace!(Tcp, "Got connection from {}", client_addr);
-						let req = alloc!(TcpServerStream(_socket, tcontext.clone()));
-						match egroup.assign_retried(req, 10) {
-							None => {
-								// can't handle it
-								log_warn!(Tcp, "Too busy to handle connection from {}", client_addr);
-								free!(req);
-								tparker.do_park(false);
-							},
-							Some(_) => {
-								tparker.do_park(true);
+				if let Some((parker, scale)) = tshared.take_tuning() {
+					tparker = parker;
+					egroup.set_scale(scale);
+					tick_every = tick_every_for(egroup.shrink_interval());
+				}
+				// Connections waiting on a worker go first, and while any are
+				// waiting, the poll times out with a backoff to retry them.
+				backlog.drain(|conn| egroup.assign_ptr(conn).is_some(), &tshared.stats);
+				// Parked connections go back to a worker once woken, or once
+				// their wait runs out, which is checked every MIN_SWEEP_MS.
+				let parked = tshared.stats.parked.load(Ordering::Relaxed) > 0;
+				let mut resumed = TcpServerStream::<T>::take_woken(&tshared);
+				if parked && last_park_check.elapsed() >= Duration::from_millis(MIN_SWEEP_MS) {
+					resumed.extend(TcpServerStream::<T>::take_expired_parks(&tshared, Instant::now()));
+					last_park_check = Instant::now();
+				}
+				for conn in resumed {
+					dispatch(conn, &egroup, &mut backlog, &tshared.stats, retry_after);
+				}
+				let mut timeout = if backlog.is_empty() { tparker.reset(); -1 } else { tparker.next_wait(false) as i32 };
+				if let Some(every) = tick_every {
+					if last_tick.elapsed() >= every {
+						TcpServerStream::<T>::sweep(&tshared);
+						egroup.shrink_idle();
+						last_tick = Instant::now();
+					}
+					let until_tick = every.saturating_sub(last_tick.elapsed()).as_millis() as i32;
+					timeout = if timeout < 0 { until_tick } else { timeout.min(until_tick) };
+				}
+				if parked {
+					timeout = if timeout < 0 { MIN_SWEEP_MS as i32 } else { timeout.min(MIN_SWEEP_MS as i32) };
+				}
+				tshared.stats.workers.store(egroup.workers(), Ordering::Relaxed);
+				tshared.stats.worker_queue.store(egroup.queued(), Ordering::Relaxed);
+				if let Err(e) = tshared.poller.wait(&mut events, timeout) {
+					log_error!(Tcp, "Failed to wait on the tcp reactor, got {}", e);
+					continue;
+				}
+				for event in events.iter() {
+					match event.u64 {
+						TOKEN_WAKER => tshared.waker.drain(),
+						TOKEN_LISTENER => loop {
+							match tlistener.accept() {
+								Ok((socket, client_addr)) => {
+									log_trace!(Tcp, "Got connection from {}", client_addr);
+									if let Err(e) = socket.set_nonblocking(true) {
+										log_error!(Tcp, "Could not set non-blocking mode for {}, got {}", client_addr, e);
+										continue;
+									}
+									let fd = socket.as_raw_fd();
+									let conn = alloc!(TcpServerStream(socket, tcontext.clone(), StreamState::new(tshared.clone(), func, Some(client_addr))));
+									if !tshared.register(conn as usize, max_conns) {
+										log_warn!(Tcp, "Refusing connection from {}, at the limit of {} connections", client_addr, max_conns);
+										TcpServerStream::reject(conn, FlotonErr::TooManyConnections);
+										continue;
+									}
+									if let Err(e) = tshared.poller.add(fd, conn as u64, EV_READ | EV_ONESHOT) {
+										log_error!(Tcp, "Could not register connection from {}, got {}", client_addr, e);
+										TcpServerStream::close(conn);
+									}
+								},
+								Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+								Err(e) => {
+									log_error!(Tcp, "Got error from socket {:?}", e);
+									break;
+								}
 							}
+						},
+						token => {
+							let conn = token as *mut TcpServerStream<T>;
+							TcpServerStream::take(conn);
+							dispatch(conn, &egroup, &mut backlog, &tshared.stats, retry_after);
 						}
-					},
-					Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-						tparker.do_park(false);
-					},
-					Err(e) => println!("Got error from socket {:?}", e)
+					}
 				}
 			}
 		});
 		TcpServer{
-			port:port,
+			port,
 			addr:addr.clone(),
 			core:listener,
-			ready:ready,
+			ready,
 			shutter:shut,
+			shared,
 			acceptor:Some(handle),
 			context:context.clone()
 		}
@@ -135,18 +801,31 @@ impl<T: 'static> TcpServer<T> {
 	pub fn start(&self) {
 		assert!(!self.ready.get());
 		self.ready.set(true);
+		self.acceptor.as_ref().unwrap().thread().unpark();
 	}
 
 	pub fn stop(&mut self) {
 		assert!(self.ready.get());
 		assert!(!self.shutter.get());
 		self.shutter.set(true);
+		self.shared.waker.wake();
 		self.acceptor.take().unwrap().join().unwrap();
 	}
 
 	pub fn is_ready(&self) -> bool {
 		self.ready.get()
 	}
+
+	// New park timings and worker scaling, picked up once the reactor wakes
+	pub fn tune(&self, parker:Parker, scale:AutoScale) {
+		*self.shared.tuning.lock().unwrap() = Some((parker, scale));
+		self.shared.retuned.store(true, Ordering::Release);
+		self.shared.waker.wake();
+	}
+
+	pub fn stats(&self) -> &TcpServerStats {
+		&self.shared.stats
+	}
 }
 
 /**
@@ -191,30 +870,19 @@ pub fn send_receive<T: ToSocketAddrs>(dest:T, content:&[u8]) -> Result<Vec<u8>,
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::ptr;
+    use std::convert::TryInto;
+    use crate::ports::next_local_addr;
+    use crate::requests::Request;
 
     struct Context(u8);
 
     fn do_echo(obj:*mut TcpServerStream<Context>) {
-    	unsafe {
-    		let mut buf = [0;4];
-    		let robj = obj.as_mut().unwrap();
-    		loop {
-	    		match robj.0.read_exact(&mut buf) {
-	    			Ok(_) => break,
-	    			Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::park_timeout(Duration::from_millis(5)),
-	    			Err(e) => panic!("Got Error on tcp {:?}", e)
-	    		}
-    		}
-
-    		loop {
-	    		match robj.0.write_all(&buf) {
-	    			Ok(_) => break,
-	    			Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::park_timeout(Duration::from_millis(5)),
-	    			Err(e) => panic!("Got Error on tcp {:?}", e)
-	    		}
-    		}
+    	let robj = unsafe { obj.as_mut().unwrap() };
+    	while robj.read_buf().len() >= 4 {
+    		let echoed:Vec<u8> = robj.read_buf().drain(0..4).collect();
+    		robj.send(&echoed);
     	}
-    	free!(obj);
     }
 
     struct Stream(TcpStream);
@@ -240,9 +908,10 @@ mod tests {
     	logging_test_set(LOG_LEVEL_INFO);
         let serv_addr = String::from("127.0.0.1");
         let serv_port = next_port();
-        let pker = Parker::new(5, 200, 15);
+        let mut opts = TcpServerOpts::new();
+        opts.parker = Parker::new(5, 200, 15);
         let cxt = alloc!(Context(8));
-        let mut server = TcpServer::<Context>::new(3, 5, &serv_addr, serv_port, &pker, do_echo, TcpServerContext::new(cxt));
+        let mut server = TcpServer::<Context>::new(3, 5, &serv_addr, serv_port, &opts, do_echo, TcpServerContext::new(cxt));
         server.start();
         let mut bits = [0;4];
         let mut resp = [0;4];
@@ -267,9 +936,10 @@ mod tests {
     	logging_test_set(LOG_LEVEL_INFO);
         let serv_addr = String::from("127.0.0.1");
         let serv_port = next_port();
-        let pker = Parker::new(5, 200, 15);
+        let mut opts = TcpServerOpts::new();
+        opts.parker = Parker::new(5, 200, 15);
         let cxt = alloc!(Context(8));
-        let mut server = TcpServer::<Context>::new(3, 5, &serv_addr, serv_port, &pker, do_echo, TcpServerContext::new(cxt));
+        let mut server = TcpServer::<Context>::new(3, 5, &serv_addr, serv_port, &opts, do_echo, TcpServerContext::new(cxt));
         server.start();
         while !server.is_ready() {
         	thread::yield_now();
@@ -288,6 +958,191 @@ mod tests {
         free!(cxt);
     }
 
+    #[test]
+    fn persistent_echo_works() {
+    	logging_test_set(LOG_LEVEL_INFO);
+        let serv_addr = String::from("127.0.0.1");
+        let serv_port = next_port();
+        let mut opts = TcpServerOpts::new();
+        opts.parker = Parker::new(5, 200, 15);
+        let cxt = alloc!(Context(8));
+        let mut server = TcpServer::<Context>::new(2, 5, &serv_addr, serv_port, &opts, do_echo, TcpServerContext::new(cxt));
+        server.start();
+        let mut sock = TcpStream::connect((serv_addr.as_str(), serv_port)).unwrap();
+        let mut resp = [0;4];
+        for i in 0..5 {
+        	// split writes arrive as separate events, the server has to wait for the rest
+        	sock.write_all(&[i, 1]).expect("Could not do the first write");
+        	sock.flush().unwrap();
+        	thread::sleep(Duration::from_millis(10));
+        	sock.write_all(&[2, 3]).expect("Could not do the second write");
+        	sock.read_exact(&mut resp).expect("Could not do the read");
+        	assert_eq!(resp, [i, 1, 2, 3]);
+        }
+        server.stop();
+        free!(cxt);
+    }
+
+    #[test]
+    fn backlog_bounds_works() {
+    	let stats = TcpServerStats::new();
+    	let mut backlog = ConnBacklog::<u32>::new(2);
+    	let (a, b, c) = (alloc!(1), alloc!(2), alloc!(3));
+    	assert!(backlog.push(a, &stats));
+    	assert!(backlog.push(b, &stats));
+    	assert!(!backlog.push(c, &stats));
+    	assert_eq!(stats.queued.load(Ordering::Relaxed), 2);
+    	assert_eq!(stats.rejected.load(Ordering::Relaxed), 1);
+    	assert_eq!(stats.backlog_len.load(Ordering::Relaxed), 2);
+    	// only the first is taken, order is kept
+    	backlog.drain(|conn| unsafe { *conn == 1 }, &stats);
+    	assert_eq!(stats.backlog_len.load(Ordering::Relaxed), 1);
+    	assert_eq!(backlog.queue[0], b);
+    	backlog.drain(|_| true, &stats);
+    	assert!(backlog.is_empty());
+    	free!(a);
+    	free!(b);
+    	free!(c);
+    }
+
+    fn read_error(sock:&mut TcpStream) -> FlotonErr {
+    	let mut resp = vec![];
+    	sock.read_to_end(&mut resp).expect("Could not read error response");
+    	assert_eq!(u64::from_le_bytes(resp[0..8].try_into().unwrap()) as usize, resp.len() - 8);
+    	let mut i = 8;
+    	FlotonErr::input_binary(&resp, &mut i).expect("Response was not an error")
+    }
+
+    fn start_server(opts:&TcpServerOpts, func:fn(*mut TcpServerStream<Context>)) -> (TcpServer<Context>, u16, *mut Context) {
+        let serv_port = next_port();
+        let cxt = alloc!(Context(8));
+        let server = TcpServer::<Context>::new(2, 5, &String::from("127.0.0.1"), serv_port, opts, func, TcpServerContext::new(cxt));
+        server.start();
+        (server, serv_port, cxt)
+    }
+
+    #[test]
+    fn reject_sends_busy_works() {
+    	let serv_addr = next_local_addr();
+    	let listener = TcpListener::bind(serv_addr).unwrap();
+    	let mut client = TcpStream::connect(serv_addr).unwrap();
+    	client.write_all(&[1, 2, 3]).unwrap();
+    	let (socket, _) = listener.accept().unwrap();
+    	socket.set_nonblocking(true).unwrap();
+    	let shared = TVal::new(ServerShared::new(Epoll::new().unwrap(), EventFd::new().unwrap(), ConnLimits::new()));
+    	shared.poller.add(socket.as_raw_fd(), 5, EV_READ | EV_ONESHOT).unwrap();
+    	let conn = alloc!(TcpServerStream(socket, TcpServerContext::new(ptr::null_mut::<Context>()), StreamState::new(shared.clone(), do_echo, None)));
+    	assert!(shared.register(conn as usize, 1));
+    	TcpServerStream::reject(conn, FlotonErr::ServerBusy(300));
+    	assert_eq!(shared.stats.conns.load(Ordering::Relaxed), 0);
+    	match read_error(&mut client) {
+    		FlotonErr::ServerBusy(retry) => assert_eq!(retry, 300),
+    		other => panic!("Expected server busy error, got {:?}", other)
+    	}
+    }
+
+    #[test]
+    fn max_conns_works() {
+    	logging_test_set(LOG_LEVEL_INFO);
+        let mut opts = TcpServerOpts::new();
+        opts.max_conns = 1;
+        let (mut server, serv_port, cxt) = start_server(&opts, do_echo);
+        let mut first = TcpStream::connect(("127.0.0.1", serv_port)).unwrap();
+        Stream(first.try_clone().unwrap()).readwrite();
+        let mut second = TcpStream::connect(("127.0.0.1", serv_port)).unwrap();
+        assert!(matches!(read_error(&mut second), FlotonErr::TooManyConnections));
+        assert_eq!(server.stats().refused.load(Ordering::Relaxed), 1);
+        assert_eq!(server.stats().conns.load(Ordering::Relaxed), 1);
+        // a slot frees up once the first goes away
+        first.shutdown(Shutdown::Both).unwrap();
+        let mut buf = [0;1];
+        assert_eq!(first.read(&mut buf).unwrap(), 0);
+        while server.stats().conns.load(Ordering::Relaxed) > 0 {
+        	thread::yield_now();
+        }
+        Stream(TcpStream::connect(("127.0.0.1", serv_port)).unwrap()).readwrite();
+        server.stop();
+        free!(cxt);
+    }
+
+    #[test]
+    fn idle_timeout_works() {
+    	logging_test_set(LOG_LEVEL_INFO);
+        let mut opts = TcpServerOpts::new();
+        opts.limits.idle_timeout = 50;
+        let (mut server, serv_port, cxt) = start_server(&opts, do_echo);
+        let mut sock = TcpStream::connect(("127.0.0.1", serv_port)).unwrap();
+        Stream(sock.try_clone().unwrap()).readwrite();
+        assert!(matches!(read_error(&mut sock), FlotonErr::IdleTimeout));
+        assert_eq!(server.stats().timed_out.load(Ordering::Relaxed), 1);
+        server.stop();
+        free!(cxt);
+    }
+
+    #[test]
+    fn read_timeout_works() {
+    	logging_test_set(LOG_LEVEL_INFO);
+        let mut opts = TcpServerOpts::new();
+        opts.limits.read_timeout = 50;
+        let (mut server, serv_port, cxt) = start_server(&opts, do_echo);
+        let mut sock = TcpStream::connect(("127.0.0.1", serv_port)).unwrap();
+        // never completes the 4 bytes the echo waits for
+        sock.write_all(&[1, 2]).unwrap();
+        assert!(matches!(read_error(&mut sock), FlotonErr::ReadTimeout));
+        server.stop();
+        free!(cxt);
+    }
+
+    fn do_requests(obj:*mut TcpServerStream<Context>) {
+    	let robj = unsafe { obj.as_mut().unwrap() };
+    	while let Some(req) = Request::parse(robj) {
+    		Response::from_vec(req.body).to_server_stream(robj);
+    	}
+    }
+
+    #[test]
+    fn request_too_large_works() {
+    	logging_test_set(LOG_LEVEL_INFO);
+        let mut opts = TcpServerOpts::new();
+        opts.limits.max_request_size = 16;
+        let (mut server, serv_port, cxt) = start_server(&opts, do_requests);
+        assert_eq!(send_receive(("127.0.0.1", serv_port), &[3;16]).unwrap(), vec![3;16]);
+        let mut sock = TcpStream::connect(("127.0.0.1", serv_port)).unwrap();
+        sock.write_all(&(1u64 << 60).to_le_bytes()).unwrap();
+        match read_error(&mut sock) {
+        	FlotonErr::RequestTooLarge(max) => assert_eq!(max, 16),
+        	other => panic!("Expected request too large error, got {:?}", other)
+        }
+        server.stop();
+        free!(cxt);
+    }
+
+    #[test]
+    fn reports_workers_works() {
+    	logging_test_set(LOG_LEVEL_INFO);
+        let mut opts = TcpServerOpts::new();
+        opts.scale.max_workers = 4;
+        opts.scale.idle_shrink = 20;
+        let (mut server, serv_port, cxt) = start_server(&opts, do_echo);
+        Stream(TcpStream::connect(("127.0.0.1", serv_port)).unwrap()).readwrite();
+        assert_eq!(server.stats().workers.load(Ordering::Relaxed), 2);
+        server.stop();
+        free!(cxt);
+    }
+
+    #[test]
+    fn shutdown_frees_conns_works() {
+    	logging_test_set(LOG_LEVEL_INFO);
+        let (mut server, serv_port, cxt) = start_server(&TcpServerOpts::new(), do_echo);
+        let mut sock = TcpStream::connect(("127.0.0.1", serv_port)).unwrap();
+        Stream(sock.try_clone().unwrap()).readwrite();
+        server.stop();
+        assert_eq!(server.stats().conns.load(Ordering::Relaxed), 0);
+        let mut buf = [0;1];
+        assert_eq!(sock.read(&mut buf).unwrap(), 0);
+        free!(cxt);
+    }
+
     #[test]
     fn send_receive_works() {
     	logging_test_set(LOG_LEVEL_INFO);
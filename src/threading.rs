@@ -1,12 +1,13 @@
-use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
 use std::{thread, ptr};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::thread::JoinHandle;
 use std::sync::Arc;
 use std::ops::Deref;
 use crate::circular::CircleList;
-use crate::auto_scale::AutoScalePolicy;
+use crate::auto_scale::AutoScale;
 use crate::traits::*;
+use crate::logging::*;
 
 #[derive(Debug)]
 struct SPSCNode<T>(AtomicPtr<T>, AtomicPtr<SPSCNode<T>>);
@@ -102,6 +103,94 @@ impl<T> SpSc<T> {
 	}
 }
 
+#[derive(Debug)]
+struct MPMCSlot<T>(AtomicUsize /*sequence*/, AtomicPtr<T>);
+
+/**
+ * Non-growable mpmc queue, any number of threads may push and pop.
+ * Each slot carries a sequence number telling whether it is ready to
+ * be written or read for the current lap around the ring, so producers
+ * and consumers only contend on their own position counter.
+ */
+#[derive(Debug)]
+pub struct MpMc<T> {
+	slots:Vec<MPMCSlot<T>>,
+	mask:usize,
+	head:AtomicUsize,
+	tail:AtomicUsize,
+	pub size:usize
+}
+
+impl<T> MpMc<T> {
+	// The capacity is rounded up to a power of two
+	pub fn new(size:usize) -> MpMc<T> {
+		let cap = size.max(2).next_power_of_two();
+		let slots = (0..cap).map(|i| MPMCSlot(AtomicUsize::new(i), AtomicPtr::new(ptr::null_mut()))).collect();
+		MpMc{slots, mask:cap - 1, head:AtomicUsize::new(0), tail:AtomicUsize::new(0), size:cap}
+	}
+
+	pub fn len(&self) -> usize {
+		let tail = self.tail.load(Ordering::SeqCst);
+		let head = self.head.load(Ordering::SeqCst);
+		tail.saturating_sub(head)
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	pub fn is_full(&self) -> bool {
+		self.len() >= self.size
+	}
+
+	pub fn push(&self, ptr:*mut T) -> bool {
+		let mut pos = self.tail.load(Ordering::Relaxed);
+		loop {
+			let slot = &self.slots[pos & self.mask];
+			let seq = slot.0.load(Ordering::Acquire);
+			if seq == pos {
+				match self.tail.compare_exchange_weak(pos, pos + 1, Ordering::SeqCst, Ordering::Relaxed) {
+					Ok(_) => {
+						slot.1.store(ptr, Ordering::Relaxed);
+						slot.0.store(pos + 1, Ordering::Release);
+						return true;
+					},
+					Err(cur) => pos = cur
+				}
+			} else if seq < pos {
+				// still holds the item from the last lap
+				return false;
+			} else {
+				pos = self.tail.load(Ordering::Relaxed);
+			}
+		}
+	}
+
+	pub fn pop(&self) -> Option<*mut T> {
+		let mut pos = self.head.load(Ordering::Relaxed);
+		loop {
+			let slot = &self.slots[pos & self.mask];
+			let seq = slot.0.load(Ordering::Acquire);
+			if seq == pos + 1 {
+				match self.head.compare_exchange_weak(pos, pos + 1, Ordering::SeqCst, Ordering::Relaxed) {
+					Ok(_) => {
+						let read_ptr = slot.1.load(Ordering::Relaxed);
+						// frees the slot for the next lap
+						slot.0.store(pos + self.mask + 1, Ordering::Release);
+						return Some(read_ptr);
+					},
+					Err(cur) => pos = cur
+				}
+			} else if seq < pos + 1 {
+				// empty, or a producer hasn't finished writing
+				return None;
+			} else {
+				pos = self.head.load(Ordering::Relaxed);
+			}
+		}
+	}
+}
+
 // Used as a switch to communicate when a thread should shut down
 #[derive(Clone, Debug)]
 pub struct Switch(Arc<AtomicBool>);
@@ -150,94 +239,206 @@ impl<T> Deref for TVal<T> {
 pub struct ExecUnit<T> {
 	handle:Option<JoinHandle<()>>,
 	switch:Switch,
-	queue:TVal<SpSc<T>>,
+	// ms since epoch when the unit parked, 0 while it's running
+	idle:TVal<AtomicU64>,
+	epoch:Instant,
+	queue:TVal<MpMc<T>>,
     func:fn(*mut T)
 }
 
 impl<T: 'static> Clone for ExecUnit<T> {
-    // Only clones queue size and function, does not copy the thread or current jobs
+    // Starts a new thread on the same queue, does not copy the thread or current jobs
     fn clone(&self) -> Self {
-        // new is hidden inside of clone to not clone the thread handle or queue
-        ExecUnit::<T>::new(self.queue.size, self.func)
+        ExecUnit::<T>::with_queue(self.queue.clone(), self.func, self.epoch)
     }
 }
 
 impl<T: 'static> ExecUnit<T> {
     pub fn new(qsize:usize, func:fn(*mut T)) -> ExecUnit<T> {
-        let queue = TVal::new(SpSc::new(qsize));
+        ExecUnit::with_queue(TVal::new(MpMc::new(qsize)), func, Instant::now())
+    }
+
+    // Several units can share one queue, whichever is free takes the next job.
+    // Idle times are measured from epoch.
+    pub fn with_queue(queue:TVal<MpMc<T>>, func:fn(*mut T), epoch:Instant) -> ExecUnit<T> {
         let switch = Switch::new();
         switch.set(true);
+        let idle = TVal::new(AtomicU64::new(0));
 
         let tqueue = queue.clone();
         let tswitch = switch.clone();
+        let tidle = idle.clone();
         let handle = thread::spawn({move ||
 	    		loop {
+	    			while let Some(ptr) = tqueue.pop() {
+	    				func(ptr);
+	    			}
 	    			if !tswitch.get() {
-	    				// Finishes any remaining requests
-	    				while let Some(ptr) = tqueue.pop() {
-	    					func(ptr);
-	    				}
+	    				// Remaining requests were finished above
 	    				break;
 	    			}
-	    			while let Some(ptr) = tqueue.pop() {
-	    				func(ptr);
+	    			tidle.store(epoch.elapsed().as_millis() as u64 + 1, Ordering::SeqCst);
+	    			// A job pushed before idle was visible would otherwise wait
+	    			if tqueue.is_empty() {
+	    				thread::park();
 	    			}
-                    thread::park();
+	    			tidle.store(0, Ordering::SeqCst);
 	    		}
 	    	});
-        ExecUnit{handle:Some(handle), switch:switch, queue:queue, func:func}
+        ExecUnit{handle:Some(handle), switch:switch, idle:idle, epoch:epoch, queue:queue, func:func}
     }
 
     pub fn queue_empty(&self) -> bool {
         self.queue.is_empty()
     }
 
+    pub fn is_idle(&self) -> bool {
+        self.idle.load(Ordering::SeqCst) != 0
+    }
+
+    // How long the unit has been parked for, zero if running
+    pub fn idle_for(&self) -> Duration {
+        match self.idle.load(Ordering::SeqCst) {
+            0 => Duration::from_millis(0),
+            since => (self.epoch.elapsed() + Duration::from_millis(1)).saturating_sub(Duration::from_millis(since))
+        }
+    }
+
+    #[inline]
+    fn unpark(&self) {
+        self.handle.as_ref().unwrap().thread().unpark();
+    }
+
     pub fn give(&self, obj:T) -> bool {
     	let result = self.queue.push(alloc!(obj));
-    	self.handle.as_ref().unwrap().thread().unpark();
+    	self.unpark();
     	return result;
     }
 
     pub fn give_ptr(&self, ptr:*mut T) -> bool {
     	let result = self.queue.push(ptr);
-    	self.handle.as_ref().unwrap().thread().unpark();
+    	self.unpark();
     	return result;
     }
 
     pub fn stop(&mut self) {
     	self.switch.set(false);
-        self.handle.as_ref().unwrap().thread().unpark();
+        self.unpark();
     	self.handle.take().unwrap().join().unwrap();
     }
 }
 
+/**
+ * A pool of ExecUnits pulling from one shared queue, so a slow job
+ * only holds up the unit running it, while queued jobs go to whichever
+ * unit frees up first.
+ */
 #[derive(Debug)]
 pub struct ExecUnitGroup<T> {
     members:CircleList<ExecUnit<T>>,
     template:ExecUnit<T>,
-    policy:AutoScalePolicy
+    queue:TVal<MpMc<T>>,
+    scale:AutoScale,
+    min_size:usize
 }
 
 impl<T: 'static> ExecUnitGroup<T> {
     pub fn new(start_size:usize, qsize:usize, func:fn(*mut T)) -> ExecUnitGroup<T> {
+        ExecUnitGroup::with_scale(start_size, qsize, func, AutoScale::new())
+    }
+
+    // qsize is per unit, the shared queue holds qsize * start_size jobs
+    pub fn with_scale(start_size:usize, qsize:usize, func:fn(*mut T), scale:AutoScale) -> ExecUnitGroup<T> {
+        let queue = TVal::new(MpMc::new(qsize * start_size));
         // Trick to form a clonable unit, but not start a thread for it
-        let template = ExecUnit{handle:None, switch:Switch::new(), queue:TVal::new(SpSc{head:newptr!(), tail:newptr!(), size:qsize}), func:func};
-        ExecUnitGroup{members:CircleList::new(&template, start_size), template:template, policy:AutoScalePolicy::WhenAllFull(1)}
+        let template = ExecUnit{handle:None, 
+                                switch:Switch::new(), 
+                                idle:TVal::new(AtomicU64::new(0)), 
+                                epoch:Instant::now(), 
+                                queue:queue.clone(), 
+                                func:func};
+        ExecUnitGroup{members:CircleList::new(&template, start_size), template:template, queue, scale, min_size:start_size}
+    }
+
+    pub fn workers(&self) -> usize {
+        self.members.len()
     }
 
-    pub fn increase_members(&self, amount:usize) {
-        for _ in 0..amount {
+    // Jobs waiting on the shared queue for a unit
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    // Adds up to amount units, without going past the max workers
+    pub fn increase_members(&self, amount:usize) -> usize {
+        let adding = amount.min(self.scale.max_workers.saturating_sub(self.members.len()));
+        for _ in 0..adding {
             self.members.add(&self.template);
         }
+        if adding > 0 {
+            log_debug!(ExecUnitGroup, "Added {} units, now at {}", adding, self.members.len());
+        }
+        adding
+    }
+
+    // Stops units parked for longer than the idle limit, down to the
+    // starting size. Only the owner of the group may call this.
+    pub fn shrink_idle(&self) -> usize {
+        if self.scale.idle_shrink == 0 {
+            return 0;
+        }
+        let limit = Duration::from_millis(self.scale.idle_shrink);
+        let mut removed = 0;
+        while self.members.len() > self.min_size {
+            match self.members.remove_where(|unit| unit.idle_for() > limit) {
+                Some(mut unit) => {
+                    unit.stop();
+                    removed += 1;
+                },
+                None => break
+            }
+        }
+        if removed > 0 {
+            log_debug!(ExecUnitGroup, "Removed {} idle units, now at {}", removed, self.members.len());
+        }
+        removed
+    }
+
+    // Takes effect on the next grow or shrink, workers past a lowered max
+    // are only stopped once idle
+    pub fn set_scale(&mut self, scale:AutoScale) {
+        self.scale = scale;
     }
 
-    pub fn assign_ptr(&self, ptr:*mut T) -> Option<usize> { // todo distribution
-        for i in 0..self.members.len() {
-            if self.members.next().give_ptr(ptr) {
-                return Some(i);
+    // How often shrink_idle needs to be called, if at all
+    pub fn shrink_interval(&self) -> Option<Duration> {
+        match self.scale.idle_shrink {
+            0 => None,
+            idle => Some(Duration::from_millis(idle / 2 + 1))
+        }
+    }
+
+    // Wakes one parked unit, if all are busy the job is taken by the
+    // first to finish
+    fn wake_idle(&self) {
+        for _ in 0..self.members.len() {
+            let unit = self.members.next();
+            if unit.is_idle() {
+                unit.unpark();
+                return;
             }
         }
-        return None;
+    }
+
+    // Returns how many jobs were queued ahead of this one
+    pub fn assign_ptr(&self, ptr:*mut T) -> Option<usize> {
+        let ahead = self.queue.len();
+        if !self.queue.push(ptr) {
+            return None;
+        }
+        self.increase_members(self.scale.growth(self.members.len(), ahead + 1, false));
+        self.wake_idle();
+        Some(ahead)
     }
 
     pub fn assign_retried(&self, ptr:*mut T, times:usize) -> Option<usize> {
@@ -246,9 +447,7 @@ impl<T: 'static> ExecUnitGroup<T> {
                 Some(n) => return Some(n),
                 None => {
                     // Full, Activate auto scaling
-                    match self.policy {
-                        AutoScalePolicy::WhenAllFull(amnt) => self.increase_members(amnt)
-                    }
+                    self.increase_members(self.scale.growth(self.members.len(), self.queue.len(), true));
                 }
             }
         }
@@ -280,13 +479,23 @@ impl Parker {
     }
 
     pub fn do_park(&mut self, result:bool) {
+        thread::park_timeout(Duration::from_millis(self.next_wait(result)));
+    }
+
+    // Advances the backoff like do_park, but leaves the waiting to the caller,
+    // such as a timeout for a blocking poll.
+    pub fn next_wait(&mut self, result:bool) -> u64 {
         if result {
             self.cur_time = self.min_time;
         } else if self.cur_time <= self.max_time {
             self.cur_time += self.segment;
-
         }
-        thread::park_timeout(Duration::from_millis(self.cur_time));
+        self.cur_time
+    }
+
+    // Returns to the minimum wait without parking
+    pub fn reset(&mut self) {
+        self.cur_time = self.min_time;
     }
 }
 
@@ -294,6 +503,7 @@ impl Parker {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::auto_scale::AutoScalePolicy;
     #[derive(Debug, Copy, Clone)]
     struct TestType(u32);
 
@@ -305,6 +515,9 @@ mod tests {
         assert_eq!(p.cur_time, p.min_time + p.segment);
         p.do_park(true);
         assert_eq!(p.cur_time, p.min_time);
+        p.do_park(false);
+        p.reset();
+        assert_eq!(p.cur_time, p.min_time);
     }
 
     #[test]
@@ -377,6 +590,72 @@ mod tests {
         assert!(queue.is_empty());
     }
 
+    #[test]
+    fn mpmc_push_pop_works() {
+    	let queue = MpMc::<TestType>::new(3);
+    	assert_eq!(queue.size, 4);
+    	assert!(queue.is_empty());
+    	let items = [alloc!(TestType(1)), alloc!(TestType(2)), alloc!(TestType(3)), alloc!(TestType(4)), alloc!(TestType(5))];
+    	for i in 0..4 {
+    		assert!(queue.push(items[i]));
+    	}
+    	assert!(queue.is_full());
+    	assert!(!queue.push(items[4]));
+    	// wraps around the ring in order
+    	for lap in 0..3 {
+    		let got = queue.pop().expect("Pop with non empty queue failed");
+    		assert_eq!(got, items[lap]);
+    		assert!(queue.push(got));
+    	}
+    	assert_eq!(queue.len(), 4);
+    	while let Some(_) = queue.pop() {}
+    	assert!(queue.is_empty());
+    	for item in items.iter() {
+    		free!(*item);
+    	}
+    }
+
+    #[test]
+    fn mpmc_mt_works() {
+    	let queue = TVal::new(MpMc::<u64>::new(64));
+    	let popped = TVal::new(AtomicUsize::new(0));
+    	let sum = TVal::new(AtomicUsize::new(0));
+    	let per_thread = 5000;
+    	let mut handles = vec![];
+    	for t in 0..3 {
+    		let tqueue = queue.clone();
+    		handles.push(thread::spawn(move || {
+    			for i in 0..per_thread {
+    				let item = alloc!((t * per_thread + i) as u64);
+    				while !tqueue.push(item) {
+    					thread::yield_now();
+    				}
+    			}
+    		}));
+    	}
+    	for _ in 0..3 {
+    		let (tqueue, tpopped, tsum) = (queue.clone(), popped.clone(), sum.clone());
+    		handles.push(thread::spawn(move || {
+    			while tpopped.load(Ordering::SeqCst) < 3 * per_thread {
+    				match tqueue.pop() {
+    					Some(item) => {
+    						tsum.fetch_add(unsafe { *item } as usize, Ordering::SeqCst);
+    						tpopped.fetch_add(1, Ordering::SeqCst);
+    						free!(item);
+    					},
+    					None => thread::yield_now()
+    				}
+    			}
+    		}));
+    	}
+    	for h in handles {
+    		h.join().unwrap();
+    	}
+    	let n = 3 * per_thread;
+    	assert_eq!(sum.load(Ordering::SeqCst), n * (n - 1) / 2);
+    	assert!(queue.is_empty());
+    }
+
     #[test]
     fn switch_works() {
 	    let a = Switch::new();
@@ -461,8 +740,9 @@ mod tests {
             Some(n) => assert_eq!(n, 0),
             None => panic!("Expected group {:?} to have an empty queue", egroup)
         }
+        // the first may still be queued
         match egroup.assign_retried(num2, 5) {
-            Some(n) => assert_eq!(n, 0),
+            Some(n) => assert!(n <= 1),
             None => panic!("Expected group {:?} to have an empty queue", egroup)
         }
         egroup.members.next();
@@ -485,4 +765,77 @@ mod tests {
         free!(num);
         free!(num2);
     }
+
+    fn slow_exec_func(obj:*mut u32) {
+        if unsafe { *obj } == 0 {
+            thread::sleep(Duration::from_millis(300));
+        }
+        unsafe { *obj += 1; }
+    }
+
+    #[test]
+    fn execgroup_slow_job_works() {
+        let mut egroup = ExecUnitGroup::new(2, 4, slow_exec_func);
+        let slow = alloc!(0);
+        assert!(egroup.assign_ptr(slow).is_some());
+        thread::sleep(Duration::from_millis(20));
+        // none of these should wait behind the slow one
+        let nums:Vec<*mut u32> = (1..6).map(|i| alloc!(i)).collect();
+        for num in nums.iter() {
+            assert!(egroup.assign_ptr(*num).is_some());
+        }
+        while !egroup.queue.is_empty() {
+            thread::yield_now();
+        }
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(unsafe { *slow }, 0);
+        for (i, num) in nums.iter().enumerate() {
+            assert_eq!(unsafe { **num }, i as u32 + 2);
+        }
+        egroup.stop_all();
+        assert_eq!(unsafe { *slow }, 1);
+        free!(slow);
+        for num in nums {
+            free!(num);
+        }
+    }
+
+    #[test]
+    fn execgroup_stops_added_works() {
+        let mut egroup = ExecUnitGroup::new(2, 2, sample_exec_func);
+        egroup.increase_members(2);
+        assert_eq!(egroup.members.len(), 4);
+        egroup.stop_all();
+        for _ in 0..4 {
+            assert!(egroup.members.next().handle.is_none());
+        }
+    }
+
+    #[test]
+    fn execgroup_scales_works() {
+        let mut scale = AutoScale::new();
+        scale.policy = AutoScalePolicy::WhenQueueDeeper(1, 2);
+        scale.max_workers = 5;
+        scale.idle_shrink = 30;
+        let mut egroup = ExecUnitGroup::with_scale(2, 8, slow_exec_func, scale);
+        assert_eq!(egroup.increase_members(10), 3);
+        assert_eq!(egroup.workers(), 5);
+        // all units get to park, then the extras are stopped
+        thread::sleep(Duration::from_millis(80));
+        assert_eq!(egroup.shrink_idle(), 3);
+        assert_eq!(egroup.workers(), 2);
+        // the slow ones hold both units, so the queue backs up and grows the group
+        let nums:Vec<*mut u32> = (0..4).map(|_| alloc!(0)).collect();
+        for num in nums.iter() {
+            assert!(egroup.assign_ptr(*num).is_some());
+        }
+        assert!(egroup.workers() > 2);
+        // busy units are never removed
+        assert_eq!(egroup.shrink_idle(), 0);
+        egroup.stop_all();
+        for num in nums {
+            assert_eq!(unsafe { *num }, 1);
+            free!(num);
+        }
+    }
 }
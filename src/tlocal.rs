@@ -1,21 +1,96 @@
-use std::sync::atomic::{AtomicUsize, AtomicBool, AtomicPtr, Ordering};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::time::Instant;
 use std::ptr;
 use std::mem::MaybeUninit;
 use std::convert::TryFrom;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::net::SocketAddr;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::process::abort;
 use crate::database::Database;
+use crate::memory::{Memory, EvictPolicy};
+use crate::stats::CmdStats;
+use crate::slowlog::SlowLog;
 use crate::logging::*;
 use crate::traits::*;
+use crate::reclaim;
+
+/**
+ * Hands out thread IDs, taking back those of exited threads. The lowest
+ * free ID is always handed out first, so tries keyed by ID stay as deep
+ * as the most threads ever alive at once. A thread given a recycled ID
+ * takes over whatever was kept under it, such as its slab heap, so
+ * nothing the exited thread left is stranded.
+ */
+#[derive(Debug)]
+struct TidPool {
+    next:usize,
+    free:BinaryHeap<Reverse<usize>>
+}
 
-static THREAD_CNTR:AtomicUsize = AtomicUsize::new(0);
+impl NewType for TidPool {
+    fn new() -> Self {
+        TidPool{next:0, free:BinaryHeap::new()}
+    }
+}
+
+impl TidPool {
+    fn take(&mut self) -> usize {
+        match self.free.pop() {
+            Some(Reverse(id)) => id,
+            None => {
+                self.next += 1;
+                self.next - 1
+            }
+        }
+    }
 
-thread_local!(static TH_ID:usize = THREAD_CNTR.fetch_add(1, Ordering::SeqCst));
+    fn give(&mut self, id:usize) {
+        self.free.push(Reverse(id));
+    }
+
+    fn live(&self) -> usize {
+        self.next - self.free.len()
+    }
+}
+
+static TID_POOL:Mutex<TidPool> = Mutex::new(TidPool{next:0, free:BinaryHeap::new()});
+
+// Holds a thread's ID, giving it back when the thread exits
+#[derive(Debug)]
+struct ThreadSlot(usize);
+
+impl NewType for ThreadSlot {
+    fn new() -> Self {
+        ThreadSlot(TID_POOL.lock().unwrap().take())
+    }
+}
+
+impl Drop for ThreadSlot {
+    fn drop(&mut self) {
+        // a pin left by the exiting thread would hold back the next taker
+        reclaim::try_unpin();
+        TID_POOL.lock().unwrap().give(self.0);
+    }
+}
+
+thread_local!(static TH_ID:ThreadSlot = ThreadSlot::new());
 
 pub fn tid() -> usize {
-    TH_ID.with(|x| { *x })
+    TH_ID.with(|x| { x.0 })
+}
+
+// None once the thread is exiting and has given its ID back
+pub fn try_tid() -> Option<usize> {
+    TH_ID.try_with(|x| { x.0 }).ok()
+}
+
+// Threads that have taken an ID and not yet exited
+pub fn live_threads() -> usize {
+    TID_POOL.lock().unwrap().live()
 }
 
 static mut MONOTONIC_EPOCH:MaybeUninit<Instant> = MaybeUninit::<Instant>::uninit();
@@ -63,10 +138,9 @@ pub fn get_db() -> *mut Database {
     ACTIVE_DB.with(|x| { *x.borrow() })
 }
 
-#[cfg(debug_assertions)]
+// For threads outside any database
 thread_local!(static MAP_SLOTS_L: RefCell<usize> = RefCell::new(20));
 
-#[cfg(debug_assertions)]
 pub fn get_map_slots() -> usize {
     let db_ptr = get_db();
     if isnull!(db_ptr) {
@@ -76,38 +150,43 @@ pub fn get_map_slots() -> usize {
     }
 }
 
-#[cfg(not(debug_assertions))]
-pub fn get_map_slots() -> usize {
-    unsafe { get_db().as_ref().unwrap().get_map_slots() }
+// The default --max-queue-len, for threads outside any database
+const NO_DB_MAX_QUEUE_LEN:usize = 1 << 20;
+
+pub fn get_max_queue_len() -> usize {
+    unsafe { get_db().as_ref() }.map_or(NO_DB_MAX_QUEUE_LEN, |db| db.get_max_queue_len())
 }
 
-#[cfg(debug_assertions)]
-thread_local!(static FREE_LIST_L: RefCell<u32> = RefCell::new(3));
+// Unlimited, for threads outside any database
+static NO_DB_MEMORY:Memory = Memory::new(0, EvictPolicy::Reject, 0);
 
-#[cfg(debug_assertions)]
-pub fn get_free_lim() -> u32 {
+pub fn get_memory() -> &'static Memory {
     let db_ptr = get_db();
-    if isnull!(db_ptr) {
-        FREE_LIST_L.with(|x| { *x.borrow() })
+    if db_ptr.is_null() {
+        &NO_DB_MEMORY
     } else {
-        unsafe { get_db().as_ref().unwrap().get_free_lim() }
+        unsafe { db_ptr.as_ref().unwrap().memory() }
     }
 }
 
-#[cfg(not(debug_assertions))]
-pub fn get_free_lim() -> u32 {
-    unsafe { get_db().as_ref().unwrap().get_free_lim() }
+// Only counted for threads running in a database
+pub fn get_stats() -> Option<&'static CmdStats> {
+    unsafe { get_db().as_ref() }.map(|db| db.cmd_stats())
 }
 
+pub fn get_slowlog() -> Option<&'static SlowLog> {
+    unsafe { get_db().as_ref() }.map(|db| db.slowlog())
+}
+
+// The client whose request is being run, for the slow log
+thread_local!(static ACTIVE_CLIENT:Cell<Option<SocketAddr>> = Cell::new(None));
 
-#[cfg(debug_assertions)]
-pub fn set_free_lim(val:u32) {
-    FREE_LIST_L.with(|x| { *x.borrow_mut() = val; })
+pub fn set_client(addr:Option<SocketAddr>) {
+    ACTIVE_CLIENT.with(|x| x.set(addr))
 }
 
-#[cfg(not(debug_assertions))]
-pub fn set_free_lim(val:u32) {
-    panic!("This function should not be called on release builds!");
+pub fn get_client() -> Option<SocketAddr> {
+    ACTIVE_CLIENT.with(|x| x.get())
 }
 
 #[cfg(test)]
@@ -123,4 +202,27 @@ mod tests {
     	});
     	handle.join().unwrap();
     }
+
+    #[test]
+    fn tid_pool_works() {
+        let mut pool = TidPool::new();
+        let taken:Vec<usize> = (0..4).map(|_| pool.take()).collect();
+        assert_eq!(taken, vec![0, 1, 2, 3]);
+        pool.give(2);
+        pool.give(1);
+        assert_eq!(pool.live(), 2);
+        // the lowest goes out first
+        assert_eq!(pool.take(), 1);
+        assert_eq!(pool.take(), 2);
+        assert_eq!(pool.take(), 4);
+        assert_eq!(pool.live(), 5);
+    }
+
+    #[test]
+    fn tid_recycle_works() {
+        let first = thread::spawn(tid).join().unwrap();
+        // other tests' threads may take it first, but it goes back once they exit
+        let reused = (0..100).any(|_| thread::spawn(tid).join().unwrap() <= first);
+        assert!(reused);
+    }
 }
\ No newline at end of file
@@ -11,4 +11,13 @@ pub trait KeyLike {
 pub trait InPutOutPut {
 	fn output_binary(&self, output: &mut Vec<u8>);
 	fn input_binary(input:&[u8], place:&mut usize) -> Result<Self, FlotonErr> where Self: Sized;
-}
\ No newline at end of file
+}
+// Told to carry on with work that was waiting on something, from any thread
+pub trait Wake: std::fmt::Debug + Send + Sync {
+	fn wake(&self);
+}
+
+// For tables that only need their keys
+impl NewType for () {
+	fn new() -> Self {}
+}
@@ -165,6 +165,17 @@ impl<T> IntNode<T> {
 		}
 		return false;
 	}
+
+	pub fn for_each<F: FnMut(&T)>(&self, func:&mut F) {
+		if let Some(r) = unsafe { self.0.load(Ordering::SeqCst).as_ref() } {
+			func(r);
+		}
+		for child in self.1.iter() {
+			if let Some(r) = unsafe { child.load(Ordering::SeqCst).as_ref() } {
+				r.for_each(func);
+			}
+		}
+	}
 }
 
 // A trie that uses integers as keys
@@ -268,10 +279,22 @@ impl<T: NewType> IntTrie<T> {
 		}
 	} 
 
+	// The value kept under key, if a thread has made it
+	#[inline]
+	pub fn get_made(&self, key:usize) -> Option<&T> {
+		unsafe { self.nodes.get_seq(key).0.load(Ordering::SeqCst).as_ref() }
+	}
+
 	#[inline]
 	pub fn check_if_one<P>(&self, func:fn(&T, &P) -> bool, arg:&P) -> bool {
 		self.nodes.check_if_one(func, arg)
 	}
+
+	// Visits every value made so far, in no particular order
+	#[inline]
+	pub fn for_each<F: FnMut(&T)>(&self, mut func:F) {
+		self.nodes.for_each(&mut func)
+	}
 }
 
 #[cfg(test)]
@@ -472,6 +495,17 @@ mod tests {
     	assert!(b.check_if_one(is_between_time, &rng));
     }
 
+    #[test]
+    fn for_each_works() {
+    	let b = IntTrie::<TimePoint>::new(4);
+    	b.get_node(3).0.store(alloc!(TimePoint(AtomicUsize::new(6))), Ordering::SeqCst);
+    	b.get_node(9).0.store(alloc!(TimePoint(AtomicUsize::new(2))), Ordering::SeqCst);
+    	let mut seen = vec![];
+    	b.for_each(|point| seen.push(point.time()));
+    	seen.sort();
+    	assert_eq!(seen, vec![2, 6]);
+    }
+
     #[test]
     fn check_if_get_by_tid_works() {
     	let b = IntTrie::<TimePoint>::new(4);
@@ -479,6 +513,8 @@ mod tests {
     	let _val = b.get_by_tid();
     	let regular_node = b.get_node(current_tid);
     	assert!(nonull!(regular_node.0.load(Ordering::SeqCst)));
+    	assert!(b.get_made(current_tid).is_some());
+    	assert!(b.get_made(current_tid + 1000).is_none());
     }
 
     struct MtVal(AtomicUsize);
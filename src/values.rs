@@ -1,6 +1,7 @@
 use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicI64, Ordering};
 use std::io::prelude::*;
 use crate::constants;
+use crate::keys;
 use crate::errors::FlotonErr;
 use crate::traits::*;
 
@@ -16,6 +17,19 @@ pub enum Value {
 }
 
 impl Value {
+	// The VBIN_ tag this value is written with
+	pub fn vbin_type(&self) -> u8 {
+		match self {
+			Value::Nothing => constants::VBIN_NOTHING,
+			Value::Bool(_) => constants::VBIN_BOOL,
+			Value::ABool(_) => constants::VBIN_ABOOL,
+			Value::UInt(_) => constants::VBIN_UINT,
+			Value::AUInt(_) => constants::VBIN_AUINT,
+			Value::IInt(_) => constants::VBIN_IINT,
+			Value::AIInt(_) => constants::VBIN_AIINT
+		}
+	}
+
 	#[inline]
 	pub fn to_bool(&self) -> bool {
 		match self {
@@ -187,40 +201,36 @@ impl InPutOutPut for Value {
 	}
 
 	fn input_binary(input:&[u8], place:&mut usize) -> Result<Self, FlotonErr> {
-		let in_type = input[*place];
+		let in_type = *input.get(*place).ok_or(FlotonErr::BadRequest(*place as u64))?;
 		*place += 1;
 		match in_type {
 			constants::VBIN_NOTHING => {
 				Ok(Value::Nothing)
 			},
 			constants::VBIN_BOOL => {
-				let to_ret = Value::Bool(input[*place] != 0);
+				let byte = *input.get(*place).ok_or(FlotonErr::BadRequest(*place as u64))?;
 				*place += 1;
-				Ok(to_ret)
+				Ok(Value::Bool(byte != 0))
 			},
 			constants::VBIN_ABOOL => {
-				let to_ret = Value::ABool(AtomicBool::new(input[*place] != 0));
+				let byte = *input.get(*place).ok_or(FlotonErr::BadRequest(*place as u64))?;
 				*place += 1;
-				Ok(to_ret)		
+				Ok(Value::ABool(AtomicBool::new(byte != 0)))		
 			},
 			constants::VBIN_UINT => {
-				let int_val = unsafe { *(input.as_ptr().offset(*place as isize) as *const u64) };
-				*place += 8;
+				let int_val = keys::read_u64(input, place)?;
 				Ok(Value::UInt(int_val))
 			},
 			constants::VBIN_AUINT => {
-				let int_val = unsafe { *(input.as_ptr().offset(*place as isize) as *const u64) };
-				*place += 8;
+				let int_val = keys::read_u64(input, place)?;
 				Ok(Value::AUInt(AtomicU64::new(int_val)))				
 			},
 			constants::VBIN_IINT => {
-				let int_val = unsafe { *(input.as_ptr().offset(*place as isize) as *const i64) };
-				*place += 8;
+				let int_val = keys::read_u64(input, place)? as i64;
 				Ok(Value::IInt(int_val))
 			},
 			constants::VBIN_AIINT => {
-				let int_val = unsafe { *(input.as_ptr().offset(*place as isize) as *const i64) };
-				*place += 8;
+				let int_val = keys::read_u64(input, place)? as i64;
 				Ok(Value::AIInt(AtomicI64::new(int_val)))				
 			}
 			_ => Err(FlotonErr::UnexpectedByte(in_type))
@@ -461,4 +471,21 @@ mod tests {
         let prev = num.fetch_sub(&arg2, Ordering::Acquire, ptr::null()).unwrap();
         assert_eq!(prev.to_uint(), 1);
     }
+
+    #[test]
+    fn input_cut_short_works() {
+    	let vals = [Value::Bool(true), Value::ABool(AtomicBool::new(false)), Value::UInt(7),
+    	            Value::AUInt(AtomicU64::new(8)), Value::IInt(-9), Value::AIInt(AtomicI64::new(-10))];
+    	for val in vals.iter() {
+    		let mut out = Vec::<u8>::new();
+    		val.output_binary(&mut out);
+    		for cut in 0..out.len() {
+    			let mut place = 0;
+    			assert!(matches!(Value::input_binary(&out[..cut], &mut place), Err(FlotonErr::BadRequest(_))));
+    		}
+    		let mut place = 0;
+    		assert!(Value::input_binary(&out, &mut place).is_ok());
+    		assert_eq!(place, out.len());
+    	}
+    }
 }